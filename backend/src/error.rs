@@ -72,6 +72,24 @@ pub enum BackendError {
 
     #[display(fmt = "Porject is already managed, type_args = {}", _0)]
     AlreadyManagedProject(H256),
+
+    #[display(fmt = "Project is not managed by driver, type_args = {}", _0)]
+    UnmanagedProject(H256),
+
+    #[display(fmt = "Project context stopped without response, type_args = {}", _0)]
+    MissContextResponse(H256),
+
+    #[display(fmt = "Lua module cell not found, type_args = {}", _0)]
+    MissModuleCell(H256),
+
+    #[display(fmt = "Manifest function mismatches contract, name = {}", _0)]
+    InvalidManifestFunction(String),
+
+    #[display(fmt = "Invalid project manifest, reason = {}", _0)]
+    InvalidProjectManifest(String),
+
+    #[display(fmt = "Sender isn't the owner of project, address = {}", _0)]
+    UnmatchedProjectOwner(String),
 }
 
 impl std::error::Error for BackendError {}
@@ -1,17 +1,23 @@
 use std::str::FromStr;
 
 use ckb_hash::blake2b_256;
+use ko_context_executor::{
+    encode_canonical_json, inject_lua_modules, read_function_abi, CompiledModules,
+};
 use ko_protocol::ckb_sdk::constants::TYPE_ID_CODE_HASH;
-use ko_protocol::ckb_sdk::rpc::ckb_indexer::{ScriptType, SearchKey};
-use ko_protocol::ckb_sdk::HumanCapacity;
+use ko_protocol::ckb_sdk::rpc::ckb_indexer::{Cell, ScriptType, SearchKey};
+use ko_protocol::ckb_sdk::Address;
 use ko_protocol::ckb_types::bytes::Bytes;
-use ko_protocol::ckb_types::core::{ScriptHashType, TransactionView};
+use ko_protocol::ckb_types::core::{Capacity, DepType, ScriptHashType, TransactionView};
 use ko_protocol::ckb_types::packed::{
-    CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction, WitnessArgs,
+    CellDep, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction, WitnessArgs,
 };
 use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
+use ko_protocol::mol_request;
+use ko_protocol::types::assembler::{KoContractAbi, KoLuaModule, KoProject, KoProjectManifest};
+use ko_protocol::types::backend::{KoRequestInput, KoUpgradeReport};
+use ko_protocol::types::config::KoFeeConfig;
 use ko_protocol::{mol_identity, traits::CkbClient, KoResult, H256};
-use ko_protocol::{mol_request, serde_json};
 
 use crate::BackendError;
 
@@ -149,6 +155,76 @@ pub async fn fetch_outpoint_cell(
     }
 }
 
+pub fn parse_candidate_scripts(candidates: &[String]) -> KoResult<Vec<Script>> {
+    let scripts = candidates
+        .iter()
+        .map(|candidate| {
+            Ok(Address::from_str(candidate)
+                .map_err(|_| BackendError::InvalidAddressFormat(candidate.clone()))?
+                .payload()
+                .into())
+        })
+        .collect::<Result<Vec<Script>, BackendError>>()?;
+    Ok(scripts)
+}
+
+#[allow(clippy::type_complexity)]
+pub async fn fetch_request_inputs(
+    rpc: &impl CkbClient,
+    input: KoRequestInput,
+) -> KoResult<(Vec<CellInput>, Vec<(Script, String)>, u64)> {
+    let mut inputs_capacity = 0u64;
+    let mut inputs = vec![];
+    let mut inputs_cell = vec![];
+    match input {
+        KoRequestInput::Address(address) => {
+            let script: Script = Address::from_str(&address)
+                .map_err(|_| BackendError::InvalidAddressFormat(address))?
+                .payload()
+                .into();
+            let (cell, ckb) = fetch_cell_by_script(rpc, &script).await?;
+            inputs_cell.push((script, String::new()));
+            inputs.push(cell);
+            inputs_capacity = ckb;
+        }
+        KoRequestInput::Outpoints(outpoints) => {
+            for out_point in outpoints {
+                let (cell, data, _) = fetch_outpoint_cell(rpc, &out_point).await?;
+                let ckb: u64 = cell.capacity().unpack();
+                inputs_capacity += ckb;
+                inputs.push(
+                    CellInput::new_builder()
+                        .previous_output(out_point.clone())
+                        .build(),
+                );
+                inputs_cell.push((cell.lock(), data));
+            }
+        }
+    }
+    if inputs.is_empty() {
+        return Err(BackendError::MissInputCell.into());
+    }
+    Ok((inputs, inputs_cell, inputs_capacity))
+}
+
+#[allow(clippy::type_complexity)]
+pub async fn fetch_request_components<'a>(
+    rpc: &impl CkbClient,
+    component_outpoints: &'a [OutPoint],
+) -> KoResult<(Vec<String>, Vec<(&'a OutPoint, [u8; 32])>)> {
+    let mut components_data = vec![];
+    let mut components = vec![];
+    for out_point in component_outpoints {
+        let (_, data, data_hash) = fetch_outpoint_cell(rpc, out_point).await?;
+        if data.is_empty() {
+            return Err(BackendError::InvalidComponentCell.into());
+        }
+        components_data.push(data);
+        components.push((out_point, data_hash));
+    }
+    Ok((components_data, components))
+}
+
 pub fn get_transaction_digest(tx: &TransactionView) -> H256 {
     let mut blake2b = ckb_hash::new_blake2b();
     blake2b.update(&tx.hash().raw_data());
@@ -165,28 +241,100 @@ pub fn get_transaction_digest(tx: &TransactionView) -> H256 {
     message.into()
 }
 
-pub fn parse_contract_code(contract: &Bytes) -> KoResult<Vec<u8>> {
-    let lua = mlua::Lua::new();
+// name of contract chunk, which shows in error messages if bytecode keeps debug info
+const CONTRACT_CHUNK_NAME: &str = "=contract";
+
+pub fn parse_contract_code(
+    lua: &mlua::Lua,
+    contract: &Bytes,
+    debug_info: bool,
+) -> KoResult<Vec<u8>> {
     let function = lua
         .load(contract.as_ref())
-        .into_function()
+        .set_name(CONTRACT_CHUNK_NAME)
+        .and_then(|chunk| chunk.into_function())
         .map_err(|err| BackendError::BadContractByteCode(err.to_string()))?;
-    Ok(function.dump(true))
+    Ok(function.dump(!debug_info))
+}
+
+pub async fn search_project_cell(rpc: &impl CkbClient, project_type_args: &H256) -> KoResult<Cell> {
+    let search_key = SearchKey {
+        script: recover_type_id_script(project_type_args.as_bytes()).into(),
+        script_type: ScriptType::Type,
+        filter: None,
+    };
+    let result = rpc
+        .fetch_live_cells(search_key, 1, None)
+        .await
+        .map_err(|err| BackendError::IndexerRpcError(err.to_string()))?;
+    result
+        .objects
+        .into_iter()
+        .next()
+        .ok_or_else(|| BackendError::MissProjectDeploymentCell(project_type_args.clone()).into())
+}
+
+// manifest goes in front of bytecode if it's provided
+pub fn make_project_data(manifest: Option<&KoProjectManifest>, bytecode: Vec<u8>) -> Bytes {
+    match manifest {
+        Some(manifest) => manifest.pack_with(&bytecode),
+        None => Bytes::from(bytecode),
+    }
+}
+
+pub async fn fetch_module_cells(
+    rpc: &impl CkbClient,
+    module_type_args: &[H256],
+) -> KoResult<(Vec<CellDep>, Vec<KoLuaModule>)> {
+    let mut cell_deps = vec![];
+    let mut modules = vec![];
+    for type_args in module_type_args {
+        let search_key = SearchKey {
+            script: recover_type_id_script(type_args.as_bytes()).into(),
+            script_type: ScriptType::Type,
+            filter: None,
+        };
+        let result = rpc
+            .fetch_live_cells(search_key, 1, None)
+            .await
+            .map_err(|err| BackendError::IndexerRpcError(err.to_string()))?;
+        let cell = result
+            .objects
+            .first()
+            .ok_or_else(|| BackendError::MissModuleCell(type_args.clone()))?;
+        cell_deps.push(
+            CellDep::new_builder()
+                .out_point(cell.out_point.clone().into())
+                .dep_type(DepType::Code.into())
+                .build(),
+        );
+        let data = cell.output_data.clone().into_bytes();
+        modules.push(KoLuaModule::pinned(type_args.clone(), data));
+    }
+    Ok((cell_deps, modules))
 }
 
 pub fn get_global_json_data(
+    lua: &mlua::Lua,
     contract: &Bytes,
     contract_owner: &String,
     driver_manager: &String,
+    modules: &[KoLuaModule],
+    manifest: Option<&KoProjectManifest>,
+    debug_info: bool,
 ) -> KoResult<(String, bool, Vec<u8>)> {
-    let lua = mlua::Lua::new();
+    inject_lua_modules(lua, modules, &CompiledModules::default())?;
     let function = lua
         .load(contract.as_ref())
-        .into_function()
+        .set_name(CONTRACT_CHUNK_NAME)
+        .and_then(|chunk| chunk.into_function())
         .map_err(|err| BackendError::BadContractByteCode(err.to_string()))?;
     function
         .call::<_, ()>(())
         .map_err(|err| BackendError::BadContractByteCode(err.to_string()))?;
+    if let Some(manifest) = manifest {
+        check_manifest_functions(lua, manifest)?;
+    }
     let func_init_global: mlua::Function = lua
         .globals()
         .get("construct")
@@ -220,23 +368,159 @@ pub fn get_global_json_data(
     let global_data: mlua::Table = global_driver_data
         .get("global")
         .map_err(|err| BackendError::InvalidConstructReturnType(err.to_string()))?;
-    let global_data_json = serde_json::to_string(&global_data)
+    let global_data_json = encode_canonical_json(lua, &mlua::Value::Table(global_data))
         .map_err(|err| BackendError::GlobalTableNotJsonify(err.to_string()))?;
-    let dump = function.dump(true);
+    let dump = function.dump(!debug_info);
     println!("len = {}", dump.len());
     Ok((global_data_json, &global_driver == contract_owner, dump))
 }
 
-pub fn calc_outputs_capacity(outputs: &[CellOutput], fee: &str) -> u64 {
-    let mut outputs_capacity = outputs
+pub fn check_contract_manifest(
+    lua: &mlua::Lua,
+    contract: &Bytes,
+    modules: &[KoLuaModule],
+    manifest: &KoProjectManifest,
+) -> KoResult<()> {
+    inject_lua_modules(lua, modules, &CompiledModules::default())?;
+    lua.load(contract.as_ref())
+        .set_name(CONTRACT_CHUNK_NAME)
+        .and_then(|chunk| chunk.exec())
+        .map_err(|err| BackendError::BadContractByteCode(err.to_string()))?;
+    check_manifest_functions(lua, manifest)
+}
+
+// functions and views declared in manifest must be defined by contract as they are declared
+fn check_manifest_functions(lua: &mlua::Lua, manifest: &KoProjectManifest) -> KoResult<()> {
+    let defined = |name: &str| match lua.globals().get(name) {
+        Ok(mlua::Value::Function(function)) => Some(function),
+        _ => None,
+    };
+    for function in &manifest.functions {
+        let abi = match defined(&function.name) {
+            Some(defined) => read_function_abi(&function.name, &defined)?,
+            None => return Err(BackendError::InvalidManifestFunction(function.name.clone()).into()),
+        };
+        if &abi != function {
+            return Err(BackendError::InvalidManifestFunction(function.name.clone()).into());
+        }
+    }
+    if let Some(view) = manifest.views.iter().find(|view| defined(view).is_none()) {
+        return Err(BackendError::InvalidManifestFunction(view.clone()).into());
+    }
+    Ok(())
+}
+
+// executor caches compiled project by out_point, so the project to preview takes a fake one
+pub fn make_preview_project(
+    lua_code: Bytes,
+    contract_owner: Script,
+    modules: Vec<KoLuaModule>,
+    manifest: Option<KoProjectManifest>,
+) -> KoProject {
+    let out_point = OutPoint::new_builder()
+        .tx_hash(CellOutput::calc_data_hash(&lua_code))
+        .index(u32::MAX.pack())
+        .build();
+    let cell_dep = CellDep::new_builder().out_point(out_point).build();
+    KoProject::new(cell_dep, lua_code, contract_owner, modules, manifest)
+}
+
+pub fn diff_contract_abi(
+    previous: &KoContractAbi,
+    current: &KoContractAbi,
+    global_data: String,
+) -> KoUpgradeReport {
+    let mut report = KoUpgradeReport {
+        global_data,
+        ..Default::default()
+    };
+    for function in &current.functions {
+        match previous.get(&function.name) {
+            None => report.added_functions.push(function.clone()),
+            Some(previous) if previous != function => {
+                report.changed_functions.push(function.clone())
+            }
+            _ => {}
+        }
+    }
+    report.removed_functions = previous
+        .functions
+        .iter()
+        .filter(|function| current.get(&function.name).is_none())
+        .cloned()
+        .collect();
+    report
+}
+
+pub fn calc_outputs_capacity(outputs: &[CellOutput], fee: u64) -> u64 {
+    let outputs_capacity = outputs
         .iter()
         .map(|output| output.capacity().unpack())
         .collect::<Vec<u64>>()
         .iter()
         .sum::<u64>();
-    let fee = HumanCapacity::from_str(fee).unwrap();
-    outputs_capacity += fee.0;
-    outputs_capacity
+    outputs_capacity + fee
+}
+
+// each lock group is signed by one secp256k1 signature in the witness of its first input,
+// and inputs in front of it take empty witnesses
+pub fn make_witness_placeholders(input_locks: &[Script]) -> Vec<Bytes> {
+    let placeholder = WitnessArgs::new_builder()
+        .lock(Some(Bytes::from(vec![0u8; 65])).pack())
+        .build()
+        .as_bytes();
+    let mut witnesses = vec![];
+    let mut lock_groups = vec![];
+    for (i, lock) in input_locks.iter().enumerate() {
+        if !lock_groups.contains(lock) {
+            lock_groups.push(lock.clone());
+            witnesses.resize(i, Bytes::new());
+            witnesses.push(placeholder.clone());
+        }
+    }
+    // transaction which hasn't collected inputs yet is going to be signed once at least
+    if witnesses.is_empty() {
+        witnesses.push(placeholder);
+    }
+    witnesses
+}
+
+/// Fee of transaction after signed by the lock groups of its inputs
+pub fn calc_transaction_fee(tx: &TransactionView, input_locks: &[Script], fee_rate: u64) -> u64 {
+    let witnesses = make_witness_placeholders(input_locks)
+        .into_iter()
+        .map(|witness| witness.pack())
+        .collect::<Vec<_>>();
+    let tx = tx.as_advanced_builder().set_witnesses(witnesses).build();
+    KoFeeConfig::calc_fee(fee_rate, tx.data().serialized_size_in_block())
+}
+
+/// Give back the capacity beyond exact fee of transaction to its change output
+pub fn balance_transaction_change(
+    tx: TransactionView,
+    change_index: usize,
+    input_locks: &[Script],
+    inputs_capacity: u64,
+    fee_rate: u64,
+) -> KoResult<TransactionView> {
+    let mut outputs = tx.outputs().into_iter().collect::<Vec<_>>();
+    let fee = calc_transaction_fee(&tx, input_locks, fee_rate);
+    let change: u64 = outputs[change_index].capacity().unpack();
+    let occupied = outputs[change_index]
+        .occupied_capacity(Capacity::zero())
+        .unwrap()
+        .as_u64();
+    // change output must keep its occupied capacity after paying fee
+    let required_capacity = calc_outputs_capacity(&outputs, fee) - change + occupied;
+    if inputs_capacity < required_capacity {
+        return Err(BackendError::InsufficientCapacity(inputs_capacity, required_capacity).into());
+    }
+    outputs[change_index] = outputs[change_index]
+        .clone()
+        .as_builder()
+        .capacity((inputs_capacity - required_capacity + occupied).pack())
+        .build();
+    Ok(tx.as_advanced_builder().set_outputs(outputs).build())
 }
 
 pub fn complete_transaction_with_signature(
@@ -247,9 +531,7 @@ pub fn complete_transaction_with_signature(
         .lock(Some(Bytes::from(signature.to_vec())).pack())
         .build()
         .as_bytes();
-    tx.as_advanced_builder()
-        .witnesses(vec![witness].pack())
-        .build()
+    tx.as_advanced_builder().witnesses([witness].pack()).build()
 }
 
 pub fn make_request_data(
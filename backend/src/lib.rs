@@ -1,6 +1,7 @@
-use std::collections::HashMap;
 use std::str::FromStr;
 
+use ckb_hash::blake2b_256;
+use ko_context_executor::{check_function_call, parse_function_call, ExecutorImpl};
 use ko_protocol::ckb_jsonrpc_types::{OutputsValidator, TransactionView as JsonTxView};
 use ko_protocol::ckb_sdk::rpc::ckb_indexer::{ScriptType, SearchKey, SearchKeyFilter};
 use ko_protocol::ckb_sdk::Address;
@@ -10,11 +11,17 @@ use ko_protocol::ckb_types::packed::{CellInput, CellOutput, OutPoint, Script, Tr
 use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
 use ko_protocol::serde_json::to_string;
 use ko_protocol::tokio::sync::mpsc::unbounded_channel;
-use ko_protocol::traits::{Backend, CkbClient, ContextRpc};
-use ko_protocol::types::backend::KoRequestInput;
+use ko_protocol::traits::{Backend, CkbClient, ContextRpc, Storage};
+use ko_protocol::types::assembler::{KoContractAbi, KoProjectManifest, KoRequestSimulation};
+use ko_protocol::types::backend::{
+    KoDeployOptions, KoProjectInfo, KoRequestInput, KoUpgradeReport,
+};
+use ko_protocol::types::config::KoFeeSettings;
+use ko_protocol::types::context::KoRequestReceipt;
 use ko_protocol::{
-    async_trait, hex, is_mol_request_identity, mol_identity, KoResult, ProjectDeps, H256,
+    async_trait, hex, is_mol_request_identity, log, mol_identity, KoResult, ProjectDeps, H256,
 };
+use ko_storage::StorageImpl;
 
 #[cfg(test)]
 mod tests;
@@ -25,21 +32,75 @@ use error::BackendError;
 
 pub struct BackendImpl<C: CkbClient, R: ContextRpc> {
     rpc_client: C,
-    cached_transactions: HashMap<H256, TransactionView>,
+    // transactions waiting for signature, keyed by digest
+    storage: StorageImpl,
     context_rpc: R,
+    // runs uploaded contracts under the limits of requests
+    executor: ExecutorImpl,
+    fee_settings: KoFeeSettings,
 }
 
 impl<C: CkbClient, R: ContextRpc> BackendImpl<C, R> {
-    pub fn new(rpc_client: &C, context_rpc: R) -> Self {
+    pub fn new(
+        rpc_client: &C,
+        context_rpc: R,
+        storage: &StorageImpl,
+        executor: &ExecutorImpl,
+        fee_settings: &KoFeeSettings,
+    ) -> Self {
         BackendImpl {
             rpc_client: rpc_client.clone(),
-            cached_transactions: HashMap::new(),
+            storage: storage.clone(),
             context_rpc,
+            executor: executor.clone(),
+            fee_settings: fee_settings.clone(),
+        }
+    }
+
+    // collect cells of lock into inputs until they pay outputs and the fee of transaction,
+    // which grows with every collected input and the lock groups to sign
+    async fn collect_fee_inputs(
+        &self,
+        mut tx: TransactionView,
+        input_locks: &mut Vec<Script>,
+        mut inputs_capacity: u64,
+        lock: &Script,
+        fee_rate: u64,
+    ) -> KoResult<(TransactionView, u64)> {
+        let search = SearchKey {
+            script: lock.clone().into(),
+            script_type: ScriptType::Lock,
+            filter: None,
+        };
+        loop {
+            let fee = helper::calc_transaction_fee(&tx, input_locks, fee_rate);
+            let outputs = tx.outputs().into_iter().collect::<Vec<_>>();
+            let outputs_capacity = helper::calc_outputs_capacity(&outputs, fee);
+            if inputs_capacity >= outputs_capacity {
+                return Ok((tx, inputs_capacity));
+            }
+            let except_inputs = tx.inputs().into_iter().collect::<Vec<_>>();
+            let (extra_inputs, capacity) = helper::fetch_live_cells(
+                &self.rpc_client,
+                search.clone(),
+                inputs_capacity,
+                outputs_capacity,
+                &except_inputs,
+            )
+            .await?;
+            if extra_inputs.is_empty() {
+                return Err(
+                    BackendError::InsufficientCapacity(inputs_capacity, outputs_capacity).into(),
+                );
+            }
+            input_locks.extend(extra_inputs.iter().map(|_| lock.clone()));
+            tx = tx.as_advanced_builder().inputs(extra_inputs).build();
+            inputs_capacity = capacity;
         }
     }
 
     pub fn peak_transaction(&self, digest: &H256) -> Option<TransactionView> {
-        self.cached_transactions.get(digest).cloned()
+        self.storage.load_digest_transaction(digest).ok().flatten()
     }
 }
 
@@ -49,6 +110,7 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
         &mut self,
         contract: Bytes,
         address: String,
+        options: KoDeployOptions,
         project_deps: &ProjectDeps,
     ) -> KoResult<(H256, H256)> {
         // prepare scripts
@@ -58,10 +120,28 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
         let manager_secp256k1_script: Script = project_deps.project_manager.payload().into();
 
         // make global of output-data
-        let owner = hex::encode(&secp256k1_script.calc_script_hash().raw_data());
-        let manager = hex::encode(&manager_secp256k1_script.calc_script_hash().raw_data());
-        let (global_data_json, owner_as_driver, contract_bytecode) =
-            helper::get_global_json_data(&contract, &owner, &manager)?;
+        let owner = hex::encode(secp256k1_script.calc_script_hash().raw_data());
+        let manager = hex::encode(manager_secp256k1_script.calc_script_hash().raw_data());
+        let (module_cell_deps, modules) =
+            helper::fetch_module_cells(&self.rpc_client, &options.module_type_args).await?;
+        let (global_data_json, owner_as_driver, contract_bytecode) = {
+            let manifest = options.manifest.clone();
+            let debug_info = options.debug_info;
+            self.executor
+                .run_limited(move |lua| {
+                    helper::get_global_json_data(
+                        lua,
+                        &contract,
+                        &owner,
+                        &manager,
+                        &modules,
+                        manifest.as_ref(),
+                        debug_info,
+                    )
+                })
+                .await?
+        };
+        let project_data = helper::make_project_data(options.manifest.as_ref(), contract_bytecode);
 
         // build mock knside-out transaction outputs and data
         let driver_secp256k1_script = if owner_as_driver {
@@ -78,7 +158,7 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
             CellOutput::new_builder()
                 .lock(secp256k1_script.clone())
                 .type_(helper::build_type_id_script(None, 0))
-                .build_exact_capacity(Capacity::bytes(contract_bytecode.len()).unwrap())
+                .build_exact_capacity(Capacity::bytes(project_data.len()).unwrap())
                 .unwrap(),
             // global cell
             CellOutput::new_builder()
@@ -92,27 +172,32 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
                 .build_exact_capacity(Capacity::zero())
                 .unwrap(),
         ];
-        let outputs_data = vec![
-            Bytes::from(contract_bytecode),
+        let outputs_data = [
+            project_data,
             Bytes::from(global_data_json.as_bytes().to_vec()),
             Bytes::default(),
         ];
-        let outputs_capacity = helper::calc_outputs_capacity(&outputs, "1.0");
 
         // fill knside-out transaction inputs
-        let search = SearchKey {
-            script: secp256k1_script.into(),
-            script_type: ScriptType::Lock,
-            filter: None,
-        };
-        let (inputs, inputs_capacity) =
-            helper::fetch_live_cells(&self.rpc_client, search, 0, outputs_capacity, &[]).await?;
-        if inputs_capacity < outputs_capacity {
-            return Err(BackendError::InternalTransactionAssembleError.into());
-        }
+        let fee_rate = self
+            .fee_settings
+            .default
+            .estimate_fee_rate(&self.rpc_client)
+            .await;
+        let draft_tx = TransactionBuilder::default()
+            .outputs(outputs.clone())
+            .outputs_data(outputs_data.pack())
+            .cell_deps(project_deps.project_cell_deps.clone())
+            .cell_deps(module_cell_deps)
+            .build();
+        let mut input_locks = vec![];
+        let (tx, inputs_capacity) = self
+            .collect_fee_inputs(draft_tx, &mut input_locks, 0, &secp256k1_script, fee_rate)
+            .await?;
 
         // rebuild type_id with real input and change
-        let project_type_script = helper::build_type_id_script(Some(&inputs[0]), 0)
+        let first_input = tx.inputs().get(0).unwrap();
+        let project_type_script = helper::build_type_id_script(Some(&first_input), 0)
             .to_opt()
             .unwrap();
         let project_type_args = {
@@ -137,24 +222,15 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
             .as_builder()
             .type_(Some(global_type_script).pack())
             .build();
-        let change = inputs_capacity - outputs_capacity;
-        outputs[2] = outputs[2]
-            .clone()
-            .as_builder()
-            .build_exact_capacity(Capacity::shannons(change))
-            .unwrap();
 
-        // build knside-out transaction
-        let tx = TransactionBuilder::default()
-            .inputs(inputs)
-            .outputs(outputs)
-            .outputs_data(outputs_data.pack())
-            .cell_deps(project_deps.project_cell_deps.clone())
-            .build();
+        // complete knside-out transaction and give the rest to change
+        let tx = tx.as_advanced_builder().set_outputs(outputs).build();
+        let tx =
+            helper::balance_transaction_change(tx, 2, &input_locks, inputs_capacity, fee_rate)?;
 
         // generate transaction digest
         let digest = helper::get_transaction_digest(&tx);
-        self.cached_transactions.insert(digest.clone(), tx);
+        self.storage.save_digest_transaction(&digest, &tx)?;
 
         // generate project type_id args
         Ok((digest, project_type_args))
@@ -165,24 +241,21 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
         contract: Bytes,
         address: String,
         project_type_args: &H256,
+        options: KoDeployOptions,
         project_deps: &ProjectDeps,
-    ) -> KoResult<H256> {
+    ) -> KoResult<(H256, KoUpgradeReport)> {
+        let report = self
+            .check_project_upgrade(
+                contract.clone(),
+                address.clone(),
+                project_type_args,
+                &options,
+            )
+            .await?;
+
         // search existed project deployment cell on CKB
-        let project_type_script = helper::recover_type_id_script(project_type_args.as_bytes());
-        let search_key = SearchKey {
-            script: project_type_script.into(),
-            script_type: ScriptType::Type,
-            filter: None,
-        };
-        let result = self
-            .rpc_client
-            .fetch_live_cells(search_key, 1, None)
-            .await
-            .map_err(|err| BackendError::IndexerRpcError(err.to_string()))?;
-        if result.objects.is_empty() {
-            return Err(BackendError::MissProjectDeploymentCell(project_type_args.clone()).into());
-        }
-        let deployment_cell = &result.objects[0];
+        let deployment_cell =
+            &helper::search_project_cell(&self.rpc_client, project_type_args).await?;
 
         // build knside-out transaction outputs
         let secp256k1_script: Script = Address::from_str(&address)
@@ -190,13 +263,20 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
             .payload()
             .into();
         let previous_type_script = deployment_cell.output.type_.as_ref().unwrap();
-        let contract_bytecode = helper::parse_contract_code(&contract)?;
-        let mut outputs = vec![
+        let debug_info = options.debug_info;
+        let contract_bytecode = self
+            .executor
+            .run_limited(move |lua| helper::parse_contract_code(lua, &contract, debug_info))
+            .await?;
+        let project_data = helper::make_project_data(options.manifest.as_ref(), contract_bytecode);
+        let (module_cell_deps, _) =
+            helper::fetch_module_cells(&self.rpc_client, &options.module_type_args).await?;
+        let outputs = vec![
             // new project deployment cell
             CellOutput::new_builder()
                 .lock(secp256k1_script.clone())
                 .type_(Some(previous_type_script.clone().into()).pack())
-                .build_exact_capacity(Capacity::bytes(contract_bytecode.len()).unwrap())
+                .build_exact_capacity(Capacity::bytes(project_data.len()).unwrap())
                 .unwrap(),
             // change cell
             CellOutput::new_builder()
@@ -204,53 +284,45 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
                 .build_exact_capacity(Capacity::zero())
                 .unwrap(),
         ];
-        let outputs_data = vec![Bytes::from(contract_bytecode), Bytes::new()];
-        let outputs_capacity = helper::calc_outputs_capacity(&outputs, "1.0");
-
-        // fill kinside-out transaction inputs
-        let inputs_capacity: u64 = deployment_cell.output.capacity.into();
-        let search = SearchKey {
-            script: secp256k1_script.into(),
-            script_type: ScriptType::Lock,
-            filter: None,
-        };
-        let (mut inputs, inputs_capacity) = helper::fetch_live_cells(
-            &self.rpc_client,
-            search,
-            inputs_capacity,
-            outputs_capacity,
-            &[],
-        )
-        .await?;
-        if inputs_capacity < outputs_capacity {
-            return Err(BackendError::InternalTransactionAssembleError.into());
-        }
+        let outputs_data = [project_data, Bytes::new()];
+        let fee_rate = self
+            .fee_settings
+            .project_fee(project_type_args)
+            .estimate_fee_rate(&self.rpc_client)
+            .await;
+
+        // fill kinside-out transaction inputs, old deployment cell goes first
         let old_deployment_cell = CellInput::new_builder()
             .previous_output(deployment_cell.out_point.clone().into())
             .build();
-        inputs.insert(0, old_deployment_cell);
-
-        // rebuild change output
-        let change = inputs_capacity - outputs_capacity;
-        outputs[1] = outputs[1]
-            .clone()
-            .as_builder()
-            .build_exact_capacity(Capacity::shannons(change))
-            .unwrap();
-
-        // build knside-out transaction
-        let tx = TransactionBuilder::default()
-            .inputs(inputs)
+        let inputs_capacity: u64 = deployment_cell.output.capacity.into();
+        let mut input_locks = vec![secp256k1_script.clone()];
+        let draft_tx = TransactionBuilder::default()
+            .input(old_deployment_cell)
             .outputs(outputs)
             .outputs_data(outputs_data.pack())
             .cell_deps(project_deps.project_cell_deps.clone())
+            .cell_deps(module_cell_deps)
             .build();
+        let (tx, inputs_capacity) = self
+            .collect_fee_inputs(
+                draft_tx,
+                &mut input_locks,
+                inputs_capacity,
+                &secp256k1_script,
+                fee_rate,
+            )
+            .await?;
+
+        // give the rest to change
+        let tx =
+            helper::balance_transaction_change(tx, 1, &input_locks, inputs_capacity, fee_rate)?;
 
         // generate transaction digest
         let digest = helper::get_transaction_digest(&tx);
-        self.cached_transactions.insert(digest.clone(), tx);
+        self.storage.save_digest_transaction(&digest, &tx)?;
 
-        Ok(digest)
+        Ok((digest, report))
     }
 
     async fn create_project_request_digest(
@@ -262,69 +334,39 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
         project_type_args: &H256,
         project_deps: &ProjectDeps,
     ) -> KoResult<(H256, u64)> {
+        // reject calls which the contract can't serve before user pays for them, abi of
+        // unmanaged project is unknown, so only the literal call grammar is checked
+        match self.get_contract_abi(project_type_args).await {
+            Ok(abi) => {
+                check_function_call(&abi, &function_call)?;
+            }
+            Err(error)
+                if matches!(
+                    error.downcast_ref::<BackendError>(),
+                    Some(BackendError::UnmanagedProject(_))
+                ) =>
+            {
+                parse_function_call(&function_call)?;
+            }
+            Err(error) => return Err(error),
+        }
+
         // build neccessary scripts
         let project_type_id: H256 = helper::recover_type_id_script(project_type_args.as_bytes())
             .calc_script_hash()
             .unpack();
-        let candidates_script = candidate_lockscripts
-            .iter()
-            .map(|candidate| {
-                Ok(Address::from_str(candidate)
-                    .map_err(|_| BackendError::InvalidAddressFormat(candidate.clone()))?
-                    .payload()
-                    .into())
-            })
-            .collect::<Result<Vec<Script>, BackendError>>()?;
+        let candidates_script = helper::parse_candidate_scripts(candidate_lockscripts)?;
         let personal_args = mol_identity(1, project_type_id.as_bytes32());
         let personal_script =
             helper::build_knsideout_script(&project_deps.project_code_hash, &personal_args);
 
         // check input cells
-        let mut inputs_capacity = 0u64;
-        let mut inputs = vec![];
-        let mut inputs_cell = vec![];
-        match input {
-            KoRequestInput::Address(address) => {
-                let script: Script = Address::from_str(&address)
-                    .map_err(|_| BackendError::InvalidAddressFormat(address))?
-                    .payload()
-                    .into();
-                let (cell, ckb) = helper::fetch_cell_by_script(&self.rpc_client, &script).await?;
-                inputs_cell.push((script, String::new()));
-                inputs.push(cell);
-                inputs_capacity = ckb;
-            }
-            KoRequestInput::Outpoints(outpoints) => {
-                for out_point in outpoints {
-                    let (cell, data, _) =
-                        helper::fetch_outpoint_cell(&self.rpc_client, &out_point).await?;
-                    let ckb: u64 = cell.capacity().unpack();
-                    inputs_capacity += ckb;
-                    inputs.push(
-                        CellInput::new_builder()
-                            .previous_output(out_point.clone())
-                            .build(),
-                    );
-                    inputs_cell.push((cell.lock(), data));
-                }
-            }
-        }
-        if inputs.is_empty() {
-            return Err(BackendError::MissInputCell.into());
-        }
+        let (inputs, inputs_cell, inputs_capacity) =
+            helper::fetch_request_inputs(&self.rpc_client, input).await?;
 
         // check component cells
-        let mut components_data = vec![];
-        let mut components = vec![];
-        for out_point in component_outpoints {
-            let (_, data, data_hash) =
-                helper::fetch_outpoint_cell(&self.rpc_client, out_point).await?;
-            if data.is_empty() {
-                return Err(BackendError::InvalidComponentCell.into());
-            }
-            components_data.push(data);
-            components.push((out_point, data_hash));
-        }
+        let (components_data, components) =
+            helper::fetch_request_components(&self.rpc_client, component_outpoints).await?;
 
         // request payment ckb of this call
         let payment_ckb = {
@@ -361,7 +403,7 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
             &candidates_script,
         );
         let request_capacity = Capacity::bytes(request_data.len()).unwrap().as_u64() + payment_ckb;
-        let mut outputs = vec![
+        let outputs = vec![
             // request cell
             CellOutput::new_builder()
                 .lock(request_script)
@@ -374,59 +416,225 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
                 .build_exact_capacity(Capacity::zero())
                 .unwrap(),
         ];
-        let outputs_data = vec![Bytes::from(request_data), Bytes::new()];
-        let outputs_capacity = helper::calc_outputs_capacity(&outputs, "1.0");
+        let outputs_data = [Bytes::from(request_data), Bytes::new()];
+        let fee_rate = self
+            .fee_settings
+            .project_fee(project_type_args)
+            .estimate_fee_rate(&self.rpc_client)
+            .await;
 
         // fill request transaction inputs
-        let search = SearchKey {
-            script: inputs_cell[0].0.clone().into(),
-            script_type: ScriptType::Lock,
-            filter: None,
-        };
-        let (mut extra_inputs, inputs_capacity) = helper::fetch_live_cells(
-            &self.rpc_client,
-            search,
-            inputs_capacity,
-            outputs_capacity,
-            &inputs,
-        )
-        .await?;
-        inputs.append(&mut extra_inputs);
-
-        // rebuild change output
-        if inputs_capacity < outputs_capacity {
-            return Err(
-                BackendError::InsufficientCapacity(inputs_capacity, outputs_capacity).into(),
-            );
-        }
-        let change = inputs_capacity - outputs_capacity;
-        outputs[1] = outputs[1]
-            .clone()
-            .as_builder()
-            .build_exact_capacity(Capacity::shannons(change))
-            .unwrap();
-
-        // build knside-out transaction
-        let tx = TransactionBuilder::default()
+        let mut input_locks = inputs_cell
+            .iter()
+            .map(|(lock, _)| lock.clone())
+            .collect::<Vec<_>>();
+        let draft_tx = TransactionBuilder::default()
             .inputs(inputs)
             .outputs(outputs)
             .outputs_data(outputs_data.pack())
             .cell_deps(project_deps.project_cell_deps.clone())
             .build();
+        let (tx, inputs_capacity) = self
+            .collect_fee_inputs(
+                draft_tx,
+                &mut input_locks,
+                inputs_capacity,
+                &inputs_cell[0].0,
+                fee_rate,
+            )
+            .await?;
+
+        // give the rest to change
+        let tx =
+            helper::balance_transaction_change(tx, 1, &input_locks, inputs_capacity, fee_rate)?;
 
         // generate transaction digest
         let digest = helper::get_transaction_digest(&tx);
-        self.cached_transactions.insert(digest.clone(), tx);
+        self.storage.save_digest_transaction(&digest, &tx)?;
 
         Ok((digest, payment_ckb))
     }
 
+    async fn simulate_project_request(
+        &mut self,
+        function_call: String,
+        input: KoRequestInput,
+        component_outpoints: &[OutPoint],
+        candidate_lockscripts: &[String],
+        project_type_args: &H256,
+    ) -> KoResult<KoRequestSimulation> {
+        // reject anything other than a plain contract function call
+        parse_function_call(&function_call)?;
+
+        // collect cells which the request would carry
+        let candidates_script = helper::parse_candidate_scripts(candidate_lockscripts)?;
+        let (_, inputs_cell, _) = helper::fetch_request_inputs(&self.rpc_client, input).await?;
+        let (components_data, _) =
+            helper::fetch_request_components(&self.rpc_client, component_outpoints).await?;
+
+        // run request against current global cell in project context
+        let (sender, mut receiver) = unbounded_channel();
+        let success = self
+            .context_rpc
+            .simulate_request(
+                project_type_args,
+                &function_call,
+                &inputs_cell,
+                &candidates_script,
+                &components_data,
+                sender,
+            )
+            .await;
+        if !success {
+            return Err(BackendError::UnmanagedProject(project_type_args.clone()).into());
+        }
+        receiver
+            .recv()
+            .await
+            .ok_or_else(|| BackendError::MissContextResponse(project_type_args.clone()))?
+    }
+
+    async fn call_project_view(
+        &mut self,
+        function_call: String,
+        input_outpoints: &[OutPoint],
+        project_type_args: &H256,
+    ) -> KoResult<String> {
+        parse_function_call(&function_call)?;
+
+        // collect personal cells which the view reads from `KOC.inputs`
+        let mut inputs_cell = vec![];
+        for out_point in input_outpoints {
+            let (cell, data, _) = helper::fetch_outpoint_cell(&self.rpc_client, out_point).await?;
+            inputs_cell.push((cell.lock(), data));
+        }
+
+        // run view against current global cell in project context
+        let (sender, mut receiver) = unbounded_channel();
+        let success = self
+            .context_rpc
+            .call_view(project_type_args, &function_call, &inputs_cell, sender)
+            .await;
+        if !success {
+            return Err(BackendError::UnmanagedProject(project_type_args.clone()).into());
+        }
+        receiver
+            .recv()
+            .await
+            .ok_or_else(|| BackendError::MissContextResponse(project_type_args.clone()))?
+    }
+
+    async fn get_contract_abi(&mut self, project_type_args: &H256) -> KoResult<KoContractAbi> {
+        let (sender, mut receiver) = unbounded_channel();
+        let success = self
+            .context_rpc
+            .get_contract_abi(project_type_args, sender)
+            .await;
+        if !success {
+            return Err(BackendError::UnmanagedProject(project_type_args.clone()).into());
+        }
+        receiver
+            .recv()
+            .await
+            .ok_or_else(|| BackendError::MissContextResponse(project_type_args.clone()))?
+    }
+
+    async fn check_project_upgrade(
+        &mut self,
+        contract: Bytes,
+        address: String,
+        project_type_args: &H256,
+        options: &KoDeployOptions,
+    ) -> KoResult<KoUpgradeReport> {
+        // only the current owner of project cell is allowed to upgrade
+        let deployment_cell =
+            helper::search_project_cell(&self.rpc_client, project_type_args).await?;
+        let owner: Script = deployment_cell.output.lock.into();
+        let sender: Script = Address::from_str(&address)
+            .map_err(|_| BackendError::InvalidAddressFormat(address.clone()))?
+            .payload()
+            .into();
+        if owner.as_slice() != sender.as_slice() {
+            return Err(BackendError::UnmatchedProjectOwner(address).into());
+        }
+
+        // dry-run new contract against live global data in project context
+        let (_, modules) =
+            helper::fetch_module_cells(&self.rpc_client, &options.module_type_args).await?;
+        let manifest = options.manifest.clone();
+        let lua_code = {
+            let (modules, manifest) = (modules.clone(), manifest.clone());
+            let debug_info = options.debug_info;
+            self.executor
+                .run_limited(move |lua| {
+                    if let Some(manifest) = &manifest {
+                        helper::check_contract_manifest(lua, &contract, &modules, manifest)?;
+                    }
+                    helper::parse_contract_code(lua, &contract, debug_info)
+                })
+                .await?
+        };
+        let project = helper::make_preview_project(lua_code.into(), owner, modules, manifest);
+
+        // unmanaged project has no context to dry-run in, so the compatibility report is empty
+        let previous_abi = match self.get_contract_abi(project_type_args).await {
+            Ok(abi) => abi,
+            Err(error)
+                if matches!(
+                    error.downcast_ref::<BackendError>(),
+                    Some(BackendError::UnmanagedProject(_))
+                ) =>
+            {
+                return Ok(KoUpgradeReport::default());
+            }
+            Err(error) => return Err(error),
+        };
+        let (sender, mut receiver) = unbounded_channel();
+        let success = self
+            .context_rpc
+            .preview_upgrade(project_type_args, &project, sender)
+            .await;
+        if !success {
+            return Err(BackendError::UnmanagedProject(project_type_args.clone()).into());
+        }
+        let (global_data, abi) = receiver
+            .recv()
+            .await
+            .ok_or_else(|| BackendError::MissContextResponse(project_type_args.clone()))??;
+
+        let report = helper::diff_contract_abi(&previous_abi, &abi, global_data);
+        if !report.removed_functions.is_empty() {
+            log::warn!(
+                "[{}] upgrade removes public functions: {:?}",
+                project_type_args,
+                report.removed_functions
+            );
+        }
+        Ok(report)
+    }
+
+    async fn get_project_info(&mut self, project_type_args: &H256) -> KoResult<KoProjectInfo> {
+        let project_cell = helper::search_project_cell(&self.rpc_client, project_type_args).await?;
+        let (manifest, bytecode) =
+            KoProjectManifest::unpack(&project_cell.output_data.clone().into_bytes())
+                .map_err(BackendError::InvalidProjectManifest)?;
+        let owner: Script = project_cell.output.lock.into();
+        let info = KoProjectInfo::new(
+            project_type_args.clone(),
+            project_cell.out_point,
+            owner.calc_script_hash().unpack(),
+            blake2b_256(&bytecode).into(),
+            manifest,
+        );
+        Ok(info)
+    }
+
     async fn check_project_request_committed(
         &mut self,
         transaction_hash: &H256,
         project_type_args: &H256,
         project_deps: &ProjectDeps,
-    ) -> KoResult<Option<H256>> {
+    ) -> KoResult<Option<KoRequestReceipt>> {
         let out_point = OutPoint::new_builder()
             .tx_hash(transaction_hash.pack())
             .index(0u32.pack())
@@ -465,8 +673,8 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
                 .listen_request_committed(project_type_args, transaction_hash, sender)
                 .await;
             if success {
-                let committed_hash = receiver.recv().await.unwrap()?;
-                return Ok(Some(committed_hash));
+                let receipt = receiver.recv().await.unwrap()?;
+                return Ok(Some(receipt));
             } else {
                 return Ok(None);
             }
@@ -474,6 +682,25 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
         Err(BackendError::InvalidRequestHash(transaction_hash.clone()).into())
     }
 
+    async fn fetch_request_receipt(
+        &mut self,
+        request_hash: &H256,
+        project_type_args: &H256,
+    ) -> KoResult<Option<KoRequestReceipt>> {
+        let (sender, mut receiver) = unbounded_channel();
+        let success = self
+            .context_rpc
+            .fetch_request_receipt(project_type_args, request_hash, sender)
+            .await;
+        if !success {
+            return Err(BackendError::UnmanagedProject(project_type_args.clone()).into());
+        }
+        receiver
+            .recv()
+            .await
+            .ok_or_else(|| BackendError::MissContextResponse(project_type_args.clone()).into())
+    }
+
     async fn drive_project_on_management(
         &mut self,
         project_type_args: &H256,
@@ -516,7 +743,7 @@ impl<C: CkbClient, R: ContextRpc> Backend for BackendImpl<C, R> {
         digest: &H256,
         signature: &[u8; 65],
     ) -> KoResult<Option<H256>> {
-        let tx = self.cached_transactions.remove(digest);
+        let tx = self.storage.take_digest_transaction(digest)?;
         if let Some(tx) = tx {
             let tx = helper::complete_transaction_with_signature(tx, signature);
             let hash = self
@@ -1,15 +1,20 @@
 use ko_context::ContextImpl;
+use ko_context_executor::{new_sandbox_lua, ExecutorImpl, ExecutorPool};
 use ko_protocol::ckb_jsonrpc_types::TransactionView as JsonTxView;
-use ko_protocol::ckb_types::{
-    bytes::Bytes, core::TransactionView, h256, packed::OutPoint, prelude::Pack,
-};
+use ko_protocol::ckb_types::core::{TransactionBuilder, TransactionView};
+use ko_protocol::ckb_types::packed::{CellInput, CellOutput, OutPoint, Script};
+use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
+use ko_protocol::ckb_types::{bytes::Bytes, h256};
 use ko_protocol::secp256k1::SecretKey;
 use ko_protocol::traits::{Backend, CkbClient, Driver};
-use ko_protocol::types::backend::KoRequestInput;
+use ko_protocol::types::assembler::{KoContractAbi, KoFunctionAbi, KoProjectManifest};
+use ko_protocol::types::backend::{KoDeployOptions, KoRequestInput, KoUpgradeReport};
+use ko_protocol::types::config::{KoFeeConfig, KoFeeSettings, KoProjectFeeConfig};
 use ko_protocol::{serde_json, tokio, TestVars::*};
 use ko_rpc_client::RpcClient;
+use ko_storage::StorageImpl;
 
-use crate::BackendImpl;
+use crate::{helper, BackendImpl};
 
 fn sign(ctx: &ContextImpl<impl CkbClient>, tx: TransactionView) -> [u8; 65] {
     println!(
@@ -17,13 +22,26 @@ fn sign(ctx: &ContextImpl<impl CkbClient>, tx: TransactionView) -> [u8; 65] {
         serde_json::to_string_pretty(&JsonTxView::from(tx.clone())).unwrap()
     );
     // sign transaction
-    let signature = {
-        let mut bytes = [0u8; 65];
-        let signature = ctx.driver.sign_transaction(&tx);
-        bytes.copy_from_slice(&signature);
-        bytes
-    };
-    signature
+    let mut bytes = [0u8; 65];
+    let signature = ctx.driver.sign_transaction(&tx);
+    bytes.copy_from_slice(&signature);
+    bytes
+}
+
+fn storage() -> StorageImpl {
+    StorageImpl::temporary().expect("storage")
+}
+
+fn executor() -> ExecutorImpl {
+    ExecutorImpl::new(
+        DRIVE_CONFIG.max_instructions_per_request,
+        DRIVE_CONFIG.max_lua_memory_bytes,
+        &ExecutorPool::new(1),
+    )
+}
+
+fn sandbox() -> mlua::Lua {
+    new_sandbox_lua().expect("sandbox")
 }
 
 #[tokio::test]
@@ -39,12 +57,21 @@ async fn deploy_project_deployment_cell() {
         &PROJECT_TYPE_ARGS.into(),
         &PROJECT_VARS,
         &DRIVE_CONFIG,
+        &ExecutorPool::new(1),
+        &storage(),
+    );
+    let mut backend = BackendImpl::new(
+        &rpc_client,
+        MockContextRpc::default(),
+        &storage(),
+        &executor(),
+        &DRIVE_CONFIG.fee_settings,
     );
-    let mut backend = BackendImpl::new(&rpc_client, MockContextRpc::default());
     let (digest, type_args) = backend
         .create_project_deploy_digest(
             Bytes::from(lua_code.as_bytes().to_vec()),
             OWNER_ADDRESS.into(),
+            KoDeployOptions::default(),
             &PROJECT_VARS,
         )
         .await
@@ -75,17 +102,27 @@ async fn update_project_deployment_cell() {
         &PROJECT_TYPE_ARGS.into(),
         &PROJECT_VARS,
         &DRIVE_CONFIG,
+        &ExecutorPool::new(1),
+        &storage(),
+    );
+    let mut backend = BackendImpl::new(
+        &rpc_client,
+        MockContextRpc::default(),
+        &storage(),
+        &executor(),
+        &DRIVE_CONFIG.fee_settings,
     );
-    let mut backend = BackendImpl::new(&rpc_client, MockContextRpc::default());
-    let digest = backend
+    let (digest, report) = backend
         .create_project_upgrade_digest(
             Bytes::from(lua_code.as_bytes().to_vec()),
             OWNER_ADDRESS.into(),
             &PROJECT_TYPE_ARGS.into(),
+            KoDeployOptions::default(),
             &PROJECT_VARS,
         )
         .await
         .expect("create digest");
+    println!("upgrade report = {:?}", report);
 
     // sign and push transaction
     let tx = backend.peak_transaction(&digest).expect("peak");
@@ -108,8 +145,16 @@ async fn request_project_request_cell() {
         &PROJECT_TYPE_ARGS.into(),
         &PROJECT_VARS,
         &DRIVE_CONFIG,
+        &ExecutorPool::new(1),
+        &storage(),
+    );
+    let mut backend = BackendImpl::new(
+        &rpc_client,
+        MockContextRpc::default(),
+        &storage(),
+        &executor(),
+        &DRIVE_CONFIG.fee_settings,
     );
-    let mut backend = BackendImpl::new(&rpc_client, MockContextRpc::default());
     // let function_call =
     //     "set_card_program(\"return function(r, t) print(\'round: \' .. r, t.race) end\")".into();
     let function_call = "open_box()".into();
@@ -141,8 +186,8 @@ async fn request_project_request_cell() {
         .create_project_request_digest(
             function_call,
             request_input,
-            &vec![],
-            &vec![],
+            &[],
+            &[],
             &PROJECT_TYPE_ARGS.into(),
             &PROJECT_VARS,
         )
@@ -170,25 +215,261 @@ async fn request_project_request_cell() {
 #[tokio::test]
 async fn fetch_global_json_data() {
     let rpc_client = RpcClient::new(CKB_URL, CKB_INDEXER_URL);
-    let global_data = BackendImpl::new(&rpc_client, MockContextRpc::default())
-        .search_global_data(&PROJECT_TYPE_ARGS.into(), &PROJECT_VARS)
-        .await
-        .expect("search global");
+    let global_data = BackendImpl::new(
+        &rpc_client,
+        MockContextRpc::default(),
+        &storage(),
+        &executor(),
+        &DRIVE_CONFIG.fee_settings,
+    )
+    .search_global_data(&PROJECT_TYPE_ARGS.into(), &PROJECT_VARS)
+    .await
+    .expect("search global");
     println!("global_data = {}", global_data);
 }
 
 #[tokio::test]
 async fn fetch_personal_json_data() {
     let rpc_client = RpcClient::new(CKB_URL, CKB_INDEXER_URL);
-    let personal_data = BackendImpl::new(&rpc_client, MockContextRpc::default())
-        .search_personal_data(
-            OWNER_ADDRESS.into(),
-            &PROJECT_TYPE_ARGS.into(),
-            &PROJECT_VARS,
-        )
-        .await
-        .expect("search personal");
+    let personal_data = BackendImpl::new(
+        &rpc_client,
+        MockContextRpc::default(),
+        &storage(),
+        &executor(),
+        &DRIVE_CONFIG.fee_settings,
+    )
+    .search_personal_data(
+        OWNER_ADDRESS.into(),
+        &PROJECT_TYPE_ARGS.into(),
+        &PROJECT_VARS,
+    )
+    .await
+    .expect("search personal");
     personal_data.into_iter().for_each(|(data, outpoint)| {
         println!("personal_data = {}, outpoint = {}", data, outpoint);
     });
 }
+
+#[test]
+fn construct_runs_in_sandbox() {
+    let owner = "00".repeat(32);
+    let contract = |body: &str| {
+        let code = format!(
+            "function construct() {} return {{ driver = KOC.driver, global = {{}} }} end",
+            body
+        );
+        Bytes::from(code)
+    };
+    assert!(helper::get_global_json_data(
+        &sandbox(),
+        &contract(""),
+        &owner,
+        &owner,
+        &[],
+        None,
+        false
+    )
+    .is_ok());
+    [
+        "io.write('x')",
+        "os.exit()",
+        "require('os')",
+        "dofile('/etc/passwd')",
+    ]
+    .iter()
+    .for_each(|body| {
+        assert!(helper::get_global_json_data(
+            &sandbox(),
+            &contract(body),
+            &owner,
+            &owner,
+            &[],
+            None,
+            false
+        )
+        .is_err());
+    });
+}
+
+#[tokio::test]
+async fn construct_runs_under_request_limits() {
+    let (owner, executor) = ("00".repeat(32), executor());
+    let construct = |body: &str| {
+        let contract = Bytes::from(format!(
+            "function construct() {} return {{ driver = KOC.driver, global = {{}} }} end",
+            body
+        ));
+        let owner = owner.clone();
+        executor.run_limited(move |lua| {
+            helper::get_global_json_data(lua, &contract, &owner, &owner, &[], None, false)
+        })
+    };
+    assert!(construct("").await.is_ok());
+    let error = construct("while true do end").await.expect_err("endless");
+    assert!(error.to_string().contains("Instruction budget exhausted"));
+    let error = construct("local t = {} for i = 1, 1e8 do t[i] = i end")
+        .await
+        .expect_err("bloated");
+    assert!(error.to_string().contains("memory"));
+}
+
+#[test]
+fn manifest_is_deployed_in_front_of_bytecode() {
+    let owner = "00".repeat(32);
+    let contract = Bytes::from(
+        r#"
+        function construct() return { driver = KOC.driver, global = {} } end
+        function transfer(to, amount) end
+        function balance(who) end
+        "#,
+    );
+    let manifest = |functions: Vec<KoFunctionAbi>, views: Vec<&str>| KoProjectManifest {
+        name: "token".into(),
+        version: "1.0.0".into(),
+        functions,
+        views: views.into_iter().map(Into::into).collect(),
+        ..Default::default()
+    };
+    let transfer = KoFunctionAbi::new("transfer".into(), 2, false);
+    let deploy = |manifest: &KoProjectManifest| {
+        helper::get_global_json_data(
+            &sandbox(),
+            &contract,
+            &owner,
+            &owner,
+            &[],
+            Some(manifest),
+            false,
+        )
+    };
+
+    let declared = manifest(vec![transfer.clone()], vec!["balance"]);
+    let (_, _, bytecode) = deploy(&declared).expect("deploy");
+    let data = helper::make_project_data(Some(&declared), bytecode.clone());
+    assert_eq!(
+        KoProjectManifest::unpack(&data).unwrap(),
+        (Some(declared), Bytes::from(bytecode.clone()))
+    );
+    let data = helper::make_project_data(None, bytecode.clone());
+    assert_eq!(
+        KoProjectManifest::unpack(&data).unwrap(),
+        (None, Bytes::from(bytecode))
+    );
+
+    // declarations must match what contract defines
+    let wrong_arity = KoFunctionAbi::new("transfer".into(), 1, false);
+    let missing = KoFunctionAbi::new("mint".into(), 1, false);
+    assert!(deploy(&manifest(vec![wrong_arity], vec![])).is_err());
+    assert!(deploy(&manifest(vec![missing], vec![])).is_err());
+    assert!(deploy(&manifest(vec![transfer], vec!["supply"])).is_err());
+}
+
+#[test]
+fn upgrade_report_lists_abi_changes() {
+    let abi = |functions: &[(&str, u8)]| {
+        let functions = functions
+            .iter()
+            .map(|(name, params)| KoFunctionAbi::new(name.to_string(), *params, false))
+            .collect();
+        KoContractAbi::new(functions)
+    };
+    let previous = abi(&[("transfer", 2), ("burn", 1), ("mint", 2)]);
+    let current = abi(&[("mint", 2), ("transfer", 3), ("approve", 2)]);
+    let report = helper::diff_contract_abi(&previous, &current, "{}".into());
+    assert_eq!(report.added_functions, abi(&[("approve", 2)]).functions);
+    assert_eq!(report.removed_functions, abi(&[("burn", 1)]).functions);
+    assert_eq!(report.changed_functions, abi(&[("transfer", 3)]).functions);
+    assert_eq!(report.global_data, "{}");
+
+    let unchanged = helper::diff_contract_abi(&current, &current, "{}".into());
+    assert_eq!(
+        unchanged,
+        KoUpgradeReport::new(vec![], vec![], vec![], "{}".into())
+    );
+
+    // upgraded manifest is checked against new contract as well
+    let contract = Bytes::from("function transfer(to, amount) end");
+    let manifest = |params| KoProjectManifest {
+        functions: vec![KoFunctionAbi::new("transfer".into(), params, false)],
+        ..Default::default()
+    };
+    assert!(helper::check_contract_manifest(&sandbox(), &contract, &[], &manifest(2)).is_ok());
+    assert!(helper::check_contract_manifest(&sandbox(), &contract, &[], &manifest(3)).is_err());
+}
+
+#[test]
+fn change_pays_exact_fee_of_signed_size() {
+    let cell = |capacity: u64| {
+        CellOutput::new_builder()
+            .capacity(capacity.pack())
+            .lock(Script::default())
+            .build()
+    };
+    let input = CellInput::new_builder()
+        .previous_output(OutPoint::new(h256!("0x1").pack(), 0))
+        .build();
+    let tx = TransactionBuilder::default()
+        .input(input)
+        .outputs(vec![cell(200_0000_0000), cell(0)])
+        .outputs_data([Bytes::new(), Bytes::new()].pack())
+        .build();
+    let fee_rate = 1000;
+    let inputs_capacity = 500_0000_0000;
+    let locks = [Script::default()];
+    let tx = helper::balance_transaction_change(tx, 1, &locks, inputs_capacity, fee_rate).unwrap();
+
+    // fee is counted along with the signature which user is going to put into witness
+    let signed_tx = helper::complete_transaction_with_signature(tx.clone(), &[0u8; 65]);
+    let fee = KoFeeConfig::calc_fee(fee_rate, signed_tx.data().serialized_size_in_block());
+    let change: u64 = tx.output(1).unwrap().capacity().unpack();
+    assert_eq!(change, inputs_capacity - 200_0000_0000 - fee);
+    assert!(
+        helper::balance_transaction_change(tx.clone(), 1, &locks, 241_0000_0000, fee_rate).is_err()
+    );
+
+    // every lock group signs at its first input, inputs in between take empty witnesses
+    let other = Script::new_builder()
+        .args(Bytes::from(vec![1u8]).pack())
+        .build();
+    let witnesses = helper::make_witness_placeholders(&[
+        Script::default(),
+        Script::default(),
+        other.clone(),
+        other.clone(),
+    ]);
+    assert_eq!(witnesses.len(), 3);
+    assert!(witnesses[1].is_empty());
+    assert_eq!(witnesses[0], witnesses[2]);
+    assert_eq!(
+        helper::make_witness_placeholders(&[]),
+        vec![witnesses[0].clone()]
+    );
+    let single_group = helper::calc_transaction_fee(&tx, &locks, fee_rate);
+    assert_eq!(single_group, fee);
+    let two_groups = helper::calc_transaction_fee(&tx, &[Script::default(), other], fee_rate);
+    assert!(two_groups > single_group);
+
+    // project follows its own fee policy, and node fee rate only within the bounds of it
+    let project_fee = KoFeeConfig {
+        fee_rate: 2000,
+        use_node_fee_rate: true,
+        max_fee_rate: 5000,
+    };
+    let fee_settings = KoFeeSettings {
+        default: KoFeeConfig::default(),
+        projects: vec![KoProjectFeeConfig {
+            project_type_args: PROJECT_TYPE_ARGS.into(),
+            fee: project_fee.clone(),
+        }],
+    };
+    assert_eq!(
+        fee_settings.project_fee(&PROJECT_TYPE_ARGS.into()),
+        &project_fee
+    );
+    assert_eq!(fee_settings.project_fee(&Default::default()).fee_rate, 1000);
+    assert_eq!(project_fee.resolve_fee_rate(None), 2000);
+    assert_eq!(project_fee.resolve_fee_rate(Some(1000)), 2000);
+    assert_eq!(project_fee.resolve_fee_rate(Some(3000)), 3000);
+    assert_eq!(project_fee.resolve_fee_rate(Some(9000)), 5000);
+    assert_eq!(KoFeeConfig::default().resolve_fee_rate(Some(9000)), 1000);
+}
@@ -0,0 +1,106 @@
+use ko_protocol::ckb_sdk::constants::TYPE_ID_CODE_HASH;
+use ko_protocol::ckb_sdk::rpc::ckb_indexer::{ScriptType, SearchKey, SearchKeyFilter};
+use ko_protocol::ckb_types::core::ScriptHashType;
+use ko_protocol::ckb_types::packed::{OutPoint, Script};
+use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
+use ko_protocol::traits::CkbClient;
+use ko_protocol::{hex, log, mol_identity, parse_mol_identity, KoResult, H256};
+
+use crate::error::AssemblerError;
+use crate::helper;
+
+// identity is a molecule table, its flag comes after the total size and two field offsets
+const IDENTITY_FLAG_END: usize = 13;
+
+/// Indexer matches script args by prefix, so the serialized identity up to flag 0 selects
+/// global cells of all projects
+pub fn global_identity_prefix() -> Vec<u8> {
+    mol_identity(0, &[0u8; 32])[..IDENTITY_FLAG_END].to_vec()
+}
+
+/// Scan indexer for live global cells which are driven by project manager, and recover the
+/// type_args of their projects from the transactions which produced them
+pub async fn discover_managed_projects(
+    rpc: &impl CkbClient,
+    project_code_hash: &H256,
+    project_manager: &Script,
+) -> KoResult<Vec<H256>> {
+    let global_typescript = Script::new_builder()
+        .code_hash(project_code_hash.pack())
+        .hash_type(ScriptHashType::Data.into())
+        .args(global_identity_prefix().pack())
+        .build();
+    let search_key = SearchKey {
+        script: global_typescript.into(),
+        script_type: ScriptType::Type,
+        filter: Some(SearchKeyFilter {
+            script: Some(project_manager.clone().into()),
+            output_data_len_range: None,
+            output_capacity_range: None,
+            block_range: None,
+        }),
+    };
+    let mut projects = vec![];
+    let mut after = None;
+    loop {
+        let result = rpc
+            .fetch_live_cells(search_key.clone(), 20, after)
+            .await
+            .map_err(|err| AssemblerError::IndexerRpcError(err.to_string()))?;
+        for cell in &result.objects {
+            let out_point: OutPoint = cell.out_point.clone().into();
+            let project_id = match cell.output.type_.clone().map(|script| script.args) {
+                Some(args) => match parse_mol_identity(args.as_bytes()) {
+                    Some((0, project_id)) => H256::from(project_id),
+                    _ => continue,
+                },
+                None => continue,
+            };
+            match recover_project_type_args(rpc, &out_point, &project_id).await {
+                Ok(project_type_args) => {
+                    if !projects.contains(&project_type_args) {
+                        projects.push(project_type_args);
+                    }
+                }
+                Err(err) => log::warn!(
+                    "skip global cell {}, error = {}",
+                    hex::encode(out_point.as_slice()),
+                    err
+                ),
+            }
+        }
+        if result.last_cursor.is_empty() {
+            break;
+        }
+        after = Some(result.last_cursor);
+    }
+    Ok(projects)
+}
+
+// project cell is the first output of deployment, or the last celldep of drive transaction
+async fn recover_project_type_args(
+    rpc: &impl CkbClient,
+    global_out_point: &OutPoint,
+    project_id: &H256,
+) -> KoResult<H256> {
+    let (tx, _) = helper::fetch_transaction(rpc, &global_out_point.tx_hash().unpack()).await?;
+    let is_type_id = |script: &Script| script.code_hash() == TYPE_ID_CODE_HASH.pack();
+    let project_type = match tx.output(0).and_then(|output| output.type_().to_opt()) {
+        Some(script) if is_type_id(&script) => Some(script),
+        _ => match tx.cell_deps().into_iter().last() {
+            Some(cell_dep) => {
+                let (project_cell, _, _) = helper::fetch_cell(rpc, &cell_dep.out_point()).await?;
+                project_cell.type_().to_opt().filter(is_type_id)
+            }
+            None => None,
+        },
+    };
+    let project_type_args = project_type
+        .and_then(|script| H256::try_from(script.args().raw_data()).ok())
+        .ok_or_else(|| AssemblerError::MissProjectDeploymentCell(project_id.clone()))?;
+    // global cell of other project may reference a foreign project cell
+    if &helper::make_project_id(&project_type_args) != project_id {
+        return Err(AssemblerError::MissProjectDeploymentCell(project_id.clone()).into());
+    }
+    Ok(project_type_args)
+}
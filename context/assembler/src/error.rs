@@ -32,6 +32,30 @@ pub enum AssemblerError {
 
     #[display(fmt = "need more {} ckbs", _0)]
     InsufficientCellCapacity(u64),
+
+    #[display(fmt = "Transaction not found, hash = {}", _0)]
+    MissTransaction(H256),
+
+    #[display(fmt = "Not a committed drive transaction, reason = {}", _0)]
+    InvalidDriveTransaction(String),
+
+    #[display(
+        fmt = "No unique seed block delay fits header_deps, candidates = {:?}",
+        _0
+    )]
+    UnderivedSeedBlockDelay(Vec<u64>),
+
+    #[display(fmt = "Cell not found, out_point = {}", _0)]
+    MissCell(String),
+
+    #[display(fmt = "Module cell has invalid type id, out_point = {}", _0)]
+    InvalidModuleCell(String),
+
+    #[display(fmt = "Live module cell not found, type_id = {}", _0)]
+    MissModuleCell(H256),
+
+    #[display(fmt = "Invalid project manifest, reason = {}", _0)]
+    InvalidProjectManifest(String),
 }
 
 impl std::error::Error for AssemblerError {}
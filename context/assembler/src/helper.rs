@@ -2,27 +2,42 @@ use ko_protocol::ckb_sdk::constants::TYPE_ID_CODE_HASH;
 use ko_protocol::ckb_sdk::rpc::ckb_indexer::{ScriptType, SearchKey, SearchKeyFilter};
 use ko_protocol::ckb_sdk::traits::LiveCell;
 use ko_protocol::ckb_types::bytes::Bytes;
-use ko_protocol::ckb_types::core::{Capacity, ScriptHashType, TransactionView};
-use ko_protocol::ckb_types::packed::{CellInput, CellOutput, Script, Transaction};
+use ko_protocol::ckb_types::core::{Capacity, DepType, ScriptHashType, TransactionView};
+use ko_protocol::ckb_types::packed::{
+    CellDep, CellInput, CellOutput, OutPoint, Script, Transaction,
+};
 use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
 use ko_protocol::generated::Request;
 use ko_protocol::traits::CkbClient;
-use ko_protocol::types::assembler::KoCellOutput;
-use ko_protocol::{is_mol_request, is_mol_request_identity, mol_identity, KoResult, H256};
+use ko_protocol::types::assembler::{KoCellOutput, KoLuaModule, KoProjectManifest, KoRequest};
+use ko_protocol::{hex, is_mol_request, is_mol_request_identity, mol_identity, KoResult, H256};
 
 use crate::error::AssemblerError;
 
+/// Search live project cell and split its data into manifest and lua bytecode
 pub async fn search_project_cell(
     rpc: &impl CkbClient,
     project_id_args: &H256,
-) -> KoResult<LiveCell> {
-    let project_typescript = Script::new_builder()
-        .code_hash(TYPE_ID_CODE_HASH.pack())
-        .hash_type(ScriptHashType::Type.into())
-        .args(project_id_args.as_bytes().pack())
-        .build();
+) -> KoResult<(LiveCell, Option<KoProjectManifest>, Bytes)> {
+    let cell = search_type_id_cell(rpc, project_id_args)
+        .await?
+        .ok_or_else(|| AssemblerError::MissProjectDeploymentCell(project_id_args.clone()))?;
+    let (manifest, lua_code) = unpack_project_data(&cell.output_data)?;
+    Ok((cell, manifest, lua_code))
+}
+
+pub fn unpack_project_data(data: &Bytes) -> KoResult<(Option<KoProjectManifest>, Bytes)> {
+    let unpacked =
+        KoProjectManifest::unpack(data).map_err(AssemblerError::InvalidProjectManifest)?;
+    Ok(unpacked)
+}
+
+pub async fn search_type_id_cell(
+    rpc: &impl CkbClient,
+    type_id_args: &H256,
+) -> KoResult<Option<LiveCell>> {
     let search_key = SearchKey {
-        script: project_typescript.into(),
+        script: make_type_id_script(type_id_args).into(),
         script_type: ScriptType::Type,
         filter: None,
     };
@@ -30,11 +45,82 @@ pub async fn search_project_cell(
         .fetch_live_cells(search_key, 1, None)
         .await
         .map_err(|err| AssemblerError::IndexerRpcError(err.to_string()))?;
-    if let Some(cell) = result.objects.first() {
-        Ok((cell.clone()).into())
-    } else {
-        Err(AssemblerError::MissProjectDeploymentCell(project_id_args.clone()).into())
+    Ok(result.objects.first().map(|cell| cell.clone().into()))
+}
+
+/// Collect lua modules which the transaction creating project cell referenced as celldeps,
+/// their data hashes are pinned to the cells referenced at that time
+pub async fn fetch_project_modules(
+    rpc: &impl CkbClient,
+    project_out_point: &OutPoint,
+    excluded_cell_deps: &[CellDep],
+) -> KoResult<Vec<KoLuaModule>> {
+    let (tx, _) = fetch_transaction(rpc, &project_out_point.tx_hash().unpack()).await?;
+    let mut modules = vec![];
+    for cell_dep in tx.cell_deps().into_iter() {
+        if excluded_cell_deps.contains(&cell_dep) || cell_dep.dep_type() != DepType::Code.into() {
+            continue;
+        }
+        let (output, data, _) = fetch_cell(rpc, &cell_dep.out_point()).await?;
+        let type_id_args = match output.type_().to_opt() {
+            Some(script) if script.code_hash() == TYPE_ID_CODE_HASH.pack() => {
+                H256::try_from(script.args().raw_data()).map_err(|_| {
+                    AssemblerError::InvalidModuleCell(hex::encode(cell_dep.out_point().as_slice()))
+                })?
+            }
+            _ => continue,
+        };
+        modules.push(KoLuaModule::pinned(type_id_args, data));
     }
+    Ok(modules)
+}
+
+/// Global data needs migration if the transaction producing global cell didn't run with the
+/// current project cell, except that it's the deployment which produced both of them
+pub async fn check_global_migration(
+    rpc: &impl CkbClient,
+    global_out_point: &OutPoint,
+    project_cell_dep: &CellDep,
+) -> KoResult<bool> {
+    let global_tx_hash = global_out_point.tx_hash();
+    let project_out_point = project_cell_dep.out_point();
+    if global_tx_hash == project_out_point.tx_hash() {
+        return Ok(false);
+    }
+    let (tx, _) = fetch_transaction(rpc, &global_tx_hash.unpack()).await?;
+    let last_cell_dep = tx.cell_deps().into_iter().last();
+    Ok(last_cell_dep.map(|cell_dep| cell_dep.out_point()) != Some(project_out_point))
+}
+
+pub async fn fetch_transaction(
+    rpc: &impl CkbClient,
+    hash: &H256,
+) -> KoResult<(TransactionView, Option<H256>)> {
+    let tx = rpc
+        .get_transaction(hash)
+        .await
+        .map_err(|err| AssemblerError::CkbRpcError(err.to_string()))?
+        .ok_or_else(|| AssemblerError::MissTransaction(hash.clone()))?;
+    let block_hash = tx.tx_status.block_hash.map(Into::into);
+    let tx = tx
+        .transaction
+        .ok_or_else(|| AssemblerError::MissTransaction(hash.clone()))?;
+    let tx: Transaction = tx.inner.into();
+    Ok((tx.into_view(), block_hash))
+}
+
+// fetch the cell which out_point refers to, along with the block it was committed in
+pub async fn fetch_cell(
+    rpc: &impl CkbClient,
+    out_point: &OutPoint,
+) -> KoResult<(CellOutput, Bytes, Option<H256>)> {
+    let (tx, block_hash) = fetch_transaction(rpc, &out_point.tx_hash().unpack()).await?;
+    let index: u32 = out_point.index().unpack();
+    let output = tx
+        .output(index as usize)
+        .ok_or_else(|| AssemblerError::MissCell(hex::encode(out_point.as_slice())))?;
+    let data = tx.outputs_data().get(index as usize).unwrap().raw_data();
+    Ok((output, data, block_hash))
 }
 
 pub async fn search_global_cell(
@@ -73,6 +159,20 @@ pub async fn search_global_cell(
     }
 }
 
+pub fn make_type_id_script(type_id_args: &H256) -> Script {
+    Script::new_builder()
+        .code_hash(TYPE_ID_CODE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(type_id_args.as_bytes().pack())
+        .build()
+}
+
+pub fn make_project_id(project_type_args: &H256) -> H256 {
+    make_type_id_script(project_type_args)
+        .calc_script_hash()
+        .unpack()
+}
+
 pub fn make_global_script(code_hash: &H256, project_id: &H256) -> Script {
     Script::new_builder()
         .code_hash(code_hash.pack())
@@ -163,6 +263,33 @@ pub async fn extract_components_from_request(
     Ok(celldeps)
 }
 
+pub async fn make_request(
+    rpc: &impl CkbClient,
+    output: &CellOutput,
+    output_data: &[u8],
+) -> KoResult<KoRequest> {
+    let request = ko_protocol::parse_mol_request(output_data);
+    let inputs = extract_inputs_from_request(&request)?;
+    let candidates = extract_candidates_from_request(&request)?;
+    let components = extract_components_from_request(rpc, &request).await?;
+    let capacity: u64 = output.capacity().unpack();
+    let payment_ckb = {
+        let exact_capacity = output
+            .occupied_capacity(Capacity::bytes(output_data.len()).unwrap())
+            .unwrap()
+            .as_u64();
+        capacity - exact_capacity
+    };
+    Ok(KoRequest::new(
+        request.function_call().raw_data(),
+        inputs,
+        candidates,
+        components,
+        payment_ckb,
+        capacity,
+    ))
+}
+
 pub fn process_raw_outputs(
     i: usize,
     cell_output: &KoCellOutput,
@@ -293,3 +420,28 @@ pub fn get_extractable_capacity(cell: &CellOutput, data_len: usize) -> u64 {
 pub fn clone_with_new_capacity(cell: &CellOutput, capacity: u64) -> CellOutput {
     cell.clone().as_builder().capacity(capacity.pack()).build()
 }
+
+// global cell takes the capacity which inputs leave after other outputs and fee, and returns
+// the shortage it can't cover by itself, which needs extra inputs of driver
+pub fn balance_global_capacity(
+    outputs: &mut [CellOutput],
+    global_data_len: usize,
+    inputs_capacity: u64,
+    outputs_capacity: u64,
+) -> u64 {
+    let capacity: u64 = outputs[0].capacity().unpack();
+    if inputs_capacity >= outputs_capacity {
+        outputs[0] =
+            clone_with_new_capacity(&outputs[0], inputs_capacity - outputs_capacity + capacity);
+        return 0;
+    }
+    let diff = outputs_capacity - inputs_capacity;
+    let change_room = get_extractable_capacity(&outputs[0], global_data_len);
+    if change_room >= diff {
+        outputs[0] = clone_with_new_capacity(&outputs[0], capacity - diff);
+        0
+    } else {
+        outputs[0] = clone_with_new_capacity(&outputs[0], capacity - change_room);
+        diff - change_room
+    }
+}
@@ -1,22 +1,31 @@
-use ckb_hash::{Blake2bBuilder, CKB_HASH_PERSONALIZATION};
+use std::collections::BTreeMap;
 
-use helper::{clone_with_new_capacity, fill_transaction_capacity_diff, get_extractable_capacity};
-use ko_protocol::ckb_sdk::constants::TYPE_ID_CODE_HASH;
+use helper::fill_transaction_capacity_diff;
 use ko_protocol::ckb_sdk::rpc::ckb_indexer::{ScriptType, SearchKey};
 use ko_protocol::ckb_types::bytes::Bytes;
-use ko_protocol::ckb_types::core::{Capacity, DepType, ScriptHashType, TransactionView};
-use ko_protocol::ckb_types::packed::{CellDep, CellInput, Script, WitnessArgs};
-use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
+use ko_protocol::ckb_types::core::{DepType, TransactionView};
+use ko_protocol::ckb_types::packed::{CellDep, CellInput, OutPoint, Script, WitnessArgs};
+use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack};
 use ko_protocol::traits::{Assembler, CkbClient};
-use ko_protocol::types::assembler::{KoAssembleReceipt, KoCellOutput, KoProject, KoRequest};
+use ko_protocol::types::assembler::{
+    KoAssembleReceipt, KoBlockContext, KoCellOutput, KoLuaModule, KoProject, KoRandomSeed,
+};
 use ko_protocol::types::context::KoContextGlobalCell;
 use ko_protocol::{async_trait, KoResult, ProjectDeps, H256};
 
+#[cfg(test)]
+mod tests;
+
+mod discover;
 mod error;
 mod helper;
+mod replay;
 
 use error::AssemblerError;
 
+pub use discover::{discover_managed_projects, global_identity_prefix};
+pub use replay::{make_transaction_outputs, replay_drive_transaction};
+
 pub struct AssemblerImpl<C: CkbClient> {
     rpc_client: C,
     project_id: H256,
@@ -24,6 +33,7 @@ pub struct AssemblerImpl<C: CkbClient> {
     project_code_hash: H256,
     project_cell_deps: Vec<CellDep>,
     project_manager: Script,
+    random_seed_block_delay: u64,
 }
 
 impl<C: CkbClient> AssemblerImpl<C> {
@@ -31,14 +41,9 @@ impl<C: CkbClient> AssemblerImpl<C> {
         rpc_client: &C,
         project_type_args: &H256,
         project_deps: &ProjectDeps,
+        random_seed_block_delay: u64,
     ) -> AssemblerImpl<C> {
-        let project_id = Script::new_builder()
-            .code_hash(TYPE_ID_CODE_HASH.pack())
-            .hash_type(ScriptHashType::Type.into())
-            .args(project_type_args.as_bytes().pack())
-            .build()
-            .calc_script_hash()
-            .unpack();
+        let project_id = helper::make_project_id(project_type_args);
         AssemblerImpl {
             project_id,
             project_id_args: project_type_args.clone(),
@@ -46,6 +51,7 @@ impl<C: CkbClient> AssemblerImpl<C> {
             project_code_hash: project_deps.project_code_hash.clone(),
             project_cell_deps: project_deps.project_cell_deps.clone(),
             project_manager: project_deps.project_manager.payload().into(),
+            random_seed_block_delay,
         }
     }
 
@@ -67,21 +73,71 @@ impl<C: CkbClient> AssemblerImpl<C> {
         .await?;
         Ok(global_cell.into())
     }
+
+    pub async fn get_tip_block_context(&self) -> KoResult<KoBlockContext> {
+        let tip = self
+            .rpc_client
+            .get_tip_header()
+            .await
+            .map_err(|err| AssemblerError::CkbRpcError(err.to_string()))?;
+        Ok(tip.into())
+    }
+
+    // resolve modules of project to their live cells, a module whose code is upgraded after
+    // project deployment keeps its pinned data hash and fails to be required
+    async fn prepare_project_modules(
+        &self,
+        project_out_point: &OutPoint,
+    ) -> KoResult<Vec<KoLuaModule>> {
+        let mut modules = helper::fetch_project_modules(
+            &self.rpc_client,
+            project_out_point,
+            &self.project_cell_deps,
+        )
+        .await?;
+        for module in &mut modules {
+            let module_cell = helper::search_type_id_cell(&self.rpc_client, &module.type_id)
+                .await?
+                .ok_or_else(|| AssemblerError::MissModuleCell(module.type_id.clone()))?;
+            module.code = module_cell.output_data;
+        }
+        Ok(modules)
+    }
+
+    pub async fn get_project_out_point(&self) -> KoResult<OutPoint> {
+        let (project_cell, _, _) =
+            helper::search_project_cell(&self.rpc_client, &self.project_id_args).await?;
+        Ok(project_cell.out_point)
+    }
+
+    async fn get_block_hash(&self, block_number: u64) -> KoResult<H256> {
+        let header = self
+            .rpc_client
+            .get_header_by_number(block_number.into())
+            .await
+            .map_err(|err| AssemblerError::CkbRpcError(err.to_string()))?;
+        Ok(header.hash.into())
+    }
 }
 
 #[async_trait]
 impl<C: CkbClient> Assembler for AssemblerImpl<C> {
     async fn prepare_transaction_project_celldep(&self) -> KoResult<KoProject> {
-        let project_cell =
+        let (project_cell, manifest, lua_code) =
             helper::search_project_cell(&self.rpc_client, &self.project_id_args).await?;
         let project_celldep = CellDep::new_builder()
-            .out_point(project_cell.out_point)
+            .out_point(project_cell.out_point.clone())
             .dep_type(DepType::Code.into())
             .build();
+        let modules = self
+            .prepare_project_modules(&project_cell.out_point)
+            .await?;
         Ok(KoProject::new(
             project_celldep,
-            project_cell.output_data,
+            lua_code,
             project_cell.output.lock(),
+            modules,
+            manifest,
         ))
     }
 
@@ -103,6 +159,8 @@ impl<C: CkbClient> Assembler for AssemblerImpl<C> {
             cell_deps.push(extra_cell_dep.clone());
             cell_deps
         };
+        // reference the header which block context of KOC comes from
+        let block_context = self.get_tip_block_context().await?;
         let mut tx = TransactionView::new_advanced_builder()
             .input(
                 CellInput::new_builder()
@@ -110,6 +168,7 @@ impl<C: CkbClient> Assembler for AssemblerImpl<C> {
                     .build(),
             )
             .cell_deps(cell_deps)
+            .header_dep(block_context.block_hash.pack())
             .build();
 
         // fill transaction inputs and collect KnsideOut requests
@@ -120,9 +179,8 @@ impl<C: CkbClient> Assembler for AssemblerImpl<C> {
             filter: None,
         };
         let mut after = None;
-        let mut blake2b = Blake2bBuilder::new(16)
-            .personal(CKB_HASH_PERSONALIZATION)
-            .build();
+        let mut random_seeds = vec![];
+        let mut seed_block_hashes = BTreeMap::new();
         while requests.len() < cell_number as usize {
             let result = self
                 .rpc_client
@@ -136,31 +194,30 @@ impl<C: CkbClient> Assembler for AssemblerImpl<C> {
                     // println!("[WARN] find invalid reqeust format");
                     continue;
                 }
-                let request = ko_protocol::parse_mol_request(output_data);
-                let inputs = helper::extract_inputs_from_request(&request)?;
-                let candidates = helper::extract_candidates_from_request(&request)?;
-                let components =
-                    helper::extract_components_from_request(&self.rpc_client, &request).await?;
-                let payment_ckb = {
-                    let capacity: u64 = output.capacity().unpack();
-                    let exact_capacity = output
-                        .occupied_capacity(Capacity::bytes(cell.output_data.len()).unwrap())
-                        .unwrap()
-                        .as_u64();
-                    capacity - exact_capacity
+                // seeds come from a block mined after the request, wait until it exists
+                let request_block_number: u64 = cell.block_number.into();
+                let seed_block_number = request_block_number + self.random_seed_block_delay;
+                if seed_block_number > block_context.block_number {
+                    continue;
+                }
+                let seed_block_hash = match seed_block_hashes.get(&seed_block_number) {
+                    Some(block_hash) => H256::clone(block_hash),
+                    None => {
+                        let block_hash = self.get_block_hash(seed_block_number).await?;
+                        seed_block_hashes.insert(seed_block_number, block_hash.clone());
+                        block_hash
+                    }
                 };
-                requests.push(KoRequest::new(
-                    request.function_call().raw_data(),
-                    inputs,
-                    candidates,
-                    components,
-                    payment_ckb,
-                    output.capacity().unpack(),
+                let request = helper::make_request(&self.rpc_client, &output, output_data).await?;
+                requests.push(request);
+                random_seeds.push(KoRandomSeed::derive(
+                    cell.out_point.clone(),
+                    seed_block_hash,
+                    seed_block_number,
                 ));
                 let input = CellInput::new_builder()
                     .previous_output(cell.out_point.into())
                     .build();
-                blake2b.update(input.as_slice());
                 tx = tx.as_advanced_builder().input(input).build();
             }
             if result.last_cursor.is_empty() {
@@ -169,10 +226,28 @@ impl<C: CkbClient> Assembler for AssemblerImpl<C> {
             after = Some(result.last_cursor);
         }
 
-        // make random seed
-        let mut random_bytes = [0u8; 16];
-        blake2b.finalize(&mut random_bytes);
-        let receipt = KoAssembleReceipt::new(requests, global_cell.into(), random_bytes);
+        // reference the headers which random seeds come from
+        for block_hash in seed_block_hashes.into_values() {
+            if block_hash != block_context.block_hash {
+                tx = tx
+                    .as_advanced_builder()
+                    .header_dep(block_hash.pack())
+                    .build();
+            }
+        }
+        let migrate_global = helper::check_global_migration(
+            &self.rpc_client,
+            &global_cell.out_point,
+            extra_cell_dep,
+        )
+        .await?;
+        let receipt = KoAssembleReceipt::new(
+            requests,
+            global_cell.into(),
+            random_seeds,
+            block_context,
+            migrate_global,
+        );
         Ok((tx, receipt))
     }
 
@@ -196,27 +271,22 @@ impl<C: CkbClient> Assembler for AssemblerImpl<C> {
         });
 
         // check inputs/outputs capacity
-        let capacity: u64 = outputs[0].capacity().unpack();
-        if inputs_capacity >= outputs_capacity {
-            outputs[0] =
-                clone_with_new_capacity(&outputs[0], inputs_capacity - outputs_capacity + capacity);
-        } else {
-            let diff = outputs_capacity - inputs_capacity;
-            let change_room = get_extractable_capacity(&outputs[0], outputs_data[0].len());
-            if change_room >= diff {
-                outputs[0] = clone_with_new_capacity(&outputs[0], capacity - diff);
-            } else {
-                outputs[0] = clone_with_new_capacity(&outputs[0], capacity - change_room);
-                fill_transaction_capacity_diff(
-                    &self.rpc_client,
-                    &outputs[0].lock(),
-                    diff - change_room,
-                    &mut tx,
-                    &mut outputs,
-                    &mut outputs_data,
-                )
-                .await?;
-            }
+        let shortage = helper::balance_global_capacity(
+            &mut outputs,
+            outputs_data[0].len(),
+            inputs_capacity,
+            outputs_capacity,
+        );
+        if shortage > 0 {
+            fill_transaction_capacity_diff(
+                &self.rpc_client,
+                &outputs[0].lock(),
+                shortage,
+                &mut tx,
+                &mut outputs,
+                &mut outputs_data,
+            )
+            .await?;
         }
 
         // complete partial tx
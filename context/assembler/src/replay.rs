@@ -0,0 +1,233 @@
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::packed::CellOutput;
+use ko_protocol::ckb_types::prelude::Unpack;
+use ko_protocol::traits::CkbClient;
+use ko_protocol::types::assembler::{
+    KoAssembleReceipt, KoBlockContext, KoCellOutput, KoDriveReplay, KoProject, KoRandomSeed,
+};
+use ko_protocol::types::context::KoContextGlobalCell;
+use ko_protocol::{KoResult, H256};
+
+use crate::error::AssemblerError;
+use crate::helper;
+
+/// Reconstruct the inputs of a committed drive transaction from chain, so that it can be
+/// executed again and compared with the outputs the driver committed
+pub async fn replay_drive_transaction(
+    rpc: &impl CkbClient,
+    transaction_hash: &H256,
+    project_code_hash: &H256,
+) -> KoResult<KoDriveReplay> {
+    let (tx, block_hash) = helper::fetch_transaction(rpc, transaction_hash).await?;
+    if block_hash.is_none() {
+        return Err(invalid("transaction is not committed"));
+    }
+
+    // project cell is always the last celldep
+    let project_dep = tx
+        .cell_deps()
+        .into_iter()
+        .last()
+        .ok_or_else(|| invalid("missing project celldep"))?;
+    let (project_cell, project_data, _) = helper::fetch_cell(rpc, &project_dep.out_point()).await?;
+    let (manifest, lua_code) = helper::unpack_project_data(&project_data)?;
+    let project_type_args: H256 = project_cell
+        .type_()
+        .to_opt()
+        .ok_or_else(|| invalid("project cell has no type script"))?
+        .args()
+        .raw_data()
+        .try_into()
+        .map_err(|err: String| invalid(&err))?;
+    let project_id = helper::make_project_id(&project_type_args);
+    // modules keep the code pinned by project, which the driver has checked live ones against
+    let cell_deps = tx.cell_deps().into_iter().collect::<Vec<_>>();
+    let modules = helper::fetch_project_modules(rpc, &project_dep.out_point(), &cell_deps).await?;
+    let project = KoProject::new(
+        project_dep,
+        lua_code,
+        project_cell.lock(),
+        modules,
+        manifest,
+    );
+
+    // global cell is always the first input
+    let inputs = tx.inputs();
+    let global_input = inputs
+        .get(0)
+        .ok_or_else(|| invalid("missing global cell input"))?;
+    let (global_output, global_data, _) =
+        helper::fetch_cell(rpc, &global_input.previous_output()).await?;
+    let global_type = helper::make_global_script(project_code_hash, &project_id);
+    if global_output.type_().to_opt() != Some(global_type) {
+        return Err(invalid("first input is not project global cell"));
+    }
+    let global_cell = KoContextGlobalCell::from_output(global_output, global_data);
+
+    // block context of KOC comes from the first header_dep
+    let header_deps = tx.header_deps();
+    let context_hash: H256 = header_deps
+        .get(0)
+        .ok_or_else(|| invalid("missing block context header_dep"))?
+        .unpack();
+    let block_context = fetch_block_context(rpc, &context_hash).await?;
+
+    // request cells follow the global cell, and change cells of driver come after them
+    let personal_type = helper::make_personal_script(project_code_hash, &project_id);
+    let mut requests = vec![];
+    let mut request_cells = vec![];
+    for input in inputs.into_iter().skip(1) {
+        let out_point = input.previous_output();
+        let (output, data, block_hash) = helper::fetch_cell(rpc, &out_point).await?;
+        if output.type_().to_opt().as_ref() != Some(&personal_type)
+            || !helper::check_valid_request(&output, &data, project_code_hash)
+        {
+            break;
+        }
+        requests.push(helper::make_request(rpc, &output, &data).await?);
+        let block_hash = block_hash.ok_or_else(|| invalid("request cell is not committed"))?;
+        let block_number = fetch_block_context(rpc, &block_hash).await?.block_number;
+        request_cells.push((out_point, block_number));
+    }
+
+    // seed blocks are the rest of header_deps, delay of driver is recovered from them
+    let mut seed_blocks = vec![];
+    for hash in header_deps.into_iter().skip(1) {
+        let hash: H256 = hash.unpack();
+        let block_number = fetch_block_context(rpc, &hash).await?.block_number;
+        seed_blocks.push((block_number, hash));
+    }
+    let request_blocks = request_cells
+        .iter()
+        .map(|(_, block_number)| *block_number)
+        .collect::<Vec<_>>();
+    let seed_block_numbers = seed_blocks
+        .iter()
+        .map(|(block_number, _)| *block_number)
+        .collect::<Vec<_>>();
+    let delay = derive_seed_block_delay(
+        &request_blocks,
+        block_context.block_number,
+        &seed_block_numbers,
+    )?;
+    seed_blocks.push((block_context.block_number, context_hash));
+    let random_seeds = request_cells
+        .into_iter()
+        .map(|(out_point, block_number)| {
+            let seed_block_number = block_number + delay;
+            let (_, seed_block_hash) = seed_blocks
+                .iter()
+                .find(|(number, _)| *number == seed_block_number)
+                .expect("derived seed block");
+            KoRandomSeed::derive(out_point.into(), seed_block_hash.clone(), seed_block_number)
+        })
+        .collect();
+
+    let migrate_global =
+        helper::check_global_migration(rpc, &global_input.previous_output(), &project.cell_dep)
+            .await?;
+    // fee is what all inputs leave after outputs, including the inputs driver paid with
+    let mut inputs_capacity = 0u64;
+    for input in tx.inputs() {
+        let (output, _, _) = helper::fetch_cell(rpc, &input.previous_output()).await?;
+        let capacity: u64 = output.capacity().unpack();
+        inputs_capacity += capacity;
+    }
+    let fee = tx
+        .outputs_capacity()
+        .ok()
+        .and_then(|capacity| inputs_capacity.checked_sub(capacity.as_u64()))
+        .ok_or_else(|| invalid("outputs capacity exceeds inputs"))?;
+
+    let receipt = KoAssembleReceipt::new(
+        requests,
+        global_cell,
+        random_seeds,
+        block_context,
+        migrate_global,
+    );
+    Ok(KoDriveReplay::new(
+        tx,
+        project,
+        project_type_args,
+        receipt,
+        fee,
+    ))
+}
+
+/// Make output cells exactly like the driver does, global cell takes the capacity which the
+/// inputs of receipt leave after fee and other outputs
+pub fn make_transaction_outputs(
+    cell_outputs: &[KoCellOutput],
+    project_code_hash: &H256,
+    project_type_args: &H256,
+    inputs_capacity: u64,
+    fee: u64,
+) -> (Vec<CellOutput>, Vec<Bytes>) {
+    let project_id = helper::make_project_id(project_type_args);
+    let mut outputs = vec![];
+    let mut outputs_data = vec![];
+    let mut outputs_capacity = fee;
+    cell_outputs.iter().enumerate().for_each(|(i, output)| {
+        let (mut cells, mut data, capacity) =
+            helper::process_raw_outputs(i, output, project_code_hash, &project_id);
+        outputs_capacity += capacity;
+        outputs.append(&mut cells);
+        outputs_data.append(&mut data);
+    });
+    if !outputs.is_empty() {
+        helper::balance_global_capacity(
+            &mut outputs,
+            outputs_data[0].len(),
+            inputs_capacity,
+            outputs_capacity,
+        );
+    }
+    (outputs, outputs_data)
+}
+
+/// Recover the delay between request blocks and their seed blocks, driver references every
+/// seed block in header_deps except the one of block context, so only the real delay makes all
+/// requests land on referenced blocks and leaves no extra seed block unused
+pub fn derive_seed_block_delay(
+    request_blocks: &[u64],
+    context_block: u64,
+    seed_blocks: &[u64],
+) -> KoResult<u64> {
+    let first_request = match request_blocks.iter().min() {
+        Some(block_number) => *block_number,
+        None => return Ok(0),
+    };
+    let referenced =
+        |block_number: u64| block_number == context_block || seed_blocks.contains(&block_number);
+    let candidates = seed_blocks
+        .iter()
+        .chain(std::iter::once(&context_block))
+        .filter(|block_number| **block_number >= first_request)
+        .map(|block_number| block_number - first_request)
+        .filter(|delay| {
+            request_blocks
+                .iter()
+                .all(|block_number| referenced(block_number + delay))
+                && seed_blocks
+                    .iter()
+                    .all(|seed| request_blocks.iter().any(|block| block + delay == *seed))
+        })
+        .collect::<Vec<_>>();
+    match candidates.as_slice() {
+        [delay] => Ok(*delay),
+        _ => Err(AssemblerError::UnderivedSeedBlockDelay(candidates).into()),
+    }
+}
+
+async fn fetch_block_context(rpc: &impl CkbClient, block_hash: &H256) -> KoResult<KoBlockContext> {
+    let block = rpc
+        .get_block(block_hash)
+        .await
+        .map_err(|err| AssemblerError::CkbRpcError(err.to_string()))?;
+    Ok(block.header.into())
+}
+
+fn invalid(reason: &str) -> ko_protocol::types::error::KoError {
+    AssemblerError::InvalidDriveTransaction(reason.into()).into()
+}
@@ -0,0 +1,33 @@
+use ko_protocol::{mol_identity, parse_mol_identity};
+
+use crate::global_identity_prefix;
+use crate::replay::derive_seed_block_delay;
+
+#[test]
+fn global_prefix_matches_only_global_identity() {
+    let project_id = [7u8; 32];
+    let global_args = mol_identity(0, &project_id);
+    assert!(global_args.starts_with(&global_identity_prefix()));
+    assert_eq!(parse_mol_identity(&global_args), Some((0, project_id)));
+
+    // personal and request identities of the same project are not global cells
+    for flag in [1u8, 2] {
+        let args = mol_identity(flag, &project_id);
+        assert!(!args.starts_with(&global_identity_prefix()));
+    }
+}
+
+#[test]
+fn seed_block_delay_is_derived_from_header_deps() {
+    // seed block of the later request is the block context itself
+    assert_eq!(derive_seed_block_delay(&[10, 11], 13, &[12]).unwrap(), 2);
+    assert_eq!(derive_seed_block_delay(&[10], 12, &[]).unwrap(), 2);
+
+    // block context is not a seed block when another one is referenced
+    assert_eq!(derive_seed_block_delay(&[10], 20, &[12]).unwrap(), 2);
+    assert_eq!(derive_seed_block_delay(&[], 20, &[]).unwrap(), 0);
+
+    // every request must land on a referenced block
+    assert!(derive_seed_block_delay(&[10, 15], 20, &[12]).is_err());
+    assert!(derive_seed_block_delay(&[10], 20, &[12, 14]).is_err());
+}
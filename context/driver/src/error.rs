@@ -1,4 +1,5 @@
 use ko_protocol::derive_more::Display;
+use ko_protocol::types::context::KoRejection;
 use ko_protocol::types::error::{ErrorType, KoError};
 use ko_protocol::H256;
 
@@ -15,6 +16,16 @@ pub enum DriverError {
 
     #[display(fmt = "Rpc get_transaction error: {}, hash = {}", _0, _1)]
     TransactionFetchError(String, H256),
+
+    #[display(fmt = "Transaction rejected: {:?}, hash = {}", _0, _1)]
+    TransactionRejected(KoRejection, H256),
+
+    #[display(
+        fmt = "Transaction still pending after {} resubmissions, hash = {}",
+        _0,
+        _1
+    )]
+    ResubmissionExhausted(usize, H256),
 }
 
 impl std::error::Error for DriverError {}
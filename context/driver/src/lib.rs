@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use ckb_hash::new_blake2b;
 use ko_protocol::ckb_jsonrpc_types::{OutputsValidator, Status, TransactionView as JsonTxView};
@@ -9,10 +9,14 @@ use ko_protocol::ckb_types::{bytes::Bytes, core::TransactionView};
 use ko_protocol::secp256k1::{Message, SecretKey};
 use ko_protocol::serde_json::to_string;
 use ko_protocol::traits::{CkbClient, Driver};
+use ko_protocol::types::context::{KoRejection, KoTransactionStatus};
 use ko_protocol::{async_trait, log, tokio, KoResult, H256};
 
+#[cfg(test)]
+mod tests;
+
 mod error;
-use error::DriverError;
+pub use error::DriverError;
 
 pub struct DriverImpl<C: CkbClient> {
     rpc_client: C,
@@ -26,6 +30,24 @@ impl<C: CkbClient> DriverImpl<C> {
             privkey: *privkey,
         }
     }
+
+    async fn get_block_number(&self, hash: &H256, block_hash: &H256) -> KoResult<u64> {
+        let block = self
+            .rpc_client
+            .get_block(block_hash)
+            .await
+            .map_err(|err| DriverError::TransactionFetchError(err.to_string(), hash.clone()))?;
+        Ok(block.header.inner.number.into())
+    }
+
+    async fn get_canonical_hash(&self, hash: &H256, block_number: u64) -> KoResult<H256> {
+        let header = self
+            .rpc_client
+            .get_header_by_number(block_number.into())
+            .await
+            .map_err(|err| DriverError::TransactionFetchError(err.to_string(), hash.clone()))?;
+        Ok(header.hash.into())
+    }
 }
 
 #[async_trait]
@@ -74,44 +96,77 @@ impl<C: CkbClient> Driver for DriverImpl<C> {
         hash: &H256,
         interval: &Duration,
         confirms: u8,
-    ) -> KoResult<()> {
-        let mut block_number = 0u64;
+        pending_timeout: &Duration,
+    ) -> KoResult<KoTransactionStatus> {
+        let mut start = Instant::now();
+        // the block which commits transaction is forgotten once it leaves canonical chain
+        let mut committed_block: Option<(u64, H256)> = None;
         loop {
             tokio::time::sleep(*interval).await;
-            let tx = self
-                .rpc_client
-                .get_transaction(hash)
-                .await
-                .map_err(|err| DriverError::TransactionFetchError(err.to_string(), hash.clone()))?
-                .unwrap();
-            if tx.tx_status.status == Status::Rejected {
-                return Err(DriverError::TransactionFetchError(
-                    tx.tx_status.reason.unwrap_or_else(|| "rejected".into()),
-                    hash.clone(),
-                )
-                .into());
-            }
-            if tx.tx_status.status != Status::Committed {
-                continue;
-            }
-            if block_number == 0 {
-                if let Some(block_hash) = tx.tx_status.block_hash {
-                    let block = self.rpc_client.get_block(&block_hash.into()).await.unwrap();
-                    block_number = block.header.inner.number.into();
-                    log::info!(
-                        "transaction #{} commited in block #{}, wait confirm...",
-                        hash,
-                        block_number
-                    );
+            let tx =
+                self.rpc_client.get_transaction(hash).await.map_err(|err| {
+                    DriverError::TransactionFetchError(err.to_string(), hash.clone())
+                })?;
+            // transaction dropped from pool is regarded as pending, so it can be rebroadcasted
+            let status = tx.map(|tx| tx.tx_status);
+            match status {
+                Some(status) if status.status == Status::Rejected => {
+                    let reason = status.reason.unwrap_or_else(|| "rejected".into());
+                    return Ok(KoTransactionStatus::Rejected(KoRejection::classify(
+                        &reason,
+                    )));
+                }
+                Some(status) if status.status == Status::Committed => {
+                    let block_hash: H256 = match status.block_hash {
+                        Some(block_hash) => block_hash.into(),
+                        None => continue,
+                    };
+                    let block_number = match &committed_block {
+                        Some((number, committed_hash)) if committed_hash == &block_hash => *number,
+                        _ => {
+                            let number = self.get_block_number(hash, &block_hash).await?;
+                            log::info!(
+                                "transaction #{} commited in block #{}, wait confirm...",
+                                hash,
+                                number
+                            );
+                            committed_block = Some((number, block_hash.clone()));
+                            number
+                        }
+                    };
+                    // confirmations only count while the block stays on canonical chain
+                    let canonical_hash = self.get_canonical_hash(hash, block_number).await?;
+                    if canonical_hash != block_hash {
+                        log::warn!(
+                            "block #{} of transaction #{} is reorganized out",
+                            block_number,
+                            hash
+                        );
+                        committed_block = None;
+                        continue;
+                    }
+                    let tip = self.rpc_client.get_tip_header().await.map_err(|err| {
+                        DriverError::TransactionFetchError(err.to_string(), hash.clone())
+                    })?;
+                    let tip_number: u64 = tip.inner.number.into();
+                    if tip_number >= block_number + confirms as u64 {
+                        return Ok(KoTransactionStatus::Committed);
+                    }
                 }
-            } else {
-                let tip = self.rpc_client.get_tip_header().await.unwrap();
-                let tip_number: u64 = tip.inner.number.into();
-                if tip_number >= block_number + confirms as u64 {
-                    break;
+                _ => {
+                    if let Some((block_number, _)) = committed_block.take() {
+                        log::warn!(
+                            "transaction #{} is reorganized out of block #{}, back to pending",
+                            hash,
+                            block_number
+                        );
+                        start = Instant::now();
+                    }
+                    if start.elapsed() >= *pending_timeout {
+                        return Ok(KoTransactionStatus::Pending);
+                    }
                 }
             }
         }
-        Ok(())
     }
 }
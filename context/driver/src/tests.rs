@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ko_protocol::ckb_jsonrpc_types::{
+    BlockNumber, BlockView, CellWithStatus, HeaderView, JsonBytes, OutPoint, OutputsValidator,
+    Transaction, TransactionWithStatus, TxStatus,
+};
+use ko_protocol::ckb_sdk::rpc::ckb_indexer::{Cell, Pagination, SearchKey};
+use ko_protocol::ckb_types::core::{self, BlockBuilder};
+use ko_protocol::ckb_types::prelude::{Pack, Unpack};
+use ko_protocol::secp256k1::SecretKey;
+use ko_protocol::traits::{CkbClient, Driver, RPC};
+use ko_protocol::types::context::{KoFeeRateStatistics, KoTransactionStatus};
+use ko_protocol::{async_trait, tokio, H256};
+
+use crate::DriverImpl;
+
+// canonical chain and the block committing transaction, which moves forward at each poll
+struct ChainState {
+    canonical: Vec<core::BlockView>,
+    committed_in: Option<core::BlockView>,
+}
+
+#[derive(Clone, Default)]
+struct MockChain {
+    states: Arc<Mutex<VecDeque<ChainState>>>,
+    current: Arc<Mutex<Option<ChainState>>>,
+}
+
+impl MockChain {
+    fn new(states: Vec<ChainState>) -> Self {
+        MockChain {
+            states: Arc::new(Mutex::new(states.into())),
+            current: Arc::default(),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.states.lock().unwrap().is_empty()
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&ChainState) -> T) -> T {
+        f(self.current.lock().unwrap().as_ref().unwrap())
+    }
+}
+
+fn block(number: u64, fork: u128) -> core::BlockView {
+    BlockBuilder::default()
+        .number(number.pack())
+        .nonce(fork.pack())
+        .build()
+}
+
+fn chain(fork: u128, tip: u64) -> Vec<core::BlockView> {
+    (0..=tip)
+        .map(|number| block(number, if number == 0 { 0 } else { fork }))
+        .collect()
+}
+
+#[async_trait]
+impl CkbClient for MockChain {
+    fn get_block_by_number(&self, _number: BlockNumber) -> RPC<BlockView> {
+        unimplemented!()
+    }
+
+    fn get_block(&self, hash: &H256) -> RPC<BlockView> {
+        let hash = hash.as_bytes().to_vec();
+        let block = self.read(|state| {
+            state
+                .canonical
+                .iter()
+                .chain(state.committed_in.iter())
+                .find(|block| block.hash().raw_data().to_vec() == hash)
+                .cloned()
+                .unwrap()
+        });
+        Box::pin(async move { Ok(block.into()) })
+    }
+
+    fn get_tip_header(&self) -> RPC<HeaderView> {
+        let header = self.read(|state| state.canonical.last().unwrap().header());
+        Box::pin(async move { Ok(header.into()) })
+    }
+
+    fn get_header_by_number(&self, number: BlockNumber) -> RPC<HeaderView> {
+        let number: u64 = number.into();
+        let header = self.read(|state| state.canonical[number as usize].header());
+        Box::pin(async move { Ok(header.into()) })
+    }
+
+    fn get_transaction(&self, _hash: &H256) -> RPC<Option<TransactionWithStatus>> {
+        let state = self.states.lock().unwrap().pop_front();
+        *self.current.lock().unwrap() = Some(state.expect("chain states exhausted"));
+        let tx_status = self.read(|state| match &state.committed_in {
+            Some(block) => TxStatus::committed(block.hash().unpack()),
+            None => TxStatus::pending(),
+        });
+        let mut tx = TransactionWithStatus::with_pending(None);
+        tx.tx_status = tx_status;
+        Box::pin(async move { Ok(Some(tx)) })
+    }
+
+    fn get_live_cell(&self, _out_point: &OutPoint, _with_data: bool) -> RPC<CellWithStatus> {
+        unimplemented!()
+    }
+
+    fn send_transaction(
+        &self,
+        _tx: &Transaction,
+        _outputs_validator: Option<OutputsValidator>,
+    ) -> RPC<H256> {
+        unimplemented!()
+    }
+
+    fn get_txs_by_hashes(&self, _hash: Vec<H256>) -> RPC<Vec<Option<TransactionWithStatus>>> {
+        unimplemented!()
+    }
+
+    fn get_fee_rate_statistics(&self) -> RPC<Option<KoFeeRateStatistics>> {
+        unimplemented!()
+    }
+
+    fn fetch_live_cells(
+        &self,
+        _search_key: SearchKey,
+        _limit: u32,
+        _cursor: Option<JsonBytes>,
+    ) -> RPC<Pagination<Cell>> {
+        unimplemented!()
+    }
+}
+
+#[tokio::test]
+async fn reorganized_commit_waits_confirms_on_canonical_chain() {
+    let privkey = SecretKey::from_slice(&[1u8; 32]).unwrap();
+    let hash = H256::from([1u8; 32]);
+    let state = |canonical, committed_in| ChainState {
+        canonical,
+        committed_in,
+    };
+
+    // committed in fork a, which is then replaced by fork b and committed again
+    let rpc_client = MockChain::new(vec![
+        state(chain(1, 1), Some(block(1, 1))),
+        state(chain(2, 2), Some(block(1, 1))),
+        state(chain(2, 2), None),
+        state(chain(2, 3), Some(block(3, 2))),
+        state(chain(2, 4), Some(block(3, 2))),
+        state(chain(2, 5), Some(block(3, 2))),
+    ]);
+    let mut driver = DriverImpl::new(&rpc_client, &privkey);
+    let status = driver
+        .wait_transaction_committed(&hash, &Duration::ZERO, 2, &Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(status, KoTransactionStatus::Committed);
+    assert!(rpc_client.is_exhausted());
+
+    // transaction which falls back into pool is pending again
+    let rpc_client = MockChain::new(vec![
+        state(chain(1, 1), Some(block(1, 1))),
+        state(chain(2, 1), None),
+    ]);
+    let mut driver = DriverImpl::new(&rpc_client, &privkey);
+    let status = driver
+        .wait_transaction_committed(&hash, &Duration::ZERO, 5, &Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(status, KoTransactionStatus::Pending);
+    assert!(rpc_client.is_exhausted());
+}
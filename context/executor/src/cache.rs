@@ -0,0 +1,78 @@
+use std::sync::Mutex;
+
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::packed::OutPoint;
+use ko_protocol::serde_json;
+use ko_protocol::types::assembler::{KoContractAbi, KoLuaModule, KoProject};
+use ko_protocol::KoResult;
+use mlua::Lua;
+
+use crate::error::ExecutorError;
+use crate::module::CompiledModules;
+
+/// Values derived from project and global cell which are reused across calls, including the lua
+/// vm with project code loaded, which is rolled back to its snapshot after every call
+#[derive(Default)]
+pub struct ExecutorCache {
+    // lua vm which has loaded project, keyed by the outpoint of project cell and the modules
+    // it may require, since a module cell can be upgraded under the same project cell
+    project: Mutex<Option<(OutPoint, Vec<KoLuaModule>, Lua)>>,
+    // decoded global JSON, keyed by the raw global data
+    global: Mutex<Option<(Bytes, serde_json::Value)>>,
+    // public functions of project, keyed by the outpoint of project cell
+    abi: Mutex<Option<(OutPoint, KoContractAbi)>>,
+    // compiled lua modules which project requires
+    pub modules: CompiledModules,
+}
+
+impl ExecutorCache {
+    pub fn contract_abi(
+        &self,
+        project: &KoProject,
+        collect: impl FnOnce() -> KoResult<KoContractAbi>,
+    ) -> KoResult<KoContractAbi> {
+        let out_point = project.cell_dep.out_point();
+        let mut cache = self.abi.lock().unwrap();
+        if let Some((cached_out_point, abi)) = cache.as_ref() {
+            if cached_out_point == &out_point {
+                return Ok(abi.clone());
+            }
+        }
+        let abi = collect()?;
+        *cache = Some((out_point, abi.clone()));
+        Ok(abi)
+    }
+
+    /// Take the cached lua vm out if it has loaded the same project, so a concurrent call
+    /// loads its own one
+    pub fn take_project(&self, project: &KoProject) -> Option<Lua> {
+        match self.project.lock().unwrap().take() {
+            Some((out_point, modules, lua))
+                if out_point == project.cell_dep.out_point() && modules == project.modules =>
+            {
+                Some(lua)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn put_project(&self, project: &KoProject, lua: Lua) {
+        let out_point = project.cell_dep.out_point();
+        *self.project.lock().unwrap() = Some((out_point, project.modules.clone(), lua));
+    }
+
+    pub fn decode_global(&self, global_data: &Bytes) -> KoResult<serde_json::Value> {
+        let mut cache = self.global.lock().unwrap();
+        if let Some((cached_data, value)) = cache.as_ref() {
+            if cached_data == global_data {
+                return Ok(value.clone());
+            }
+        }
+        let json_string = String::from_utf8(global_data.to_vec())
+            .map_err(|_| ExecutorError::InvalidUTF8FormatForGlobalData)?;
+        let value: serde_json::Value = serde_json::from_str(&json_string)
+            .map_err(|_| ExecutorError::InvalidJsonFormatForGlobalData(json_string))?;
+        *cache = Some((global_data.clone(), value.clone()));
+        Ok(value)
+    }
+}
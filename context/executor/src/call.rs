@@ -0,0 +1,468 @@
+use ko_protocol::types::assembler::KoContractAbi;
+use ko_protocol::KoResult;
+use mlua::{Lua, MultiValue, Value};
+
+use crate::error::ExecutorError;
+
+// max nesting depth of table literals in arguments
+const MAX_TABLE_DEPTH: usize = 32;
+
+/// Literal argument of contract function call
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallArgument {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(Vec<u8>),
+    Table(Vec<(Option<CallArgument>, CallArgument)>),
+}
+
+/// Contract function call in form of `name(literal, ...)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: Vec<CallArgument>,
+}
+
+impl CallArgument {
+    pub fn to_lua<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        let value = match self {
+            CallArgument::Nil => Value::Nil,
+            CallArgument::Boolean(value) => Value::Boolean(*value),
+            CallArgument::Integer(value) => Value::Integer(*value),
+            CallArgument::Number(value) => Value::Number(*value),
+            CallArgument::String(value) => Value::String(lua.create_string(value)?),
+            CallArgument::Table(fields) => {
+                let table = lua.create_table()?;
+                let mut index = 0i64;
+                for (key, value) in fields {
+                    let value = value.to_lua(lua)?;
+                    if let Some(key) = key {
+                        table.raw_set(key.to_lua(lua)?, value)?;
+                    } else {
+                        index += 1;
+                        table.raw_set(index, value)?;
+                    }
+                }
+                Value::Table(table)
+            }
+        };
+        Ok(value)
+    }
+}
+
+impl FunctionCall {
+    pub fn to_lua_args<'lua>(&self, lua: &'lua Lua) -> mlua::Result<MultiValue<'lua>> {
+        self.arguments
+            .iter()
+            .map(|argument| argument.to_lua(lua))
+            .collect::<mlua::Result<Vec<_>>>()
+            .map(MultiValue::from_vec)
+    }
+}
+
+pub fn parse_function_call(function_call: &str) -> KoResult<FunctionCall> {
+    let mut parser = Parser::new(function_call.as_bytes());
+    let call = parser
+        .parse_call()
+        .map_err(|reason| ExecutorError::InvalidFunctionCall(function_call.into(), reason))?;
+    Ok(call)
+}
+
+/// Parse `function_call` and make sure it calls a contract function with matched arguments,
+/// optional parameters must be passed as explicit `nil` and extra arguments are only allowed by
+/// variadic function
+pub fn check_function_call(abi: &KoContractAbi, function_call: &str) -> KoResult<FunctionCall> {
+    let call = parse_function_call(function_call)?;
+    let function = abi
+        .get(&call.name)
+        .ok_or_else(|| ExecutorError::UnknownContractFunction(call.name.clone()))?;
+    let (expected, given) = (function.params as usize, call.arguments.len());
+    if given < expected {
+        let reason = format!("expect at least {} arguments, got {}", expected, given);
+        return Err(ExecutorError::InvalidFunctionCall(function_call.into(), reason).into());
+    }
+    if given > expected && !function.variadic {
+        let reason = format!("expect at most {} arguments, got {}", expected, given);
+        return Err(ExecutorError::InvalidFunctionCall(function_call.into(), reason).into());
+    }
+    Ok(call)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Parser { bytes, offset: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.offset).cloned()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<u8> {
+        self.bytes.get(self.offset + n).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if !byte.is_ascii_whitespace() {
+                break;
+            }
+            self.offset += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.offset += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", byte as char)))
+        }
+    }
+
+    fn unexpected(&self, expect: &str) -> String {
+        match self.peek() {
+            Some(byte) => format!(
+                "expect {} at offset {}, found '{}'",
+                expect,
+                self.offset,
+                (byte as char).escape_default()
+            ),
+            None => format!("expect {} at offset {}, found end", expect, self.offset),
+        }
+    }
+
+    fn parse_call(&mut self) -> Result<FunctionCall, String> {
+        self.skip_whitespace();
+        let name = self
+            .parse_name()
+            .ok_or_else(|| self.unexpected("function name"))?;
+        self.expect(b'(')?;
+        let mut arguments = vec![];
+        self.skip_whitespace();
+        if self.peek() != Some(b')') {
+            loop {
+                arguments.push(self.parse_value(0)?);
+                self.skip_whitespace();
+                if self.peek() == Some(b',') {
+                    self.offset += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        self.expect(b')')?;
+        self.skip_whitespace();
+        if self.peek() == Some(b';') {
+            self.offset += 1;
+            self.skip_whitespace();
+        }
+        if self.peek().is_some() {
+            return Err(self.unexpected("end of function call"));
+        }
+        Ok(FunctionCall { name, arguments })
+    }
+
+    fn parse_name(&mut self) -> Option<String> {
+        let start = self.offset;
+        match self.peek() {
+            Some(byte) if byte.is_ascii_alphabetic() || byte == b'_' => self.offset += 1,
+            _ => return None,
+        }
+        while let Some(byte) = self.peek() {
+            if !byte.is_ascii_alphanumeric() && byte != b'_' {
+                break;
+            }
+            self.offset += 1;
+        }
+        let name = String::from_utf8(self.bytes[start..self.offset].to_vec()).unwrap();
+        if is_lua_keyword(&name) {
+            self.offset = start;
+            return None;
+        }
+        Some(name)
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<CallArgument, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.parse_table(depth + 1),
+            Some(b'"') | Some(b'\'') => self.parse_quoted_string().map(CallArgument::String),
+            Some(b'[') if matches!(self.peek_at(1), Some(b'[') | Some(b'=')) => {
+                self.parse_long_string().map(CallArgument::String)
+            }
+            Some(byte) if byte.is_ascii_digit() || byte == b'-' || byte == b'.' => {
+                self.parse_number()
+            }
+            Some(byte) if byte.is_ascii_alphabetic() => {
+                let start = self.offset;
+                while let Some(byte) = self.peek() {
+                    if !byte.is_ascii_alphanumeric() && byte != b'_' {
+                        break;
+                    }
+                    self.offset += 1;
+                }
+                match &self.bytes[start..self.offset] {
+                    b"nil" => Ok(CallArgument::Nil),
+                    b"true" => Ok(CallArgument::Boolean(true)),
+                    b"false" => Ok(CallArgument::Boolean(false)),
+                    _ => {
+                        self.offset = start;
+                        Err(self.unexpected("literal value"))
+                    }
+                }
+            }
+            _ => Err(self.unexpected("literal value")),
+        }
+    }
+
+    fn parse_table(&mut self, depth: usize) -> Result<CallArgument, String> {
+        if depth > MAX_TABLE_DEPTH {
+            return Err(format!("table nested deeper than {}", MAX_TABLE_DEPTH));
+        }
+        self.expect(b'{')?;
+        let mut fields = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(b'}') {
+                break;
+            }
+            let key = match self.peek() {
+                Some(b'[') if !matches!(self.peek_at(1), Some(b'[') | Some(b'=')) => {
+                    self.offset += 1;
+                    let key = self.parse_value(depth)?;
+                    match key {
+                        CallArgument::Nil | CallArgument::Table(_) => {
+                            return Err(format!("invalid table key at offset {}", self.offset))
+                        }
+                        CallArgument::Number(value) if value.is_nan() => {
+                            return Err(format!("invalid table key at offset {}", self.offset))
+                        }
+                        _ => {}
+                    }
+                    self.expect(b']')?;
+                    self.expect(b'=')?;
+                    Some(key)
+                }
+                _ => {
+                    let start = self.offset;
+                    match self.parse_name() {
+                        Some(name) => {
+                            self.skip_whitespace();
+                            if self.peek() == Some(b'=') {
+                                self.offset += 1;
+                                Some(CallArgument::String(name.into_bytes()))
+                            } else {
+                                self.offset = start;
+                                None
+                            }
+                        }
+                        None => None,
+                    }
+                }
+            };
+            let value = self.parse_value(depth)?;
+            fields.push((key, value));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') | Some(b';') => self.offset += 1,
+                _ => break,
+            }
+        }
+        self.expect(b'}')?;
+        Ok(CallArgument::Table(fields))
+    }
+
+    fn parse_number(&mut self) -> Result<CallArgument, String> {
+        let start = self.offset;
+        let negative = self.peek() == Some(b'-');
+        if negative {
+            self.offset += 1;
+            self.skip_whitespace();
+        }
+        let digits_start = self.offset;
+        if self.peek() == Some(b'0') && matches!(self.peek_at(1), Some(b'x') | Some(b'X')) {
+            self.offset += 2;
+            let hex_start = self.offset;
+            while matches!(self.peek(), Some(byte) if byte.is_ascii_hexdigit()) {
+                self.offset += 1;
+            }
+            let hex = std::str::from_utf8(&self.bytes[hex_start..self.offset]).unwrap();
+            // like lua, hexadecimal integers wrap around
+            let value = u64::from_str_radix(hex, 16)
+                .map_err(|_| format!("invalid hexadecimal number at offset {}", start))?;
+            let value = value as i64;
+            return Ok(CallArgument::Integer(if negative {
+                value.wrapping_neg()
+            } else {
+                value
+            }));
+        }
+        let mut is_float = false;
+        while let Some(byte) = self.peek() {
+            match byte {
+                b'0'..=b'9' => {}
+                b'.' => is_float = true,
+                b'e' | b'E' => {
+                    is_float = true;
+                    if matches!(self.peek_at(1), Some(b'+') | Some(b'-')) {
+                        self.offset += 1;
+                    }
+                }
+                _ => break,
+            }
+            self.offset += 1;
+        }
+        if matches!(self.peek(), Some(byte) if byte.is_ascii_alphanumeric() || byte == b'_') {
+            return Err(self.unexpected("number"));
+        }
+        let digits = std::str::from_utf8(&self.bytes[digits_start..self.offset]).unwrap();
+        if !is_float {
+            let literal = if negative {
+                format!("-{}", digits)
+            } else {
+                digits.to_owned()
+            };
+            if let Ok(value) = literal.parse::<i64>() {
+                return Ok(CallArgument::Integer(value));
+            }
+        }
+        let value = digits
+            .parse::<f64>()
+            .map_err(|_| format!("invalid number at offset {}", start))?;
+        Ok(CallArgument::Number(if negative { -value } else { value }))
+    }
+
+    fn parse_quoted_string(&mut self) -> Result<Vec<u8>, String> {
+        let quote = self.peek().unwrap();
+        self.offset += 1;
+        let mut string = vec![];
+        loop {
+            let byte = self.peek().ok_or_else(|| "unfinished string".to_owned())?;
+            self.offset += 1;
+            match byte {
+                b'\n' | b'\r' => return Err("unfinished string".into()),
+                b'\\' => self.parse_escape(&mut string)?,
+                byte if byte == quote => break,
+                byte => string.push(byte),
+            }
+        }
+        Ok(string)
+    }
+
+    fn parse_escape(&mut self, string: &mut Vec<u8>) -> Result<(), String> {
+        let invalid = |offset: usize| format!("invalid escape sequence at offset {}", offset);
+        let byte = self.peek().ok_or_else(|| invalid(self.offset))?;
+        self.offset += 1;
+        match byte {
+            b'n' => string.push(b'\n'),
+            b't' => string.push(b'\t'),
+            b'r' => string.push(b'\r'),
+            b'a' => string.push(0x07),
+            b'b' => string.push(0x08),
+            b'f' => string.push(0x0c),
+            b'v' => string.push(0x0b),
+            b'\\' | b'"' | b'\'' | b'\n' => string.push(byte),
+            b'z' => self.skip_whitespace(),
+            b'x' => {
+                let hex = self
+                    .bytes
+                    .get(self.offset..self.offset + 2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| invalid(self.offset))?;
+                self.offset += 2;
+                string.push(hex);
+            }
+            b'u' => {
+                self.expect(b'{').map_err(|_| invalid(self.offset))?;
+                let start = self.offset;
+                while matches!(self.peek(), Some(byte) if byte.is_ascii_hexdigit()) {
+                    self.offset += 1;
+                }
+                let code = std::str::from_utf8(&self.bytes[start..self.offset])
+                    .ok()
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| invalid(start))?;
+                self.expect(b'}').map_err(|_| invalid(self.offset))?;
+                let mut buffer = [0u8; 4];
+                string.extend_from_slice(code.encode_utf8(&mut buffer).as_bytes());
+            }
+            b'0'..=b'9' => {
+                let mut value = (byte - b'0') as u32;
+                for _ in 0..2 {
+                    match self.peek() {
+                        Some(byte) if byte.is_ascii_digit() => {
+                            value = value * 10 + (byte - b'0') as u32;
+                            self.offset += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                let value = u8::try_from(value).map_err(|_| invalid(self.offset))?;
+                string.push(value);
+            }
+            _ => return Err(invalid(self.offset - 1)),
+        }
+        Ok(())
+    }
+
+    fn parse_long_string(&mut self) -> Result<Vec<u8>, String> {
+        self.offset += 1;
+        let mut level = 0;
+        while self.peek() == Some(b'=') {
+            level += 1;
+            self.offset += 1;
+        }
+        self.expect(b'[')?;
+        // like lua, skip the first newline of long string
+        if self.peek() == Some(b'\n') {
+            self.offset += 1;
+        }
+        let close = [b"]".to_vec(), vec![b'='; level], b"]".to_vec()].concat();
+        let rest = &self.bytes[self.offset..];
+        let end = rest
+            .windows(close.len())
+            .position(|window| window == close.as_slice())
+            .ok_or_else(|| "unfinished long string".to_owned())?;
+        let string = rest[..end].to_vec();
+        self.offset += end + close.len();
+        Ok(string)
+    }
+}
+
+fn is_lua_keyword(name: &str) -> bool {
+    matches!(
+        name,
+        "and"
+            | "break"
+            | "do"
+            | "else"
+            | "elseif"
+            | "end"
+            | "false"
+            | "for"
+            | "function"
+            | "goto"
+            | "if"
+            | "in"
+            | "local"
+            | "nil"
+            | "not"
+            | "or"
+            | "repeat"
+            | "return"
+            | "then"
+            | "true"
+            | "until"
+            | "while"
+    )
+}
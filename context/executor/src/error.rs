@@ -1,5 +1,6 @@
 use ko_protocol::derive_more::Display;
 use ko_protocol::types::error::{ErrorType, KoError};
+use ko_protocol::H256;
 use mlua::Error;
 
 #[derive(Display, Debug)]
@@ -25,6 +26,12 @@ pub enum ExecutorError {
     #[display(fmt = "Celldep data is not a JSON string")]
     InvalidJsonFormatForCelldepData,
 
+    #[display(fmt = "Invalid function call, call = {}, reason = {}", _0, _1)]
+    InvalidFunctionCall(String, String),
+
+    #[display(fmt = "Function is not provided by contract, name = {}", _0)]
+    UnknownContractFunction(String),
+
     #[display(fmt = "Invalid request lua code, code = {}, reason = {}", _0, _1)]
     ErrorLoadRequestLuaCode(String, String),
 
@@ -40,12 +47,56 @@ pub enum ExecutorError {
     #[display(fmt = "The function call result must be Nil or Table")]
     UnexpectedFunctionCallResult,
 
+    #[display(fmt = "The `migrate` must be a function which returns Nil or Table")]
+    UnexpectedMigrationResult,
+
     #[display(fmt = "Lua code execution error = {}", _0)]
     LuaVmError(String),
+
+    #[display(fmt = "Instruction budget exhausted, code = {}, limit = {}", _0, _1)]
+    InstructionBudgetExhausted(String, u64),
+
+    #[display(fmt = "Lua memory limit exceeded, reason = {}", _0)]
+    MemoryLimitExceeded(String),
+
+    #[display(fmt = "Lua value can't be encoded into JSON, reason = {}", _0)]
+    InvalidJsonEncoding(String),
+
+    #[display(fmt = "JSON data can't be decoded into lua, reason = {}", _0)]
+    InvalidJsonDecoding(String),
+
+    #[display(fmt = "View call must not change KOC, code = {}", _0)]
+    ViewMutatesContext(String),
+
+    #[display(fmt = "Invalid ckb amount, reason = {}", _0)]
+    InvalidCkbAmount(String),
+
+    #[display(fmt = "Random seeds count mismatch, requests = {}, seeds = {}", _0, _1)]
+    RandomSeedsCountMismatch(usize, usize),
+
+    #[display(fmt = "Lua execution aborted, reason = {}", _0)]
+    ExecutionAborted(String),
+
+    #[display(
+        fmt = "Module code doesn't match pinned data hash, module = {}, hash = {}",
+        _0,
+        _1
+    )]
+    ModuleDataHashMismatch(String, H256),
 }
 
 impl std::error::Error for ExecutorError {}
 
+impl ExecutorError {
+    // outcome of these depends on the limits of local config rather than the contract
+    pub fn exceeds_limits(&self) -> bool {
+        matches!(
+            self,
+            ExecutorError::InstructionBudgetExhausted(..) | ExecutorError::MemoryLimitExceeded(_)
+        )
+    }
+}
+
 impl From<ExecutorError> for KoError {
     fn from(error: ExecutorError) -> KoError {
         KoError::new(ErrorType::Executor, Box::new(error))
@@ -54,6 +105,18 @@ impl From<ExecutorError> for KoError {
 
 impl From<Error> for ExecutorError {
     fn from(error: Error) -> Self {
-        ExecutorError::LuaVmError(error.to_string())
+        if is_memory_error(&error) {
+            ExecutorError::MemoryLimitExceeded(error.to_string())
+        } else {
+            ExecutorError::LuaVmError(error.to_string())
+        }
+    }
+}
+
+pub fn is_memory_error(error: &Error) -> bool {
+    match error {
+        Error::MemoryError(_) => true,
+        Error::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
     }
 }
@@ -1,17 +1,263 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use ko_protocol::ckb_types::bytes::Bytes;
 use ko_protocol::ckb_types::packed::Script;
-use ko_protocol::types::assembler::{KoCellOutput, KoRequest};
+use ko_protocol::types::assembler::{
+    KoCellOutput, KoContractAbi, KoEvent, KoFunctionAbi, KoProject, KoProjectManifest, KoRequest,
+};
 use ko_protocol::types::context::KoContextGlobalCell;
 use ko_protocol::{hex, serde_json, KoResult};
-use mlua::{Lua, LuaSerdeExt, Table, Value};
+use mlua::{ChunkMode, Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value};
 
-use crate::error::ExecutorError;
+use crate::call::parse_function_call;
+use crate::error::{is_memory_error, ExecutorError};
+use crate::json::{decode_json, encode_canonical_json};
 use crate::luac;
 
+// the constructor only runs once while deploying project
+const CONTRACT_CONSTRUCTOR: &str = "construct";
+
+// the migration only runs once in the first drive after project upgraded
+const CONTRACT_MIGRATION: &str = "migrate";
+
+// how many lua instructions run between two budget checks
+const INSTRUCTION_HOOK_STEP: u32 = 1000;
+
+// re-raise errors caught by `pcall`/`xpcall`/`coroutine.resume` if they are fatal to the
+// request (exhausted instruction budget or memory limit), so a contract can't swallow them,
+// lua skips the message handler only for memory errors, so `handled` tells them apart
+const FATAL_ERROR_GUARD: &str = r#"
+    local raise_fatal = ...
+    local pcall, xpcall, resume = pcall, xpcall, coroutine.resume
+    local function guard(state, ok, ...)
+        if not ok then
+            raise_fatal((...), state.handled)
+        end
+        return ok, ...
+    end
+    _G.pcall = function (f, ...)
+        local state = {}
+        local function handler(error)
+            state.handled = true
+            return error
+        end
+        return guard(state, xpcall(f, handler, ...))
+    end
+    _G.xpcall = function (f, msgh, ...)
+        local state = {}
+        local function handler(error)
+            state.handled = true
+            return msgh(error)
+        end
+        return guard(state, xpcall(f, handler, ...))
+    end
+    coroutine.resume = function (...) return guard({ handled = true }, resume(...)) end
+"#;
+
+// max events which a single request can emit through `KOC.emit`
+const MAX_EVENTS_PER_REQUEST: usize = 64;
+
+// shannons of one CKB
+const SHANNONS_PER_CKB: u64 = 100_000_000;
+
+// the largest shannon amount which a float can hold without losing precision
+const MAX_EXACT_FLOAT_SHANNONS: f64 = (1u64 << 53) as f64;
+
+struct InstructionMeter {
+    budget: u64,
+    used: u64,
+}
+
+impl InstructionMeter {
+    fn is_exhausted(&self) -> bool {
+        self.used >= self.budget
+    }
+}
+
+pub fn apply_execution_limits(lua: &Lua, budget: u64, memory_limit: usize) -> KoResult<()> {
+    luac!(lua.set_memory_limit(memory_limit));
+    lua.set_app_data(InstructionMeter { budget, used: 0 });
+    let raise_fatal = luac!(lua.create_function(|lua, (error, handled): (Value, bool)| {
+        if let Some(meter) = lua.app_data_ref::<InstructionMeter>() {
+            if meter.is_exhausted() {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "instruction budget {} exhausted",
+                    meter.budget
+                )));
+            }
+        }
+        match error {
+            Value::Error(error) if is_memory_error(&error) => Err(error),
+            error if !handled => {
+                let message = lua.coerce_string(error)?;
+                let message = message.map(|message| message.to_string_lossy().into_owned());
+                Err(mlua::Error::MemoryError(message.unwrap_or_default()))
+            }
+            _ => Ok(()),
+        }
+    }));
+    luac!(luac!(lua.load(FATAL_ERROR_GUARD).set_name("=guard")).call::<_, ()>(raise_fatal));
+    Ok(())
+}
+
+pub fn start_instruction_meter(lua: &Lua) -> KoResult<()> {
+    if let Some(mut meter) = lua.app_data_mut::<InstructionMeter>() {
+        meter.used = 0;
+    }
+    let triggers = HookTriggers::every_nth_instruction(INSTRUCTION_HOOK_STEP);
+    luac!(lua.set_hook(triggers, |lua, _| {
+        if let Some(mut meter) = lua.app_data_mut::<InstructionMeter>() {
+            meter.used += INSTRUCTION_HOOK_STEP as u64;
+            if meter.is_exhausted() {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "instruction budget {} exhausted",
+                    meter.budget
+                )));
+            }
+        }
+        Ok(())
+    }));
+    Ok(())
+}
+
+// returns the exhausted budget if the request ran out of instructions
+pub fn stop_instruction_meter(lua: &Lua) -> Option<u64> {
+    lua.remove_hook();
+    lua.app_data_ref::<InstructionMeter>()
+        .filter(|meter| meter.is_exhausted())
+        .map(|meter| meter.budget)
+}
+
+// names of global functions defined by project code, which are callable from requests
+struct ContractFunctions(HashSet<String>);
+
+// events emitted by the running request
+#[derive(Default)]
+struct EmittedEvents(Vec<KoEvent>);
+
+/// Inject `KOC.emit(name, data)` which records an event with canonical JSON data
+pub fn koc_inject_emit(lua: &Lua, context: &Table) -> KoResult<()> {
+    let emit = luac!(lua.create_function(|lua, (name, data): (String, Value)| {
+        if name.is_empty() {
+            return Err(mlua::Error::RuntimeError("event name is empty".into()));
+        }
+        let data = encode_canonical_json(lua, &data)
+            .map_err(|err| mlua::Error::RuntimeError(err.to_string()))?;
+        let mut events = lua.app_data_mut::<EmittedEvents>().expect("emitted events");
+        if events.0.len() >= MAX_EVENTS_PER_REQUEST {
+            return Err(mlua::Error::RuntimeError(format!(
+                "too many events, limit = {}",
+                MAX_EVENTS_PER_REQUEST
+            )));
+        }
+        events.0.push(KoEvent::new(name, data));
+        Ok(())
+    }));
+    luac!(context.set("emit", emit));
+    lua.set_app_data(EmittedEvents::default());
+    Ok(())
+}
+
+fn take_emitted_events(lua: &Lua) -> Vec<KoEvent> {
+    lua.app_data_mut::<EmittedEvents>()
+        .map(|mut events| std::mem::take(&mut events.0))
+        .unwrap_or_default()
+}
+
+pub fn snapshot_global_functions(lua: &Lua) -> KoResult<HashMap<String, Function<'_>>> {
+    let mut functions = HashMap::new();
+    for pair in lua.globals().pairs::<Value, Value>() {
+        if let (Value::String(name), Value::Function(function)) = luac!(pair) {
+            functions.insert(luac!(name.to_str()).to_owned(), function);
+        }
+    }
+    Ok(functions)
+}
+
+pub fn register_contract_functions(
+    lua: &Lua,
+    builtins: &HashMap<String, Function>,
+) -> KoResult<()> {
+    let functions = snapshot_global_functions(lua)?
+        .into_iter()
+        .filter(|(name, function)| {
+            name != CONTRACT_CONSTRUCTOR
+                && name != CONTRACT_MIGRATION
+                && builtins.get(name) != Some(function)
+        })
+        .map(|(name, _)| name)
+        .collect();
+    lua.set_app_data(ContractFunctions(functions));
+    Ok(())
+}
+
+/// Collect contract functions along with their arities
+pub fn collect_contract_abi(lua: &Lua) -> KoResult<KoContractAbi> {
+    let mut names = lua
+        .app_data_ref::<ContractFunctions>()
+        .map(|functions| functions.0.iter().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    names.sort();
+    let mut functions = vec![];
+    for name in names {
+        let function: Function = luac!(lua.globals().get(name.as_str()));
+        functions.push(read_function_abi(&name, &function)?);
+    }
+    Ok(KoContractAbi::new(functions))
+}
+
+pub fn read_function_abi(name: &str, function: &Function) -> KoResult<KoFunctionAbi> {
+    let (params, variadic) = read_function_arity(function).map_err(|error| {
+        ExecutorError::LuaVmError(format!("bad arity of `{}`, error = {}", name, error))
+    })?;
+    Ok(KoFunctionAbi::new(name.into(), params, variadic))
+}
+
+// `debug` library is not reachable in sandbox, so the dumped function is loaded into a
+// separate state for `debug.getinfo`, where it is never called
+fn read_function_arity(function: &Function) -> mlua::Result<(u8, bool)> {
+    let lua = unsafe { Lua::unsafe_new_with(StdLib::DEBUG, LuaOptions::default()) };
+    let function = lua
+        .load(&function.dump(true))
+        .set_mode(ChunkMode::Binary)
+        .into_function()?;
+    let debug: Table = lua.globals().get("debug")?;
+    let info: Table = debug.get::<_, Function>("getinfo")?.call((function, "u"))?;
+    Ok((info.get("nparams")?, info.get("isvararg")?))
+}
+
+// names which manifest allows to call, views can call declared views besides functions, and
+// nothing is narrowed down if manifest doesn't declare any function
+fn declared_functions(manifest: Option<&KoProjectManifest>, view: bool) -> Option<Vec<&str>> {
+    let manifest = manifest.filter(|manifest| !manifest.functions.is_empty())?;
+    let mut names = manifest
+        .functions
+        .iter()
+        .map(|function| function.name.as_str())
+        .collect::<Vec<_>>();
+    if view {
+        names.extend(manifest.views.iter().map(String::as_str));
+    }
+    Some(names)
+}
+
+fn get_contract_function<'lua>(
+    lua: &'lua Lua,
+    name: &str,
+    declared: Option<Vec<&str>>,
+) -> KoResult<Function<'lua>> {
+    let registered = lua
+        .app_data_ref::<ContractFunctions>()
+        .map(|functions| functions.0.contains(name))
+        .unwrap_or(false);
+    let declared = declared.is_none_or(|names| names.contains(&name));
+    if !registered || !declared {
+        return Err(ExecutorError::UnknownContractFunction(name.into()).into());
+    }
+    Ok(luac!(lua.globals().get(name)))
+}
+
 fn koc_fill_candidates(context: &Table, candidates: &[Script]) -> KoResult<()> {
     let candidates = candidates
         .iter()
@@ -31,7 +277,7 @@ fn koc_fill_inputs(lua: &Lua, context: &Table, inputs: &[(Script, Bytes)]) -> Ko
             if !data.is_empty() {
                 let value: serde_json::Value = serde_json::from_slice(data)
                     .map_err(|_| ExecutorError::InvalidJsonFormatForPersonalData)?;
-                if let mlua::Value::Table(data) = luac!(lua.to_value(&value)) {
+                if let mlua::Value::Table(data) = decode_json(lua, &value)? {
                     luac!(input.set("data", data));
                 } else {
                     return Err(ExecutorError::InvalidJsonFormatForPersonalData.into());
@@ -50,7 +296,7 @@ fn koc_fill_components(lua: &Lua, context: &Table, components: &[Bytes]) -> KoRe
         .map(|data| {
             let value: serde_json::Value = serde_json::from_slice(data)
                 .map_err(|_| ExecutorError::InvalidJsonFormatForCelldepData)?;
-            Ok(luac!(lua.to_value(&value)))
+            decode_json(lua, &value)
         })
         .collect::<KoResult<Vec<_>>>()?;
     luac!(context.set("components", components));
@@ -72,8 +318,8 @@ fn koc_extract_outputs(lua: &Lua, method_call: &str) -> KoResult<Vec<(String, Op
                 match value {
                     Value::Nil => None,
                     Value::Table(data) => {
-                        let data = serde_json::to_string(&data).unwrap();
-                        Some(Bytes::from(data.as_bytes().to_vec()))
+                        let data = encode_canonical_json(lua, &Value::Table(data))?;
+                        Some(Bytes::from(data.into_bytes()))
                     }
                     _ => Err(ExecutorError::ErrorLoadRequestLuaCode(
                         method_call.to_owned(),
@@ -135,19 +381,185 @@ fn apply_function_call_result(lua: &Lua, result: &Value) -> KoResult<()> {
     }
 }
 
+/// Convert the amount passed to `KOC.ckb_deposit`/`KOC.ckb_withdraw` into shannons
+///
+/// The amount is counted in CKB by default, or in shannons if `unit` is "shannon", and it
+/// can be an integer, a decimal string like "12.5" or a float rounded to the nearest shannon
+pub fn ckb_amount_to_shannons(amount: Value, unit: Option<String>) -> mlua::Result<u64> {
+    let scale = match unit.as_deref() {
+        None | Some("ckb") => SHANNONS_PER_CKB,
+        Some("shannon") => 1,
+        Some(unit) => return Err(invalid_ckb_amount(format!("unknown unit {}", unit))),
+    };
+    match amount {
+        Value::Integer(amount) => u64::try_from(amount)
+            .ok()
+            .and_then(|amount| amount.checked_mul(scale))
+            .ok_or_else(|| invalid_ckb_amount(format!("{} is out of range", amount))),
+        Value::Number(amount) => {
+            let shannons = (amount * scale as f64).round();
+            if !(0.0..=MAX_EXACT_FLOAT_SHANNONS).contains(&shannons) {
+                return Err(invalid_ckb_amount(format!(
+                    "{} is out of exact float range, use integer or string instead",
+                    amount
+                )));
+            }
+            if scale == 1 && amount.fract() != 0.0 {
+                return Err(invalid_ckb_amount(format!("{} is not integral", amount)));
+            }
+            Ok(shannons as u64)
+        }
+        Value::String(amount) => {
+            let amount = amount.to_str()?;
+            parse_decimal_amount(amount, scale)
+                .ok_or_else(|| invalid_ckb_amount(format!("\"{}\" is not a valid amount", amount)))
+        }
+        other => Err(invalid_ckb_amount(format!(
+            "{} is not a number or string",
+            other.type_name()
+        ))),
+    }
+}
+
+// parse unsigned decimal string with no more fractional digits than `scale` can hold
+fn parse_decimal_amount(amount: &str, scale: u64) -> Option<u64> {
+    let (integral, fractional) = amount.split_once('.').unwrap_or((amount, ""));
+    let digits = scale.to_string().len() - 1;
+    let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if integral.is_empty() || fractional.len() > digits || !all_digits(integral) {
+        return None;
+    }
+    if !all_digits(fractional) || (amount.contains('.') && fractional.is_empty()) {
+        return None;
+    }
+    let fractional = format!("{:0<width$}", fractional, width = digits);
+    let fractional = if digits > 0 {
+        fractional.parse().ok()?
+    } else {
+        0
+    };
+    integral
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(scale)?
+        .checked_add(fractional)
+}
+
+fn invalid_ckb_amount(reason: String) -> mlua::Error {
+    mlua::Error::external(ExecutorError::InvalidCkbAmount(reason))
+}
+
 pub fn apply_randomseed(lua: &Lua, randomseeds: &[i64; 2]) -> KoResult<()> {
     let math: Table = luac!(lua.globals().get("math"));
     let randomseed: mlua::Function = luac!(math.get("randomseed"));
-    luac!(randomseed.call((randomseeds[0], randomseeds[1])));
+    luac!(randomseed.call::<_, ()>((randomseeds[0], randomseeds[1])));
     // inject randomseeds
     let context: Table = luac!(lua.globals().get("KOC"));
     luac!(context.set("seeds", *randomseeds));
     Ok(())
 }
 
+// run literal function call under instruction budget and memory limit
+fn call_contract_function<'lua>(
+    lua: &'lua Lua,
+    method_call: &str,
+    declared: Option<Vec<&str>>,
+) -> KoResult<Value<'lua>> {
+    let function_call = parse_function_call(method_call)?;
+    let function = get_contract_function(lua, &function_call.name, declared)?;
+    let arguments = luac!(function_call.to_lua_args(lua));
+    start_instruction_meter(lua)?;
+    let result = function.call::<_, Value>(arguments);
+    if let Some(budget) = stop_instruction_meter(lua) {
+        return Err(ExecutorError::InstructionBudgetExhausted(method_call.into(), budget).into());
+    }
+    let result = result.map_err(|err| {
+        if is_memory_error(&err) {
+            ExecutorError::MemoryLimitExceeded(format!("{}, code = {}", err, method_call))
+        } else {
+            ExecutorError::ErrorLoadRequestLuaCode(method_call.into(), err.to_string())
+        }
+    })?;
+    Ok(result)
+}
+
+// snapshot everything in `KOC` that a view call is not allowed to change
+fn snapshot_view_state(lua: &Lua) -> KoResult<(Table<'_>, String)> {
+    let context: Table = luac!(lua.globals().get("KOC"));
+    let state = luac!(lua.create_table());
+    for field in ["owner", "driver", "global", "inputs"] {
+        luac!(state.set(field, luac!(context.get::<_, Value>(field))));
+    }
+    let state = encode_canonical_json(lua, &Value::Table(state))?;
+    Ok((context, state))
+}
+
+/// Run contract function in read-only mode and return its result in JSON format, any
+/// change of `KOC` or call of `KOC.ckb_deposit`/`KOC.ckb_withdraw`/`KOC.emit` fails the view
+pub fn run_view(
+    lua: &Lua,
+    method_call: &str,
+    inputs: &[(Script, Bytes)],
+    manifest: Option<&KoProjectManifest>,
+) -> KoResult<String> {
+    let context: Table = luac!(lua.globals().get("KOC"));
+    koc_fill_candidates(&context, &[])?;
+    koc_fill_inputs(lua, &context, inputs)?;
+    koc_fill_components(lua, &context, &[])?;
+    for name in ["ckb_deposit", "ckb_withdraw", "emit"] {
+        let reject = luac!(lua.create_function(move |_, ()| -> mlua::Result<()> {
+            Err(mlua::Error::RuntimeError(format!(
+                "KOC.{} is not allowed in view call",
+                name
+            )))
+        }));
+        luac!(context.set(name, reject));
+    }
+
+    let (previous_context, previous_state) = snapshot_view_state(lua)?;
+    let result = call_contract_function(lua, method_call, declared_functions(manifest, true))?;
+    let (context, state) = snapshot_view_state(lua)?;
+    if context != previous_context || state != previous_state {
+        return Err(ExecutorError::ViewMutatesContext(method_call.into()).into());
+    }
+    encode_canonical_json(lua, &result)
+}
+
+/// Run `migrate(old_global)` of contract if it's defined, the returned table replaces global
+/// data and `nil` keeps the one it may have changed in place
+pub fn run_migration(lua: &Lua) -> KoResult<()> {
+    let migrate = match luac!(lua.globals().get::<_, Value>(CONTRACT_MIGRATION)) {
+        Value::Function(function) => function,
+        Value::Nil => return Ok(()),
+        _ => return Err(ExecutorError::UnexpectedMigrationResult.into()),
+    };
+    let context: Table = luac!(lua.globals().get("KOC"));
+    let old_global: Value = luac!(context.get("global"));
+    start_instruction_meter(lua)?;
+    let result = migrate.call::<_, Value>(old_global);
+    if let Some(budget) = stop_instruction_meter(lua) {
+        return Err(
+            ExecutorError::InstructionBudgetExhausted(CONTRACT_MIGRATION.into(), budget).into(),
+        );
+    }
+    let result = result.map_err(|err| {
+        if is_memory_error(&err) {
+            ExecutorError::MemoryLimitExceeded(format!("{}, code = {}", err, CONTRACT_MIGRATION))
+        } else {
+            ExecutorError::ErrorLoadRequestLuaCode(CONTRACT_MIGRATION.into(), err.to_string())
+        }
+    })?;
+    match result {
+        Value::Table(global) => luac!(context.set("global", global)),
+        Value::Nil => {}
+        _ => return Err(ExecutorError::UnexpectedMigrationResult.into()),
+    }
+    Ok(())
+}
+
 pub fn run_request(
     lua: &Lua,
-    owner: &Script,
+    project: &KoProject,
     global_driver: &mut Script,
     request: &KoRequest,
     offset: usize,
@@ -160,22 +572,19 @@ pub fn run_request(
     luac!(lua.globals().set("KOC", context));
     luac!(lua.globals().set("i", offset));
 
-    // run user request call
-    let method_call = {
-        let function_call = String::from_utf8(request.function_call.to_vec())
-            .map_err(|_| ExecutorError::InvalidUFT8FormatForFunctionCall)?;
-        "return ".to_owned() + &function_call
-    };
-    let result: Value = lua.load(&method_call).call(()).map_err(|err| {
-        ExecutorError::ErrorLoadRequestLuaCode(method_call.clone(), err.to_string())
-    })?;
+    // run user request call, events left by previous failed request are dropped
+    take_emitted_events(lua);
+    let method_call = String::from_utf8(request.function_call.to_vec())
+        .map_err(|_| ExecutorError::InvalidUFT8FormatForFunctionCall)?;
+    let declared = declared_functions(project.manifest.as_ref(), false);
+    let result = call_contract_function(lua, &method_call, declared)?;
     apply_function_call_result(lua, &result)?;
 
     // check specified owner lock_hash
     let context: Table = luac!(lua.globals().get("KOC"));
     let owner_lockhash: mlua::String = luac!(context.get("owner"));
     let koc_owner = luac!(owner_lockhash.to_str()).into();
-    let expect_owner = hex::encode(owner.calc_script_hash().raw_data());
+    let expect_owner = hex::encode(project.contract_owner.calc_script_hash().raw_data());
     if koc_owner != expect_owner {
         return Err(ExecutorError::OwnerLockhashMismatch(koc_owner, expect_owner).into());
     }
@@ -193,6 +602,12 @@ pub fn run_request(
         }
     }
 
+    // check global data still can be encoded into JSON
+    let global: Value = luac!(context.get("global"));
+    encode_canonical_json(lua, &global).map_err(|err| {
+        ExecutorError::ErrorLoadRequestLuaCode(method_call.clone(), err.to_string())
+    })?;
+
     // check specified user outputs
     let outputs = koc_extract_outputs(lua, &method_call)?
         .iter()
@@ -207,45 +622,55 @@ pub fn run_request(
 
     // make occupied request cell capacity assign to output_cell's basic capacity
     let basic_ckb = request.capacity - request.payment_ckb;
-    Ok(KoCellOutput::new(outputs, basic_ckb))
+    Ok(KoCellOutput::new(
+        outputs,
+        basic_ckb,
+        take_emitted_events(lua),
+    ))
 }
 
 pub fn parse_requests_to_outputs(
     lua: &Lua,
-    owner: &Script,
+    project: &KoProject,
     global_cell: &mut KoContextGlobalCell,
     requests: &[KoRequest],
+    random_seeds: &[[i64; 2]],
 ) -> KoResult<Vec<KoResult<KoCellOutput>>> {
     let context: Table = luac!(lua.globals().get("KOC"));
 
     // complete deposit injection
     let payments = requests.iter().map(|v| v.payment_ckb).collect::<Vec<_>>();
-    let ckb_deposit = luac!(lua.create_function(move |lua, ckb: f64| {
-        let i: usize = lua.globals().get("i").expect("ckb_deposit get i");
-        let offer_ckb = payments.get(i).expect("requests get i");
-        let require_ckb = (ckb * 100_000_000.0) as u64;
-        Ok(*offer_ckb >= require_ckb)
-    }));
+    let ckb_deposit = luac!(lua.create_function(
+        move |lua, (amount, unit): (Value, Option<String>)| {
+            let i: usize = lua.globals().get("i").expect("ckb_deposit get i");
+            let offer_ckb = payments.get(i).expect("requests get i");
+            let require_ckb = ckb_amount_to_shannons(amount, unit)?;
+            Ok(*offer_ckb >= require_ckb)
+        }
+    ));
     luac!(context.set("ckb_deposit", ckb_deposit));
 
     // complete withdraw injection
     let occupied_ckb = global_cell.occupied_capacity;
-    let global_capacity_rc = Rc::new(RefCell::new(global_cell.capacity));
+    let global_capacity_rc = Arc::new(Mutex::new(global_cell.capacity));
     let global_capacity = global_capacity_rc.clone();
-    let personal_extra_rc = Rc::new(RefCell::new(HashMap::new()));
+    let personal_extra_rc = Arc::new(Mutex::new(HashMap::new()));
     let personal_extra = personal_extra_rc.clone();
-    let ckb_withdraw = luac!(lua.create_function(move |lua, ckb: f64| {
-        let withdraw_ckb = (ckb * 100_000_000.0) as u64;
-        let avaliable_ckb = *global_capacity.borrow() - occupied_ckb;
-        if avaliable_ckb >= withdraw_ckb {
-            *global_capacity.borrow_mut() -= withdraw_ckb;
-            let i: usize = lua.globals().get("i").expect("ckb_withdraw get i");
-            personal_extra.borrow_mut().insert(i, withdraw_ckb);
-            Ok(true)
-        } else {
-            Ok(false)
+    let ckb_withdraw = luac!(lua.create_function(
+        move |lua, (amount, unit): (Value, Option<String>)| {
+            let withdraw_ckb = ckb_amount_to_shannons(amount, unit)?;
+            let mut global_capacity = global_capacity.lock().unwrap();
+            let avaliable_ckb = *global_capacity - occupied_ckb;
+            if avaliable_ckb >= withdraw_ckb {
+                *global_capacity -= withdraw_ckb;
+                let i: usize = lua.globals().get("i").expect("ckb_withdraw get i");
+                personal_extra.lock().unwrap().insert(i, withdraw_ckb);
+                Ok(true)
+            } else {
+                Ok(false)
+            }
         }
-    }));
+    ));
     luac!(context.set("ckb_withdraw", ckb_withdraw));
     luac!(lua.globals().set("KOC", context));
 
@@ -254,14 +679,16 @@ pub fn parse_requests_to_outputs(
         .iter()
         .enumerate()
         .map(|(i, request)| {
+            // reseed for each request so its randomness doesn't depend on requests before it
+            apply_randomseed(lua, &random_seeds[i])?;
             let previous_context = {
                 let context: Table = luac!(lua.globals().get("KOC"));
                 deep_clone_table(lua, context)?
             };
-            match run_request(lua, owner, &mut global_cell.lock_script, request, i) {
+            match run_request(lua, project, &mut global_cell.lock_script, request, i) {
                 Ok(mut output) => {
                     output.suggested_capacity +=
-                        if let Some(extra_ckb) = personal_extra_rc.borrow().get(&i) {
+                        if let Some(extra_ckb) = personal_extra_rc.lock().unwrap().get(&i) {
                             *extra_ckb
                         } else {
                             0
@@ -272,6 +699,8 @@ pub fn parse_requests_to_outputs(
                     println!("request err = {}", err);
                     // recover previous global data
                     luac!(lua.globals().set("KOC", previous_context));
+                    // release garbage left by failed request before running the next one
+                    luac!(lua.gc_collect());
                     Err(err)
                 }
             }
@@ -279,13 +708,39 @@ pub fn parse_requests_to_outputs(
         .collect::<Vec<_>>();
 
     // apply adjusted global cell capacity
-    global_cell.capacity = *global_capacity_rc.borrow();
+    global_cell.capacity = *global_capacity_rc.lock().unwrap();
 
     Ok(user_outputs)
 }
 
 pub fn deep_clone_table<'lua>(lua: &'lua Lua, table: Table<'lua>) -> KoResult<Table<'lua>> {
-    let deep_copy: mlua::Function = luac!(lua.globals().get("_deep_copy"));
-    let table = luac!(deep_copy.call::<_, Table<'lua>>(table));
-    Ok(table)
+    deep_clone_table_with(lua, table, &mut HashMap::new())
+}
+
+// metatables are kept so that empty JSON arrays still encode into `[]` after recovering
+fn deep_clone_table_with<'lua>(
+    lua: &'lua Lua,
+    table: Table<'lua>,
+    cloned: &mut HashMap<usize, Table<'lua>>,
+) -> KoResult<Table<'lua>> {
+    let pointer = table.to_pointer() as usize;
+    if let Some(copy) = cloned.get(&pointer) {
+        return Ok(copy.clone());
+    }
+    let copy = luac!(lua.create_table());
+    cloned.insert(pointer, copy.clone());
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, value) = luac!(pair);
+        let key = match key {
+            Value::Table(key) => Value::Table(deep_clone_table_with(lua, key, cloned)?),
+            key => key,
+        };
+        let value = match value {
+            Value::Table(value) => Value::Table(deep_clone_table_with(lua, value, cloned)?),
+            value => value,
+        };
+        luac!(copy.raw_set(key, value));
+    }
+    copy.set_metatable(table.get_metatable());
+    Ok(copy)
 }
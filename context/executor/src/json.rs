@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use ko_protocol::{serde_json, KoResult};
+use mlua::{Lua, LuaSerdeExt, Table, Value};
+
+use crate::error::ExecutorError;
+use crate::luac;
+
+// max nesting depth of lua tables while encoding
+const MAX_JSON_DEPTH: usize = 128;
+
+/// Encode lua value into canonical JSON, the same value always produces the same bytes:
+///
+/// 1. a table whose keys are exactly `1..=n` (n > 0) is an array
+/// 2. an empty table is an array only if it carries the array metatable, which is attached
+///    to arrays while decoding JSON, otherwise it's an object
+/// 3. any other table is an object whose keys must be strings or numbers, numeric keys are
+///    written in decimal, and all keys are sorted by their UTF-8 bytes
+/// 4. NaN, infinity, non-UTF-8 strings and non-data values like functions are rejected
+pub fn encode_canonical_json(lua: &Lua, value: &Value) -> KoResult<String> {
+    let mut encoder = Encoder {
+        lua,
+        output: String::new(),
+        visiting: HashSet::new(),
+    };
+    encoder.encode(value, 0)?;
+    Ok(encoder.output)
+}
+
+struct Encoder<'a> {
+    lua: &'a Lua,
+    output: String,
+    visiting: HashSet<usize>,
+}
+
+impl<'a> Encoder<'a> {
+    fn encode(&mut self, value: &Value, depth: usize) -> KoResult<()> {
+        match value {
+            Value::Nil => self.output.push_str("null"),
+            Value::LightUserData(data) if data.0.is_null() => self.output.push_str("null"),
+            Value::Boolean(value) => self.output.push_str(if *value { "true" } else { "false" }),
+            Value::Integer(value) => self.output.push_str(&value.to_string()),
+            Value::Number(value) => self.output.push_str(&encode_number(*value)?),
+            Value::String(value) => {
+                let value = value.to_str().map_err(|_| invalid("string is not UTF-8"))?;
+                self.output.push_str(&encode_string(value));
+            }
+            Value::Table(table) => self.encode_table(table, depth + 1)?,
+            other => return Err(invalid(&format!("{} is not encodable", other.type_name()))),
+        }
+        Ok(())
+    }
+
+    fn encode_table(&mut self, table: &Table, depth: usize) -> KoResult<()> {
+        if depth > MAX_JSON_DEPTH {
+            return Err(invalid(&format!(
+                "table nested deeper than {}",
+                MAX_JSON_DEPTH
+            )));
+        }
+        let pointer = table.to_pointer() as usize;
+        if !self.visiting.insert(pointer) {
+            return Err(invalid("table contains cycle reference"));
+        }
+
+        let mut entries = vec![];
+        for pair in table.clone().pairs::<Value, Value>() {
+            entries.push(luac!(pair));
+        }
+        if entries.is_empty() {
+            let is_array = table.get_metatable() == Some(self.lua.array_metatable());
+            self.output.push_str(if is_array { "[]" } else { "{}" });
+        } else if let Some(values) = as_sequence(&entries) {
+            self.output.push('[');
+            for (i, value) in values.into_iter().enumerate() {
+                if i > 0 {
+                    self.output.push(',');
+                }
+                self.encode(value, depth)?;
+            }
+            self.output.push(']');
+        } else {
+            let mut fields = entries
+                .iter()
+                .map(|(key, value)| Ok((encode_key(key)?, value)))
+                .collect::<KoResult<Vec<_>>>()?;
+            fields.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+            if fields.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(invalid("table contains duplicated keys"));
+            }
+            self.output.push('{');
+            for (i, (key, value)) in fields.into_iter().enumerate() {
+                if i > 0 {
+                    self.output.push(',');
+                }
+                self.output.push_str(&encode_string(&key));
+                self.output.push(':');
+                self.encode(value, depth)?;
+            }
+            self.output.push('}');
+        }
+
+        self.visiting.remove(&pointer);
+        Ok(())
+    }
+}
+
+// collect values in order if keys are exactly `1..=n`
+fn as_sequence<'a, 'lua>(
+    entries: &'a [(Value<'lua>, Value<'lua>)],
+) -> Option<Vec<&'a Value<'lua>>> {
+    let mut values = vec![None; entries.len()];
+    for (key, value) in entries {
+        match key {
+            Value::Integer(i) if *i >= 1 && *i as usize <= entries.len() => {
+                values[*i as usize - 1] = Some(value);
+            }
+            _ => return None,
+        }
+    }
+    values.into_iter().collect()
+}
+
+fn encode_key(key: &Value) -> KoResult<String> {
+    match key {
+        Value::String(key) => Ok(key
+            .to_str()
+            .map_err(|_| invalid("table key is not UTF-8"))?
+            .to_owned()),
+        Value::Integer(key) => Ok(key.to_string()),
+        Value::Number(key) => encode_number(*key),
+        other => Err(invalid(&format!(
+            "table key of {} is not encodable",
+            other.type_name()
+        ))),
+    }
+}
+
+fn encode_number(value: f64) -> KoResult<String> {
+    if !value.is_finite() {
+        return Err(invalid("number is NaN or infinity"));
+    }
+    Ok(serde_json::to_string(&value).unwrap())
+}
+
+fn encode_string(value: &str) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+/// Decode JSON value into lua value, integers are kept as lua integers so that 64-bit
+/// numbers never drift through `f64`, and integers out of `i64` range are rejected
+pub fn decode_json<'lua>(lua: &'lua Lua, value: &serde_json::Value) -> KoResult<Value<'lua>> {
+    decode_json_with_depth(lua, value, 0)
+}
+
+fn decode_json_with_depth<'lua>(
+    lua: &'lua Lua,
+    value: &serde_json::Value,
+    depth: usize,
+) -> KoResult<Value<'lua>> {
+    if depth > MAX_JSON_DEPTH {
+        return Err(malformed(format!(
+            "JSON nested deeper than {}",
+            MAX_JSON_DEPTH
+        )));
+    }
+    let value = match value {
+        serde_json::Value::Null => lua.null(),
+        serde_json::Value::Bool(value) => Value::Boolean(*value),
+        serde_json::Value::Number(number) => {
+            if let Some(integer) = number.as_i64() {
+                Value::Integer(integer)
+            } else if number.is_u64() {
+                return Err(malformed(format!("integer {} is out of i64 range", number)));
+            } else {
+                Value::Number(number.as_f64().unwrap())
+            }
+        }
+        serde_json::Value::String(value) => Value::String(luac!(lua.create_string(value))),
+        serde_json::Value::Array(values) => {
+            let table = luac!(lua.create_table_with_capacity(values.len() as i32, 0));
+            for (i, value) in values.iter().enumerate() {
+                let value = decode_json_with_depth(lua, value, depth + 1)?;
+                luac!(table.raw_set(i + 1, value));
+            }
+            table.set_metatable(Some(lua.array_metatable()));
+            Value::Table(table)
+        }
+        serde_json::Value::Object(fields) => {
+            let table = luac!(lua.create_table_with_capacity(0, fields.len() as i32));
+            for (key, value) in fields {
+                let value = decode_json_with_depth(lua, value, depth + 1)?;
+                luac!(table.raw_set(key.as_str(), value));
+            }
+            Value::Table(table)
+        }
+    };
+    Ok(value)
+}
+
+fn malformed(reason: String) -> ko_protocol::types::error::KoError {
+    ExecutorError::InvalidJsonDecoding(reason).into()
+}
+
+fn invalid(reason: &str) -> ko_protocol::types::error::KoError {
+    ExecutorError::InvalidJsonEncoding(reason.into()).into()
+}
@@ -1,18 +1,36 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use ko_protocol::ckb_types::bytes::Bytes;
 use ko_protocol::ckb_types::packed::Script;
-use ko_protocol::derive_more::Constructor;
 use ko_protocol::traits::Executor;
-use ko_protocol::types::assembler::{KoCellOutput, KoRequest};
+use ko_protocol::types::assembler::{
+    KoBlockContext, KoCellOutput, KoContractAbi, KoProject, KoRequest, KoRequestSimulation,
+};
 use ko_protocol::types::context::KoContextGlobalCell;
-use ko_protocol::{hex, serde_json, KoResult};
-use mlua::{Lua, LuaSerdeExt, Table};
+use ko_protocol::{async_trait, hex, KoResult};
+use mlua::{Lua, Table};
 
+#[cfg(test)]
+mod tests;
+
+mod cache;
+mod call;
 mod error;
 mod helper;
-use error::ExecutorError;
+mod json;
+mod module;
+mod pool;
+mod sandbox;
+mod snapshot;
+use cache::ExecutorCache;
+
+pub use call::{check_function_call, parse_function_call, CallArgument, FunctionCall};
+pub use error::ExecutorError;
+pub use helper::read_function_abi;
+pub use json::{decode_json, encode_canonical_json};
+pub use module::{inject_lua_modules, CompiledModules};
+pub use pool::ExecutorPool;
+pub use sandbox::new_sandbox_lua;
 
 #[macro_export]
 macro_rules! luac {
@@ -21,39 +39,204 @@ macro_rules! luac {
     };
 }
 
-#[derive(Constructor)]
-pub struct ExecutorImpl {}
+/// Executor which runs every lua execution on the shared blocking pool
+#[derive(Clone)]
+pub struct ExecutorImpl {
+    lua_executor: Arc<LuaExecutor>,
+    pool: ExecutorPool,
+}
 
 impl ExecutorImpl {
-    fn prepare_lua_context(
+    pub fn new(
+        max_instructions_per_request: u64,
+        max_lua_memory_bytes: u64,
+        pool: &ExecutorPool,
+    ) -> Self {
+        let lua_executor = LuaExecutor {
+            max_instructions_per_request,
+            max_lua_memory_bytes,
+            cache: ExecutorCache::default(),
+        };
+        ExecutorImpl {
+            lua_executor: Arc::new(lua_executor),
+            pool: pool.clone(),
+        }
+    }
+
+    /// Run a job against a fresh limited sandbox on the blocking pool, the whole job is metered
+    /// like a single request, which is for running code that doesn't belong to a project yet
+    pub async fn run_limited<T, F>(&self, job: F) -> KoResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Lua) -> KoResult<T> + Send + 'static,
+    {
+        let executor = self.lua_executor.clone();
+        self.pool
+            .run(move || {
+                let lua = executor.new_limited_lua()?;
+                helper::start_instruction_meter(&lua)?;
+                let result = job(&lua);
+                if let Some(budget) = helper::stop_instruction_meter(&lua) {
+                    return Err(ExecutorError::InstructionBudgetExhausted(
+                        "<contract>".into(),
+                        budget,
+                    )
+                    .into());
+                }
+                result
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl Executor for ExecutorImpl {
+    async fn execute_lua_requests(
+        &self,
+        global_cell: &mut KoContextGlobalCell,
+        project: &KoProject,
+        user_requests: &[KoRequest],
+        random_seeds: &[[i64; 2]],
+        block_context: &KoBlockContext,
+    ) -> KoResult<Vec<KoResult<KoCellOutput>>> {
+        let executor = self.lua_executor.clone();
+        let mut cell = global_cell.clone();
+        let (project, block_context) = (project.clone(), block_context.clone());
+        let (requests, seeds) = (user_requests.to_vec(), random_seeds.to_vec());
+        let (cell, outputs) = self
+            .pool
+            .run(move || {
+                let outputs = executor.execute_lua_requests(
+                    &mut cell,
+                    &project,
+                    &requests,
+                    &seeds,
+                    &block_context,
+                );
+                Ok((cell, outputs))
+            })
+            .await?;
+        *global_cell = cell;
+        outputs
+    }
+
+    async fn estimate_payment_ckb(
         &self,
         global_cell: &KoContextGlobalCell,
-        project_owner: &Script,
-        project_lua_code: &Bytes,
-    ) -> KoResult<Lua> {
-        // initialize project lua code
-        let lua = Lua::new();
-        lua.load(&project_lua_code.to_vec())
-            .exec()
-            .map_err(|err| ExecutorError::ErrorLoadProjectLuaCode(err.to_string()))?;
+        project: &KoProject,
+        request: KoRequest,
+        block_context: &KoBlockContext,
+    ) -> KoResult<u64> {
+        let executor = self.lua_executor.clone();
+        let (cell, project, block_context) =
+            (global_cell.clone(), project.clone(), block_context.clone());
+        self.pool
+            .run(move || executor.estimate_payment_ckb(&cell, &project, request, &block_context))
+            .await
+    }
 
-        // prepare global context `KOC`
-        let owner = hex::encode(project_owner.calc_script_hash().raw_data());
-        let driver = hex::encode(global_cell.lock_script.calc_script_hash().raw_data());
-        let global_table = {
-            let json_string = String::from_utf8(global_cell.output_data.to_vec())
-                .map_err(|_| ExecutorError::InvalidUTF8FormatForGlobalData)?;
-            let value: serde_json::Value = serde_json::from_str(&json_string)
-                .map_err(|_| ExecutorError::InvalidJsonFormatForGlobalData(json_string))?;
-            luac!(lua.to_value(&value))
-        };
+    async fn simulate_request(
+        &self,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        request: KoRequest,
+        block_context: &KoBlockContext,
+    ) -> KoResult<KoRequestSimulation> {
+        let executor = self.lua_executor.clone();
+        let (cell, project, block_context) =
+            (global_cell.clone(), project.clone(), block_context.clone());
+        self.pool
+            .run(move || executor.simulate_request(&cell, &project, request, &block_context))
+            .await
+    }
 
-        let context = luac!(lua.create_table());
-        luac!(context.set("owner", owner));
-        luac!(context.set("driver", driver));
-        luac!(context.set("global", global_table));
-        luac!(lua.globals().set("KOC", context));
+    async fn call_view(
+        &self,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        function_call: &str,
+        inputs: &[(Script, Bytes)],
+        block_context: &KoBlockContext,
+    ) -> KoResult<String> {
+        let executor = self.lua_executor.clone();
+        let (cell, project, block_context) =
+            (global_cell.clone(), project.clone(), block_context.clone());
+        let (function_call, inputs) = (function_call.to_owned(), inputs.to_vec());
+        self.pool
+            .run(move || {
+                executor.call_view(&cell, &project, &function_call, &inputs, &block_context)
+            })
+            .await
+    }
+
+    async fn migrate_global(
+        &self,
+        global_cell: &mut KoContextGlobalCell,
+        project: &KoProject,
+        block_context: &KoBlockContext,
+    ) -> KoResult<()> {
+        let executor = self.lua_executor.clone();
+        let mut cell = global_cell.clone();
+        let (project, block_context) = (project.clone(), block_context.clone());
+        let cell = self
+            .pool
+            .run(move || {
+                executor.migrate_global(&mut cell, &project, &block_context)?;
+                Ok(cell)
+            })
+            .await?;
+        *global_cell = cell;
+        Ok(())
+    }
+
+    async fn get_contract_abi(&self, project: &KoProject) -> KoResult<KoContractAbi> {
+        let executor = self.lua_executor.clone();
+        let project = project.clone();
+        self.pool
+            .run(move || executor.get_contract_abi(&project))
+            .await
+    }
+}
+
+// synchronous lua executor, each call runs in the cached lua vm of project which is rolled back
+// to the state right after project loaded once the call finishes
+struct LuaExecutor {
+    max_instructions_per_request: u64,
+    max_lua_memory_bytes: u64,
+    cache: ExecutorCache,
+}
 
+impl LuaExecutor {
+    fn new_limited_lua(&self) -> KoResult<Lua> {
+        // limit the instructions of each lua run and the memory of whole vm
+        let lua = luac!(new_sandbox_lua());
+        helper::apply_execution_limits(
+            &lua,
+            self.max_instructions_per_request,
+            self.max_lua_memory_bytes as usize,
+        )?;
+        Ok(lua)
+    }
+
+    fn load_project(&self, lua: &Lua, project: &KoProject) -> KoResult<()> {
+        module::inject_lua_modules(lua, &project.modules, &self.cache.modules)?;
+        let builtins = helper::snapshot_global_functions(lua)?;
+        let chunk = lua
+            .load(&project.lua_code.to_vec())
+            .set_name("=contract")
+            .and_then(|chunk| chunk.into_function())
+            .map_err(|err| ExecutorError::ErrorLoadProjectLuaCode(err.to_string()))?;
+        helper::start_instruction_meter(lua)?;
+        let result = chunk.call::<_, ()>(());
+        if let Some(budget) = helper::stop_instruction_meter(lua) {
+            return Err(
+                ExecutorError::InstructionBudgetExhausted("<project>".into(), budget).into(),
+            );
+        }
+        result.map_err(|err| ExecutorError::ErrorLoadProjectLuaCode(err.to_string()))?;
+        helper::register_contract_functions(lua, &builtins)?;
+
+        // helpers `_compare_tables` and `_deep_copy` which are not contract functions
         let preload = [
             27u8, 76, 117, 97, 84, 0, 25, 147, 13, 10, 26, 10, 4, 8, 8, 120, 86, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 40, 119, 64, 1, 128, 128, 128, 0, 1, 2, 134, 81, 0, 0, 0, 79, 0, 0, 0,
@@ -78,76 +261,225 @@ impl ExecutorImpl {
             121, 129, 0, 0, 0, 128, 128, 128, 128, 128, 128, 128, 128, 128,
         ];
         luac!(lua.load(&preload[..]).exec());
+        Ok(())
+    }
 
-        Ok(lua)
+    // run the call in cached lua vm of project, or a freshly loaded one if project changed,
+    // the vm goes back into cache only if it's rolled back to its snapshot
+    fn with_project_lua<T>(
+        &self,
+        project: &KoProject,
+        call: impl FnOnce(&Lua) -> KoResult<T>,
+    ) -> KoResult<T> {
+        let (lua, restorable) = match self.cache.take_project(project) {
+            Some(lua) => (lua, true),
+            None => {
+                let lua = self.new_limited_lua()?;
+                self.load_project(&lua, project)?;
+                let restorable = snapshot::take_snapshot(&lua)?;
+                (lua, restorable)
+            }
+        };
+        let result = call(&lua);
+        if restorable && snapshot::restore_snapshot(&lua).is_ok() {
+            self.cache.put_project(project, lua);
+        }
+        result
+    }
+
+    fn prepare_lua_context(
+        &self,
+        lua: &Lua,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        block_context: &KoBlockContext,
+    ) -> KoResult<()> {
+        // prepare global context `KOC`
+        let owner = hex::encode(project.contract_owner.calc_script_hash().raw_data());
+        let driver = hex::encode(global_cell.lock_script.calc_script_hash().raw_data());
+        let global_table = {
+            let value = self.cache.decode_global(&global_cell.output_data)?;
+            decode_json(lua, &value)?
+        };
+
+        let context = luac!(lua.create_table());
+        luac!(context.set("owner", owner));
+        luac!(context.set("driver", driver));
+        luac!(context.set("global", global_table));
+        luac!(context.set("block_hash", hex::encode(&block_context.block_hash)));
+        luac!(context.set("block_number", block_context.block_number));
+        luac!(context.set("timestamp", block_context.timestamp));
+        luac!(context.set("epoch", block_context.epoch));
+        helper::koc_inject_emit(lua, &context)?;
+        luac!(lua.globals().set("KOC", context));
+        Ok(())
     }
 }
 
-impl Executor for ExecutorImpl {
+impl LuaExecutor {
     fn execute_lua_requests(
         &self,
         global_cell: &mut KoContextGlobalCell,
-        project_owner: &Script,
+        project: &KoProject,
         user_requests: &[KoRequest],
-        project_lua_code: &Bytes,
-        random_seeds: &[i64; 2],
+        random_seeds: &[[i64; 2]],
+        block_context: &KoBlockContext,
     ) -> KoResult<Vec<KoResult<KoCellOutput>>> {
-        let lua = self.prepare_lua_context(global_cell, project_owner, project_lua_code)?;
+        if user_requests.len() != random_seeds.len() {
+            return Err(ExecutorError::RandomSeedsCountMismatch(
+                user_requests.len(),
+                random_seeds.len(),
+            )
+            .into());
+        }
+        self.with_project_lua(project, |lua| {
+            self.prepare_lua_context(lua, global_cell, project, block_context)?;
 
-        // applying random seeds
-        helper::apply_randomseed(&lua, random_seeds)?;
+            // running each user function_call requests with its own random seeds
+            let personal_outputs = helper::parse_requests_to_outputs(
+                lua,
+                project,
+                global_cell,
+                user_requests,
+                random_seeds,
+            )?;
 
-        // running each user function_call requests
-        let personal_outputs =
-            helper::parse_requests_to_outputs(&lua, project_owner, global_cell, user_requests)?;
+            // make final global json string
+            global_cell.output_data = {
+                let context: Table = luac!(lua.globals().get("KOC"));
+                let global_table = luac!(context.get("global"));
+                let data = encode_canonical_json(lua, &global_table)?;
+                Bytes::from(data.into_bytes())
+            };
 
-        // make final global json string
-        global_cell.output_data = {
-            let context: Table = luac!(lua.globals().get("KOC"));
-            let global_table: Table = luac!(context.get("global"));
-            let data = serde_json::to_string(&global_table).expect("execute global");
-            Bytes::from(data.as_bytes().to_vec())
-        };
-
-        // collect results to make execute receipt
-        Ok(personal_outputs)
+            // collect results to make execute receipt
+            Ok(personal_outputs)
+        })
     }
 
     fn estimate_payment_ckb(
         &self,
         global_cell: &KoContextGlobalCell,
-        project_owner: &Script,
+        project: &KoProject,
         request: KoRequest,
-        project_lua_code: &Bytes,
+        block_context: &KoBlockContext,
     ) -> KoResult<u64> {
-        let lua = self.prepare_lua_context(global_cell, project_owner, project_lua_code)?;
-        let context: Table = luac!(lua.globals().get("KOC"));
-
-        // prepare payment ckb catcher
-        let payment_ckb = Rc::new(RefCell::new(0u64));
-        let payment = payment_ckb.clone();
-        let ckb_deposit = luac!(lua.create_function(move |_, ckb: f64| {
-            *payment.borrow_mut() = (ckb * 100_000_000.0) as u64;
-            Ok(true)
-        }));
-
-        // prepare global ckb poller
-        let avaliable_ckb = global_cell.capacity - global_cell.occupied_capacity;
-        let ckb_withdraw = luac!(lua.create_function(move |_, ckb: f64| {
-            let withdraw_ckb = (ckb * 100_000_000.0) as u64;
-            Ok(avaliable_ckb >= withdraw_ckb)
-        }));
-
-        // inject functions
-        luac!(context.set("ckb_deposit", ckb_deposit));
-        luac!(context.set("ckb_withdraw", ckb_withdraw));
-        luac!(lua.globals().set("KOC", context));
+        let simulation = self.simulate_request(global_cell, project, request, block_context)?;
+        simulation.outputs?;
+        Ok(simulation.deposit_ckb)
+    }
+
+    fn simulate_request(
+        &self,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        request: KoRequest,
+        block_context: &KoBlockContext,
+    ) -> KoResult<KoRequestSimulation> {
+        self.with_project_lua(project, |lua| {
+            self.prepare_lua_context(lua, global_cell, project, block_context)?;
+            let context: Table = luac!(lua.globals().get("KOC"));
+
+            // prepare payment ckb catcher
+            let payment_ckb = Arc::new(Mutex::new(0u64));
+            let payment = payment_ckb.clone();
+            let ckb_deposit = luac!(lua.create_function(
+                move |_, (amount, unit): (mlua::Value, Option<String>)| {
+                    *payment.lock().unwrap() = helper::ckb_amount_to_shannons(amount, unit)?;
+                    Ok(true)
+                }
+            ));
+
+            // prepare global ckb poller
+            let avaliable_ckb = global_cell.capacity - global_cell.occupied_capacity;
+            let withdraw_ckb = Arc::new(Mutex::new(0u64));
+            let withdraw = withdraw_ckb.clone();
+            let ckb_withdraw = luac!(lua.create_function(
+                move |_, (amount, unit): (mlua::Value, Option<String>)| {
+                    let amount = helper::ckb_amount_to_shannons(amount, unit)?;
+                    let mut withdraw = withdraw.lock().unwrap();
+                    match withdraw.checked_add(amount) {
+                        Some(total) if total <= avaliable_ckb => {
+                            *withdraw = total;
+                            Ok(true)
+                        }
+                        _ => Ok(false),
+                    }
+                }
+            ));
+
+            // inject functions
+            luac!(context.set("ckb_deposit", ckb_deposit));
+            luac!(context.set("ckb_withdraw", ckb_withdraw));
+            luac!(lua.globals().set("KOC", context));
 
-        // run request and trigger ckb_deposit/ckb_withdraw function if it exists
-        let mut global_driver = global_cell.lock_script.clone();
-        helper::run_request(&lua, project_owner, &mut global_driver, &request, 0)?;
+            // run request and trigger ckb_deposit/ckb_withdraw function if it exists
+            let mut global_driver = global_cell.lock_script.clone();
+            let outputs = helper::run_request(lua, project, &mut global_driver, &request, 0).map(
+                |mut output| {
+                    output.suggested_capacity += *withdraw_ckb.lock().unwrap();
+                    output
+                },
+            );
+
+            // make global json string the request would leave behind
+            let global_data = if outputs.is_ok() {
+                let context: Table = luac!(lua.globals().get("KOC"));
+                let data = encode_canonical_json(lua, &luac!(context.get("global")))?;
+                Bytes::from(data.into_bytes())
+            } else {
+                global_cell.output_data.clone()
+            };
+
+            let payment_ckb = *payment_ckb.lock().unwrap();
+            let withdraw_ckb = *withdraw_ckb.lock().unwrap();
+            let simulation =
+                KoRequestSimulation::new(outputs, global_data, payment_ckb, withdraw_ckb);
+            Ok(simulation)
+        })
+    }
+
+    fn call_view(
+        &self,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        function_call: &str,
+        inputs: &[(Script, Bytes)],
+        block_context: &KoBlockContext,
+    ) -> KoResult<String> {
+        self.with_project_lua(project, |lua| {
+            self.prepare_lua_context(lua, global_cell, project, block_context)?;
+            helper::run_view(lua, function_call, inputs, project.manifest.as_ref())
+        })
+    }
+
+    fn migrate_global(
+        &self,
+        global_cell: &mut KoContextGlobalCell,
+        project: &KoProject,
+        block_context: &KoBlockContext,
+    ) -> KoResult<()> {
+        self.with_project_lua(project, |lua| {
+            self.prepare_lua_context(lua, global_cell, project, block_context)?;
+            helper::run_migration(lua)?;
+            global_cell.output_data = {
+                let context: Table = luac!(lua.globals().get("KOC"));
+                let data = encode_canonical_json(lua, &luac!(context.get("global")))?;
+                Bytes::from(data.into_bytes())
+            };
+            Ok(())
+        })
+    }
 
-        // get payment ckb cost
-        Ok(payment_ckb.take())
+    fn get_contract_abi(&self, project: &KoProject) -> KoResult<KoContractAbi> {
+        // manifest narrows public functions down to what it declares
+        if let Some(manifest) = &project.manifest {
+            if !manifest.functions.is_empty() {
+                return Ok(KoContractAbi::new(manifest.functions.clone()));
+            }
+        }
+        self.cache.contract_abi(project, || {
+            self.with_project_lua(project, helper::collect_contract_abi)
+        })
     }
 }
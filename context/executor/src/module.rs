@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ko_protocol::types::assembler::KoLuaModule;
+use ko_protocol::{hex, KoResult, H256};
+use mlua::{ChunkMode, Error, Function, Lua, Table, Value};
+
+use crate::error::ExecutorError;
+use crate::luac;
+
+pub const LOADED_MODULES: &str = "_KO_LOADED_MODULES";
+
+/// Compiled lua modules shared across lua vms, keyed by module data hash
+pub type CompiledModules = Arc<Mutex<HashMap<H256, Vec<u8>>>>;
+
+/// Inject `require`, which only resolves modules from celldeps of project by their type id,
+/// e.g. `require("0x0a...")`, and returns the same value for every later `require` in vm
+pub fn inject_lua_modules(
+    lua: &Lua,
+    modules: &[KoLuaModule],
+    compiled: &CompiledModules,
+) -> KoResult<()> {
+    let modules = modules
+        .iter()
+        .map(|module| (hex::encode(&module.type_id), module.clone()))
+        .collect::<HashMap<_, _>>();
+    let compiled = compiled.clone();
+    luac!(lua.set_named_registry_value(LOADED_MODULES, luac!(lua.create_table())));
+    let require = luac!(lua.create_function(move |lua, name: String| {
+        let type_id = name.trim_start_matches("0x").to_lowercase();
+        let loaded: Table = lua.named_registry_value(LOADED_MODULES)?;
+        match loaded.raw_get::<_, Value>(type_id.as_str())? {
+            Value::Nil => {}
+            // a module which is still loading has been required again
+            Value::Boolean(false) => {
+                return Err(Error::RuntimeError(format!(
+                    "module '{}' is required cyclically",
+                    name
+                )))
+            }
+            module => return Ok(module),
+        }
+        let module = modules.get(&type_id).ok_or_else(|| {
+            Error::RuntimeError(format!("module '{}' is not a celldep of project", name))
+        })?;
+        let chunk = load_module(lua, &type_id, module, &compiled)?;
+        loaded.raw_set(type_id.as_str(), false)?;
+        let module = match chunk.call::<_, Value>(type_id.as_str())? {
+            Value::Nil => Value::Boolean(true),
+            module => module,
+        };
+        loaded.raw_set(type_id.as_str(), module.clone())?;
+        Ok(module)
+    }));
+    luac!(lua.globals().set("require", require));
+    Ok(())
+}
+
+// modules are compiled from text only, the cached bytecode never comes from outside
+fn load_module<'lua>(
+    lua: &'lua Lua,
+    type_id: &str,
+    module: &KoLuaModule,
+    compiled: &CompiledModules,
+) -> mlua::Result<Function<'lua>> {
+    if !module.verify() {
+        return Err(Error::external(ExecutorError::ModuleDataHashMismatch(
+            type_id.into(),
+            module.data_hash.clone(),
+        )));
+    }
+    let bytecode = compiled.lock().unwrap().get(&module.data_hash).cloned();
+    if let Some(bytecode) = bytecode {
+        return lua
+            .load(&bytecode)
+            .set_mode(ChunkMode::Binary)
+            .into_function();
+    }
+    let chunk = lua
+        .load(module.code.as_ref())
+        .set_name(format!("={}", type_id))?
+        .set_mode(ChunkMode::Text)
+        .into_function()?;
+    compiled
+        .lock()
+        .unwrap()
+        .insert(module.data_hash.clone(), chunk.dump(false));
+    Ok(chunk)
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use ko_protocol::tokio::sync::Semaphore;
+use ko_protocol::{tokio, KoResult};
+
+use crate::error::ExecutorError;
+
+/// Bounded pool of blocking threads which runs lua executions, so that a heavy contract never
+/// occupies async runtime workers, the pool is shared by all contexts
+#[derive(Clone)]
+pub struct ExecutorPool {
+    permits: Arc<Semaphore>,
+}
+
+impl ExecutorPool {
+    pub fn new(size: usize) -> Self {
+        ExecutorPool {
+            permits: Arc::new(Semaphore::new(size.max(1))),
+        }
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> KoResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> KoResult<T> + Send + 'static,
+    {
+        // permit is released by the job itself, so a cancelled caller can't free its slot early
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("executor pool closed");
+        tokio::task::spawn_blocking(move || {
+            let result = job();
+            drop(permit);
+            result
+        })
+        .await
+        .map_err(|err| ExecutorError::ExecutionAborted(err.to_string()))?
+    }
+}
@@ -0,0 +1,57 @@
+use ko_protocol::log;
+use mlua::{Lua, LuaOptions, StdLib, Value};
+
+// registry key of `debug` library, which is hidden from contracts
+pub const DEBUG_LIBRARY: &str = "_KO_DEBUG";
+
+// patch the base library loaded by default, which still contains host-touching functions
+const SANDBOX_PATCH: &str = r##"
+    local log = ...
+    local load, select, tostring, concat = load, select, tostring, table.concat
+    local randomseed = math.randomseed
+
+    -- only text chunks are loadable and they can't escape from sandbox globals
+    _G.load = function (chunk, name, _, env)
+        return load(chunk, name, "t", env or _G)
+    end
+
+    -- redirect print into driver log instead of stdout
+    _G.print = function (...)
+        local values = {}
+        for i = 1, select("#", ...) do
+            values[i] = tostring((select(i, ...)))
+        end
+        log(concat(values, "\t"))
+    end
+
+    -- reseeding from system clock would break determinism
+    math.randomseed = function (...)
+        assert(select("#", ...) > 0, "math.randomseed requires explicit seeds")
+        return randomseed(...)
+    end
+
+    _G.dofile = nil
+    _G.loadfile = nil
+    _G.collectgarbage = nil
+    _G.warn = nil
+    string.dump = nil
+"##;
+
+/// Create lua vm which only contains deterministic and side-effect-free standard libraries,
+/// `io`, `os`, `package`, `debug` and any file or bytecode loading are unreachable
+pub fn new_sandbox_lua() -> mlua::Result<Lua> {
+    let libs = StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH;
+    // `debug` is moved into registry before any code runs, only snapshots of vm can use it
+    let lua = unsafe { Lua::unsafe_new_with(libs | StdLib::DEBUG, LuaOptions::default()) };
+    let debug: Value = lua.globals().get("debug")?;
+    lua.set_named_registry_value(DEBUG_LIBRARY, debug)?;
+    lua.globals().set("debug", Value::Nil)?;
+    let log = lua.create_function(|_, message: String| {
+        log::debug!("[lua] {}", message);
+        Ok(())
+    })?;
+    lua.load(SANDBOX_PATCH)
+        .set_name("=sandbox")?
+        .call::<_, ()>(log)?;
+    Ok(lua)
+}
@@ -0,0 +1,113 @@
+use ko_protocol::KoResult;
+use mlua::{Function, Lua, Table, Value};
+
+use crate::error::ExecutorError;
+use crate::luac;
+use crate::module::LOADED_MODULES;
+use crate::sandbox::DEBUG_LIBRARY;
+
+// registry key of the function which rolls lua vm back to its snapshot
+const RESTORE_SNAPSHOT: &str = "_KO_RESTORE_SNAPSHOT";
+
+// record every table and lua function upvalue reachable from roots, and return the function
+// which puts them back, tables left untouched are skipped so restoring a clean vm is cheap
+const SNAPSHOT: &str = r#"
+    local debug, roots = ...
+    local getupvalue, setupvalue, getinfo = debug.getupvalue, debug.setupvalue, debug.getinfo
+    local getmetatable, setmetatable = debug.getmetatable, debug.setmetatable
+    local next, type, rawset, rawget, rawequal = next, type, rawset, rawget, rawequal
+
+    local tables, functions, pending = {}, {}, { getmetatable("") }
+    for _, root in next, roots do
+        pending[#pending + 1] = root
+    end
+    while #pending > 0 do
+        local value = pending[#pending]
+        pending[#pending] = nil
+        local kind = type(value)
+        if kind == "thread" then
+            return nil
+        elseif kind == "table" and tables[value] == nil then
+            local entries, size = {}, 0
+            for k, v in next, value do
+                entries[k] = v
+                size = size + 1
+                pending[#pending + 1] = k
+                pending[#pending + 1] = v
+            end
+            local metatable = getmetatable(value)
+            pending[#pending + 1] = metatable
+            tables[value] = { entries = entries, size = size, metatable = metatable }
+        elseif kind == "function" and functions[value] == nil then
+            local upvalues = { n = 0 }
+            if getinfo(value, "S").what ~= "C" then
+                local name, v = getupvalue(value, 1)
+                while name ~= nil do
+                    upvalues.n = upvalues.n + 1
+                    upvalues[upvalues.n] = v
+                    pending[#pending + 1] = v
+                    name, v = getupvalue(value, upvalues.n + 1)
+                end
+            end
+            functions[value] = upvalues
+        end
+    end
+
+    local function unchanged(t, record)
+        if not rawequal(getmetatable(t), record.metatable) then
+            return false
+        end
+        local size = 0
+        for k, v in next, t do
+            if not rawequal(rawget(record.entries, k), v) then
+                return false
+            end
+            size = size + 1
+        end
+        return size == record.size
+    end
+
+    return function ()
+        for t, record in next, tables do
+            if not unchanged(t, record) then
+                for k in next, t do
+                    rawset(t, k, nil)
+                end
+                for k, v in next, record.entries do
+                    rawset(t, k, v)
+                end
+                setmetatable(t, record.metatable)
+            end
+        end
+        for f, upvalues in next, functions do
+            for i = 1, upvalues.n do
+                setupvalue(f, i, upvalues[i])
+            end
+        end
+    end
+"#;
+
+/// Record the base state of lua vm, which is everything reachable from globals and loaded
+/// modules, returns false if it can't be restored since coroutines are reachable
+pub fn take_snapshot(lua: &Lua) -> KoResult<bool> {
+    let debug: Table = luac!(lua.named_registry_value(DEBUG_LIBRARY));
+    let loaded: Value = luac!(lua.named_registry_value(LOADED_MODULES));
+    let roots = luac!(lua.create_sequence_from([Value::Table(lua.globals()), loaded]));
+    let restore =
+        luac!(luac!(lua.load(SNAPSHOT).set_name("=snapshot"))
+            .call::<_, Option<Function>>((debug, roots)));
+    match restore {
+        Some(restore) => {
+            luac!(lua.set_named_registry_value(RESTORE_SNAPSHOT, restore));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Roll lua vm back to the base state recorded by `take_snapshot`
+pub fn restore_snapshot(lua: &Lua) -> KoResult<()> {
+    let restore: Function = luac!(lua.named_registry_value(RESTORE_SNAPSHOT));
+    luac!(restore.call::<_, ()>(()));
+    Ok(())
+}
@@ -0,0 +1,1029 @@
+use std::future::Future;
+use std::time::Duration;
+
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::packed::{CellDep, OutPoint, Script};
+use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack};
+use ko_protocol::tokio;
+use ko_protocol::traits::Executor;
+use ko_protocol::types::assembler::{
+    KoBlockContext, KoEvent, KoFunctionAbi, KoLuaModule, KoProject, KoProjectManifest,
+    KoRandomSeed, KoRequest,
+};
+use ko_protocol::types::context::KoContextGlobalCell;
+
+use crate::helper::ckb_amount_to_shannons;
+use crate::{
+    check_function_call, encode_canonical_json, new_sandbox_lua, parse_function_call, CallArgument,
+    ExecutorImpl, ExecutorPool,
+};
+
+const CONTRACT: &str = r#"
+    function construct()
+        return {
+            driver = KOC.driver,
+            global = { count = 0 }
+        }
+    end
+
+    function increase()
+        KOC.global.count = KOC.global.count + 1
+        return {
+            global = KOC.global
+        }
+    end
+
+    function endless()
+        KOC.global.count = KOC.global.count + 100
+        while true do end
+    end
+
+    function bloat()
+        local t = {}
+        for i = 1, 1000000 do
+            t[i] = string.rep("x", 1024) .. i
+        end
+    end
+
+    function bloat_pcall()
+        pcall(string.rep, "x", 1 << 30)
+        KOC.global.count = KOC.global.count + 100
+        return {
+            global = KOC.global
+        }
+    end
+
+    function catch_errors()
+        local ok, err = pcall(error, { code = 1 })
+        assert(not ok and err.code == 1)
+        ok, err = xpcall(error, function (err) return err.code + 1 end, { code = 1 })
+        assert(not ok and err == 2)
+        KOC.global.count = KOC.global.count + 10
+        return {
+            global = KOC.global
+        }
+    end
+
+    function touch_host(chunk)
+        local fn = assert(load(chunk))
+        return fn()
+    end
+
+    function pay(amount, unit)
+        assert(KOC.ckb_deposit(amount, unit), "insufficient ckb")
+    end
+
+    function cashout(amount, unit)
+        assert(KOC.ckb_withdraw(amount, unit), "insufficient ckb")
+        KOC.global.count = KOC.global.count - 1
+        return {
+            global = KOC.global
+        }
+    end
+
+    function cashout_twice(first, second)
+        assert(KOC.ckb_withdraw(first, "shannon"), "insufficient ckb")
+        return cashout(second, "shannon")
+    end
+
+    function view_count(extra)
+        return { count = KOC.global.count + (extra or 0), inputs = #KOC.inputs }
+    end
+
+    function lottery(id)
+        KOC.emit("won", { nft = id })
+        KOC.global.count = KOC.global.count + 1
+        return {
+            global = KOC.global
+        }
+    end
+
+    function lottery_failed()
+        KOC.emit("lost", {})
+        error("no luck")
+    end
+
+    function roll()
+        KOC.global.last = math.random(1, 1000000)
+        KOC.global.seeds = KOC.seeds
+        return {
+            global = KOC.global
+        }
+    end
+
+    function endless_pcall()
+        while true do
+            pcall(function () while true do end end)
+        end
+    end
+
+    local touched = 0
+
+    function leak()
+        local intact = string.rep ~= nil
+        touched = touched + 1
+        leaked = (leaked or 0) + 1
+        string.rep = nil
+        KOC.global.count = touched + leaked * 10 + (intact and 0 or 100)
+        return {
+            global = KOC.global
+        }
+    end
+"#;
+
+fn script(byte: u8) -> Script {
+    Script::new_builder()
+        .args(Bytes::from(vec![byte; 20]).pack())
+        .build()
+}
+
+fn global_cell(data: &str) -> KoContextGlobalCell {
+    KoContextGlobalCell::new(script(0), Bytes::from(data.to_owned()), 1000, 100)
+}
+
+fn project() -> KoProject {
+    KoProject::new(
+        Default::default(),
+        Bytes::from(CONTRACT),
+        script(0),
+        vec![],
+        None,
+    )
+}
+
+fn block() -> KoBlockContext {
+    KoBlockContext::new([7u8; 32].into(), 1000, 1_650_000_000_000, 12)
+}
+
+fn request(function_call: &str) -> KoRequest {
+    KoRequest::new(
+        Bytes::from(function_call.to_owned()),
+        vec![(script(1), Bytes::new())],
+        vec![],
+        vec![],
+        0,
+        100,
+    )
+}
+
+// drive executor future to the end, lua itself runs on blocking pool
+fn wait<T>(future: impl Future<Output = T>) -> T {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("runtime")
+        .block_on(future)
+}
+
+fn execute(
+    executor: &ExecutorImpl,
+    global_cell: &mut KoContextGlobalCell,
+    calls: &[&str],
+) -> Vec<Result<(), String>> {
+    let requests = calls.iter().map(|call| request(call)).collect::<Vec<_>>();
+    wait(executor.execute_lua_requests(
+        global_cell,
+        &project(),
+        &requests,
+        &vec![[0, 0]; requests.len()],
+        &block(),
+    ))
+    .expect("execute")
+    .into_iter()
+    .map(|output| output.map(|_| ()).map_err(|err| err.to_string()))
+    .collect()
+}
+
+#[test]
+fn endless_request_exhausts_instruction_budget() {
+    let executor = ExecutorImpl::new(100_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let mut global_cell = global_cell(r#"{"count":0}"#);
+    let results = execute(
+        &executor,
+        &mut global_cell,
+        &["increase()", "endless()", "endless_pcall()", "increase()"],
+    );
+    assert!(results[0].is_ok());
+    assert!(results[1]
+        .as_ref()
+        .unwrap_err()
+        .contains("Instruction budget exhausted"));
+    assert!(results[2]
+        .as_ref()
+        .unwrap_err()
+        .contains("Instruction budget exhausted"));
+    assert!(results[3].is_ok());
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":2}"#));
+}
+
+#[test]
+fn estimate_payment_exhausts_instruction_budget() {
+    let executor = ExecutorImpl::new(100_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let global_cell = global_cell(r#"{"count":0}"#);
+    let error = wait(executor.estimate_payment_ckb(
+        &global_cell,
+        &project(),
+        request("endless()"),
+        &block(),
+    ))
+    .expect_err("estimate");
+    assert!(error.to_string().contains("Instruction budget exhausted"));
+}
+
+#[test]
+fn bloated_request_exceeds_memory_limit() {
+    let executor = ExecutorImpl::new(100_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let mut global_cell = global_cell(r#"{"count":0}"#);
+    let results = execute(
+        &executor,
+        &mut global_cell,
+        &[
+            "increase()",
+            "bloat()",
+            "bloat_pcall()",
+            "catch_errors()",
+            "increase()",
+        ],
+    );
+    assert!(results[0].is_ok());
+    assert!(results[1]
+        .as_ref()
+        .unwrap_err()
+        .contains("memory limit exceeded"));
+    assert!(results[2]
+        .as_ref()
+        .unwrap_err()
+        .contains("memory limit exceeded"));
+    // other errors are still caught along with their error objects
+    assert!(results[3].is_ok());
+    assert!(results[4].is_ok());
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":12}"#));
+}
+
+#[test]
+fn contract_runs_in_sandbox() {
+    let executor = ExecutorImpl::new(100_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let mut global_cell = global_cell(r#"{"count":0}"#);
+    let results = execute(
+        &executor,
+        &mut global_cell,
+        &[
+            "touch_host('return io.open(\"/etc/passwd\")')",
+            "touch_host('return os.time()')",
+            "touch_host('return require(\"os\")')",
+            "touch_host('return debug.getinfo(1)')",
+            "touch_host('return dofile(\"/etc/passwd\")')",
+            "touch_host('return load(string.dump(print))')",
+            "touch_host('return math.randomseed()')",
+            "touch_host('return collectgarbage(\"count\")')",
+            "touch_host('return increase()')",
+        ],
+    );
+    results[..8]
+        .iter()
+        .for_each(|result| assert!(result.is_err()));
+    assert!(results[8].is_ok());
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":1}"#));
+}
+
+#[test]
+fn parse_literal_function_call() {
+    let call = parse_function_call(
+        r#" transfer ( -42, 0x10, 1.5e3, "a\"\65\u{4e2d}", [[raw]], true, nil,
+            { 1, two = 2; ["three"] = { false } } ); "#,
+    )
+    .expect("parse");
+    assert_eq!(call.name, "transfer");
+    assert_eq!(
+        call.arguments,
+        vec![
+            CallArgument::Integer(-42),
+            CallArgument::Integer(16),
+            CallArgument::Number(1500.0),
+            CallArgument::String("a\"A中".as_bytes().to_vec()),
+            CallArgument::String(b"raw".to_vec()),
+            CallArgument::Boolean(true),
+            CallArgument::Nil,
+            CallArgument::Table(vec![
+                (None, CallArgument::Integer(1)),
+                (
+                    Some(CallArgument::String(b"two".to_vec())),
+                    CallArgument::Integer(2)
+                ),
+                (
+                    Some(CallArgument::String(b"three".to_vec())),
+                    CallArgument::Table(vec![(None, CallArgument::Boolean(false))])
+                ),
+            ]),
+        ]
+    );
+    [
+        "",
+        "increase",
+        "increase() increase()",
+        "KOC.global.count = 100",
+        "increase(KOC.owner)",
+        "increase(function () end)",
+        "increase((1))",
+        "increase('x' .. 'y')",
+        "increase({ [{}] = 1 })",
+        "increase('unfinished)",
+        "end()",
+    ]
+    .iter()
+    .for_each(|call| assert!(parse_function_call(call).is_err(), "{}", call));
+}
+
+#[test]
+fn only_contract_functions_are_callable() {
+    let executor = ExecutorImpl::new(100_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let mut global_cell = global_cell(r#"{"count":0}"#);
+    let results = execute(
+        &executor,
+        &mut global_cell,
+        &[
+            "increase() KOC.owner = 'x'",
+            "construct()",
+            "print('x')",
+            "_deep_copy({})",
+            "missing()",
+            "increase()",
+        ],
+    );
+    assert!(results[0]
+        .as_ref()
+        .unwrap_err()
+        .contains("Invalid function call"));
+    results[1..5].iter().for_each(|result| {
+        assert!(result
+            .as_ref()
+            .unwrap_err()
+            .contains("not provided by contract"));
+    });
+    assert!(results[5].is_ok());
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":1}"#));
+}
+
+#[test]
+fn encode_canonical_json_is_deterministic() {
+    let lua = new_sandbox_lua().expect("sandbox");
+    let encode = |chunk: &str| {
+        let value = lua.load(chunk).eval().expect("eval");
+        encode_canonical_json(&lua, &value).map_err(|err| err.to_string())
+    };
+
+    assert_eq!(
+        encode(r#"{ zeta = 1, alpha = { 3, 2, 1 }, ["10"] = true, ["9"] = false }"#).unwrap(),
+        r#"{"10":true,"9":false,"alpha":[3,2,1],"zeta":1}"#
+    );
+    assert_eq!(
+        encode(r#"{ [1] = "a", [3] = "c" }"#).unwrap(),
+        r#"{"1":"a","3":"c"}"#
+    );
+    assert_eq!(encode("{ 1.5, 2.0, -0 }").unwrap(), "[1.5,2.0,0]");
+    assert_eq!(encode(r#"{ s = "\"\n" }"#).unwrap(), r#"{"s":"\"\n"}"#);
+    assert_eq!(encode("{}").unwrap(), "{}");
+    assert!(encode(r#"{ [1] = 1, ["1"] = 2, x = 3 }"#)
+        .unwrap_err()
+        .contains("duplicated keys"));
+    assert!(encode("{ f = print }").unwrap_err().contains("function"));
+    assert!(encode("{ n = 0/0 }").unwrap_err().contains("NaN"));
+    assert!(encode("local t = {} t.t = t return t")
+        .unwrap_err()
+        .contains("cycle"));
+
+    // the same JSON decoded by different paths always encodes into the same bytes
+    let json = r#"{"b":[],"a":{},"c":[{"y":1,"x":2}]}"#;
+    let mut global_cell = global_cell(&format!(r#"{{"count":0,"data":{}}}"#, json));
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let results = execute(
+        &executor,
+        &mut global_cell,
+        &[
+            "increase()",
+            "touch_host('KOC.global.f = print')",
+            "increase()",
+        ],
+    );
+    assert!(results[0].is_ok());
+    assert!(results[1].as_ref().unwrap_err().contains("not encodable"));
+    assert!(results[2].is_ok());
+    assert_eq!(
+        global_cell.output_data,
+        Bytes::from(r#"{"count":2,"data":{"a":{},"b":[],"c":[{"x":2,"y":1}]}}"#)
+    );
+}
+
+#[test]
+fn integers_are_lossless_between_json_and_lua() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let mut large_cell = global_cell(r#"{"count":9007199254740993}"#);
+    let results = execute(&executor, &mut large_cell, &["increase()"]);
+    assert!(results[0].is_ok());
+    assert_eq!(
+        large_cell.output_data,
+        Bytes::from(r#"{"count":9007199254740994}"#)
+    );
+
+    let mut overflow_cell = global_cell(r#"{"count":9223372036854775808}"#);
+    let error = wait(executor.execute_lua_requests(
+        &mut overflow_cell,
+        &project(),
+        &[request("increase()")],
+        &[[0, 0]],
+        &block(),
+    ))
+    .expect_err("out of range");
+    assert!(error.to_string().contains("out of i64 range"));
+}
+
+#[test]
+fn ckb_amounts_are_exact_shannons() {
+    let lua = new_sandbox_lua().expect("sandbox");
+    let amount = |chunk: &str, unit: Option<&str>| {
+        let value = lua.load(chunk).eval().expect("eval");
+        ckb_amount_to_shannons(value, unit.map(String::from)).map_err(|err| err.to_string())
+    };
+
+    assert_eq!(amount("0.29", None), Ok(29_000_000));
+    assert_eq!(amount("'0.29'", None), Ok(29_000_000));
+    assert_eq!(
+        amount("'123456789.12345678'", None),
+        Ok(12_345_678_912_345_678)
+    );
+    assert_eq!(amount("42", Some("ckb")), Ok(4_200_000_000));
+    assert_eq!(amount("42", Some("shannon")), Ok(42));
+    assert_eq!(
+        amount("'18446744073709551615'", Some("shannon")),
+        Ok(u64::MAX)
+    );
+    assert!(amount("'0.123456789'", None).is_err());
+    assert!(amount("'1.5'", Some("shannon")).is_err());
+    assert!(amount("1.5", Some("shannon")).is_err());
+    assert!(amount("-1", None).is_err());
+    assert!(amount("'1.'", None).is_err());
+    assert!(amount("'-1'", None).is_err());
+    assert!(amount("1e300", None).is_err());
+    assert!(amount("92233720368547758", None).is_err());
+    assert!(amount("1", Some("wei")).is_err());
+
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let global_cell = global_cell(r#"{"count":0}"#);
+    let estimate = |call: &str| {
+        wait(executor.estimate_payment_ckb(&global_cell, &project(), request(call), &block()))
+            .map_err(|err| err.to_string())
+    };
+    assert_eq!(estimate("pay(0.29)"), Ok(29_000_000));
+    assert_eq!(estimate("pay('1.00000001')"), Ok(100_000_001));
+    assert_eq!(estimate("pay(12345, 'shannon')"), Ok(12345));
+    assert!(estimate("pay({})")
+        .unwrap_err()
+        .contains("Invalid ckb amount"));
+}
+
+#[test]
+fn simulate_request_reports_outputs_and_global() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let global_cell = global_cell(r#"{"count":0}"#);
+    let simulate = |call: &str| {
+        wait(executor.simulate_request(&global_cell, &project(), request(call), &block()))
+            .expect("simulate")
+    };
+
+    let simulation = simulate("increase()");
+    let output = simulation.outputs.expect("outputs");
+    assert_eq!(output.cells.len(), 1);
+    assert_eq!(output.cells[0].0.as_slice(), script(1).as_slice());
+    assert_eq!(simulation.global_data, Bytes::from(r#"{"count":1}"#));
+    assert_eq!(simulation.deposit_ckb, 0);
+    assert_eq!(simulation.withdraw_ckb, 0);
+
+    let simulation = simulate("pay('1.5')");
+    assert!(simulation.outputs.is_ok());
+    assert_eq!(simulation.deposit_ckb, 150_000_000);
+
+    let simulation = simulate("cashout(300, 'shannon')");
+    assert_eq!(
+        simulation.outputs.expect("outputs").suggested_capacity,
+        100 + 300
+    );
+    assert_eq!(simulation.global_data, Bytes::from(r#"{"count":-1}"#));
+    assert_eq!(simulation.withdraw_ckb, 300);
+
+    let simulation = simulate("cashout(1000, 'shannon')");
+    assert!(simulation
+        .outputs
+        .unwrap_err()
+        .to_string()
+        .contains("insufficient ckb"));
+    assert_eq!(simulation.global_data, Bytes::from(r#"{"count":0}"#));
+    assert_eq!(simulation.withdraw_ckb, 0);
+
+    // withdrawals which overflow in total are insufficient as well
+    let simulation = simulate("cashout_twice(100, 200)");
+    assert_eq!(simulation.withdraw_ckb, 300);
+    let simulation = simulate("cashout_twice(1, '18446744073709551615')");
+    assert!(simulation
+        .outputs
+        .unwrap_err()
+        .to_string()
+        .contains("insufficient ckb"));
+    assert_eq!(simulation.withdraw_ckb, 1);
+}
+
+#[test]
+fn view_call_is_read_only() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let global_cell = global_cell(r#"{"count":1}"#);
+    let view = |call: &str| {
+        wait(executor.call_view(
+            &global_cell,
+            &project(),
+            call,
+            &[(script(1), Bytes::from(r#"{"level":1}"#))],
+            &block(),
+        ))
+        .map_err(|err| err.to_string())
+    };
+
+    assert_eq!(
+        view("view_count(2)"),
+        Ok(r#"{"count":3,"inputs":1}"#.into())
+    );
+    assert_eq!(view("view_count()"), Ok(r#"{"count":1,"inputs":1}"#.into()));
+    assert!(view("increase()")
+        .unwrap_err()
+        .contains("must not change KOC"));
+    assert!(view("touch_host('KOC.inputs[1].data.level = 2')")
+        .unwrap_err()
+        .contains("must not change KOC"));
+    assert!(view("touch_host('KOC = {}')")
+        .unwrap_err()
+        .contains("must not change KOC"));
+    assert!(view("pay(1)")
+        .unwrap_err()
+        .contains("KOC.ckb_deposit is not allowed in view call"));
+    assert!(view("construct()")
+        .unwrap_err()
+        .contains("Function is not provided by contract"));
+}
+
+#[test]
+fn emitted_events_are_collected_per_request() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let mut global_cell = global_cell(r#"{"count":0}"#);
+    let requests = ["lottery(42)", "lottery_failed()", "increase()"]
+        .iter()
+        .map(|call| request(call))
+        .collect::<Vec<_>>();
+    let outputs = wait(executor.execute_lua_requests(
+        &mut global_cell,
+        &project(),
+        &requests,
+        &[[0, 0]; 3],
+        &block(),
+    ))
+    .expect("execute");
+    assert_eq!(
+        outputs[0].as_ref().expect("lottery").events,
+        vec![KoEvent::new("won".into(), r#"{"nft":42}"#.into())]
+    );
+    assert!(outputs[1].is_err());
+    assert!(outputs[2].as_ref().expect("increase").events.is_empty());
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":2}"#));
+
+    let error = wait(executor.call_view(&global_cell, &project(), "lottery(1)", &[], &block()))
+        .expect_err("view emits");
+    assert!(error
+        .to_string()
+        .contains("KOC.emit is not allowed in view call"));
+}
+
+#[test]
+fn block_context_is_visible_in_koc() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let result = wait(executor.call_view(
+        &global_cell(r#"{"count":0}"#),
+        &project(),
+        "touch_host('return { KOC.block_hash, KOC.block_number, KOC.timestamp, KOC.epoch }')",
+        &[],
+        &block(),
+    ))
+    .expect("view");
+    assert_eq!(
+        result,
+        format!(r#"["{}",1000,1650000000000,12]"#, "07".repeat(32))
+    );
+}
+
+#[test]
+fn random_seeds_are_derived_per_request() {
+    let outpoint = |index: u32| {
+        ko_protocol::ckb_types::packed::OutPoint::new_builder()
+            .tx_hash([1u8; 32].pack())
+            .index(index.pack())
+            .build()
+            .into()
+    };
+    let seed_one = KoRandomSeed::derive(outpoint(0), [9u8; 32].into(), 1002);
+    let seed_two = KoRandomSeed::derive(outpoint(1), [9u8; 32].into(), 1002);
+    assert!(seed_one.verify() && seed_two.verify());
+    assert_ne!(seed_one.seeds, seed_two.seeds);
+    let mut forged = seed_one.clone();
+    forged.seed_block_hash = [8u8; 32].into();
+    assert!(!forged.verify());
+
+    // a request rolls the same number no matter which requests run before it
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let roll = |seeds: &[[i64; 2]]| {
+        let mut global_cell = global_cell(r#"{"last":0}"#);
+        let requests = seeds.iter().map(|_| request("roll()")).collect::<Vec<_>>();
+        wait(executor.execute_lua_requests(
+            &mut global_cell,
+            &project(),
+            &requests,
+            seeds,
+            &block(),
+        ))
+        .expect("execute");
+        global_cell.output_data
+    };
+    let alone = roll(&[seed_one.seeds]);
+    let after_other = roll(&[seed_two.seeds, seed_one.seeds]);
+    assert_eq!(alone, after_other);
+    assert!(String::from_utf8(alone.to_vec())
+        .unwrap()
+        .contains(&seed_one.seeds[0].to_string()));
+
+    let error = wait(executor.execute_lua_requests(
+        &mut global_cell("{}"),
+        &project(),
+        &[request("roll()")],
+        &[],
+        &block(),
+    ))
+    .expect_err("seeds mismatch");
+    assert!(error.to_string().contains("Random seeds count mismatch"));
+}
+
+#[test]
+fn project_chunk_is_cached_by_outpoint() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let upgraded = |index: u32| {
+        let cell_dep = CellDep::new_builder()
+            .out_point(OutPoint::new_builder().index(index.pack()).build())
+            .build();
+        let code = CONTRACT.replace("KOC.global.count + 1\n", "KOC.global.count + 10\n");
+        KoProject::new(cell_dep, Bytes::from(code), script(0), vec![], None)
+    };
+    let increase = |project: &KoProject| {
+        let mut global_cell = global_cell(r#"{"count":0}"#);
+        wait(executor.execute_lua_requests(
+            &mut global_cell,
+            project,
+            &[request("increase()")],
+            &[[0, 0]],
+            &block(),
+        ))
+        .expect("execute")
+        .remove(0)
+        .expect("increase");
+        global_cell.output_data
+    };
+
+    // code of the same project cell is loaded only once
+    assert_eq!(increase(&project()), Bytes::from(r#"{"count":1}"#));
+    let mut stale = upgraded(0);
+    stale.cell_dep = project().cell_dep;
+    assert_eq!(increase(&stale), Bytes::from(r#"{"count":1}"#));
+
+    // a new project cell replaces the cached vm
+    assert_eq!(increase(&upgraded(1)), Bytes::from(r#"{"count":10}"#));
+    assert_eq!(increase(&project()), Bytes::from(r#"{"count":1}"#));
+}
+
+#[test]
+fn cached_project_is_restored_after_each_call() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(2));
+    let leak = || {
+        let mut global_cell = global_cell(r#"{"count":0}"#);
+        wait(executor.execute_lua_requests(
+            &mut global_cell,
+            &project(),
+            &[request("leak()")],
+            &[[0, 0]],
+            &block(),
+        ))
+        .expect("execute")
+        .remove(0)
+        .expect("leak");
+        global_cell.output_data
+    };
+
+    // upvalues, globals and library tables changed by a call never reach the next one
+    assert_eq!(leak(), Bytes::from(r#"{"count":11}"#));
+    assert_eq!(leak(), Bytes::from(r#"{"count":11}"#));
+    let view = wait(executor.call_view(
+        &global_cell(r#"{"count":0}"#),
+        &project(),
+        "view_count()",
+        &[],
+        &block(),
+    ))
+    .expect("view");
+    assert_eq!(view, r#"{"count":0,"inputs":0}"#);
+    assert_eq!(leak(), Bytes::from(r#"{"count":11}"#));
+}
+
+#[tokio::test]
+async fn execution_runs_off_async_runtime() {
+    let executor = ExecutorImpl::new(50_000_000, 16 * 1024 * 1024, &ExecutorPool::new(1));
+    let heavy = {
+        let executor = executor.clone();
+        tokio::spawn(async move {
+            let global_cell = global_cell(r#"{"count":0}"#);
+            executor
+                .estimate_payment_ckb(&global_cell, &project(), request("endless()"), &block())
+                .await
+        })
+    };
+
+    // single-threaded runtime keeps ticking while the contract spins
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!heavy.is_finished());
+    let error = heavy.await.expect("join").expect_err("endless");
+    assert!(error.to_string().contains("Instruction budget exhausted"));
+}
+
+#[test]
+fn require_resolves_pinned_module_by_type_id() {
+    const MODULE: &str = r#"
+        local counter = { step = 5 }
+        function counter.bump(value)
+            return value + counter.step
+        end
+        return counter
+    "#;
+    const MODULE_CONTRACT: &str = r#"
+        local counter = require("0x0505050505050505050505050505050505050505050505050505050505050505")
+        assert(require("0505050505050505050505050505050505050505050505050505050505050505") == counter)
+
+        function construct()
+            return { driver = KOC.driver, global = { count = 0 } }
+        end
+
+        function bump()
+            KOC.global.count = counter.bump(KOC.global.count)
+            return { global = KOC.global }
+        end
+    "#;
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(1));
+    let project_with = |modules: Vec<KoLuaModule>| {
+        KoProject::new(
+            Default::default(),
+            Bytes::from(MODULE_CONTRACT),
+            script(0),
+            modules,
+            None,
+        )
+    };
+    let bump = |project: &KoProject| {
+        let mut global_cell = global_cell(r#"{"count":0}"#);
+        wait(executor.execute_lua_requests(
+            &mut global_cell,
+            project,
+            &[request("bump()")],
+            &[[0, 0]],
+            &block(),
+        ))
+        .map(|_| global_cell.output_data)
+    };
+    let pinned = KoLuaModule::pinned([5u8; 32].into(), Bytes::from(MODULE));
+
+    // compiled module is reused by later lua vms
+    let project = project_with(vec![pinned.clone()]);
+    assert_eq!(bump(&project).unwrap(), Bytes::from(r#"{"count":5}"#));
+    assert_eq!(bump(&project).unwrap(), Bytes::from(r#"{"count":5}"#));
+
+    // module cell upgraded after project pinned it
+    let mut upgraded = pinned.clone();
+    upgraded.code = Bytes::from(MODULE.replace("step = 5", "step = 6"));
+    let error = bump(&project_with(vec![upgraded])).expect_err("upgraded module");
+    assert!(error.to_string().contains("pinned data hash"));
+
+    // bytecode modules can't be loaded even if their hash is pinned
+    let bytecode = mlua::Lua::new()
+        .load(MODULE)
+        .into_function()
+        .unwrap()
+        .dump(false);
+    let binary = KoLuaModule::pinned([5u8; 32].into(), Bytes::from(bytecode));
+    let error = bump(&project_with(vec![binary])).expect_err("binary module");
+    assert!(error.to_string().contains("attempt to load a binary chunk"));
+
+    let error = bump(&project_with(vec![])).expect_err("missing module");
+    assert!(error.to_string().contains("is not a celldep of project"));
+}
+
+#[test]
+fn contract_abi_is_read_from_bytecode() {
+    const ABI_CONTRACT: &str = r#"
+        local secret = 42
+
+        function construct()
+            return { driver = KOC.driver, global = {} }
+        end
+
+        function transfer(to, amount)
+            return secret + amount
+        end
+
+        function batch(kind, ...)
+            return select('#', ...)
+        end
+
+        function ping() end
+    "#;
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(1));
+    let project = KoProject::new(
+        Default::default(),
+        Bytes::from(ABI_CONTRACT),
+        script(0),
+        vec![],
+        None,
+    );
+    let abi = wait(executor.get_contract_abi(&project)).expect("abi");
+    assert_eq!(
+        abi.functions,
+        vec![
+            KoFunctionAbi::new("batch".into(), 1, true),
+            KoFunctionAbi::new("ping".into(), 0, false),
+            KoFunctionAbi::new("transfer".into(), 2, false),
+        ]
+    );
+
+    // manifest narrows public functions down
+    let mut published = project.clone();
+    published.manifest = Some(KoProjectManifest {
+        functions: vec![KoFunctionAbi::new("transfer".into(), 2, false)],
+        ..Default::default()
+    });
+    let published_abi = wait(executor.get_contract_abi(&published)).expect("abi");
+    assert_eq!(published_abi.functions, abi.functions[2..]);
+    assert!(check_function_call(&published_abi, "ping()").is_err());
+
+    assert!(check_function_call(&abi, "transfer('ab', 10)").is_ok());
+    assert!(check_function_call(&abi, "transfer('ab', nil)").is_ok());
+    assert!(check_function_call(&abi, "batch(1)").is_ok());
+    assert!(check_function_call(&abi, "batch(1, 2, 3)").is_ok());
+    [
+        ("transfer('ab')", "expect at least 2 arguments, got 1"),
+        ("transfer('ab', 10, 1)", "expect at most 2 arguments, got 3"),
+        ("batch()", "expect at least 1 arguments, got 0"),
+        ("ping(1)", "expect at most 0 arguments, got 1"),
+        ("construct()", "Function is not provided by contract"),
+        ("print('x')", "Function is not provided by contract"),
+    ]
+    .iter()
+    .for_each(|(call, reason)| {
+        let error = check_function_call(&abi, call).expect_err(call);
+        assert!(error.to_string().contains(reason), "{}", error);
+    });
+}
+
+#[test]
+fn manifest_narrows_callable_functions() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(1));
+    let mut project = project();
+    project.manifest = Some(KoProjectManifest {
+        functions: vec![KoFunctionAbi::new("increase".into(), 0, false)],
+        views: vec!["view_count".into()],
+        ..Default::default()
+    });
+    let mut global_cell = global_cell(r#"{"count":0}"#);
+    let results = wait(executor.execute_lua_requests(
+        &mut global_cell,
+        &project,
+        &[
+            request("increase()"),
+            request("roll()"),
+            request("view_count()"),
+        ],
+        &[[0, 0]; 3],
+        &block(),
+    ))
+    .expect("execute");
+    assert!(results[0].is_ok());
+    results[1..].iter().for_each(|result| {
+        assert!(result
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("not provided by contract"));
+    });
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":1}"#));
+
+    // views can call declared views besides declared functions
+    let view = |call: &str| {
+        wait(executor.call_view(&global_cell, &project, call, &[], &block()))
+            .map_err(|err| err.to_string())
+    };
+    assert_eq!(view("view_count()"), Ok(r#"{"count":1,"inputs":0}"#.into()));
+    assert!(view("roll()")
+        .unwrap_err()
+        .contains("not provided by contract"));
+}
+
+#[test]
+fn migration_rewrites_global_before_requests() {
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(1));
+    let upgraded = |index: u32, migrate: &str| {
+        let cell_dep = CellDep::new_builder()
+            .out_point(OutPoint::new_builder().index(index.pack()).build())
+            .build();
+        let code = format!("{}\n{}", CONTRACT, migrate);
+        KoProject::new(cell_dep, Bytes::from(code), script(0), vec![], None)
+    };
+    let migrate = |project: &KoProject| {
+        let mut global_cell = global_cell(r#"{"count":3}"#);
+        wait(executor.migrate_global(&mut global_cell, project, &block()))
+            .map(|_| String::from_utf8_lossy(&global_cell.output_data).into_owned())
+            .map_err(|err| err.to_string())
+    };
+
+    // returned table replaces global, nil keeps changes made in place
+    let renamed = upgraded(
+        1,
+        "function migrate(global) return { total = global.count } end",
+    );
+    assert_eq!(migrate(&renamed), Ok(r#"{"total":3}"#.into()));
+    let in_place = upgraded(2, "function migrate(global) global.count = 0 end");
+    assert_eq!(migrate(&in_place), Ok(r#"{"count":0}"#.into()));
+    assert_eq!(migrate(&project()), Ok(r#"{"count":3}"#.into()));
+
+    let invalid = upgraded(3, "function migrate(global) return 1 end");
+    assert!(migrate(&invalid).is_err());
+    let endless = upgraded(4, "function migrate(global) while true do end end");
+    assert!(migrate(&endless).is_err());
+
+    // migrate is never callable by requests
+    let abi = wait(executor.get_contract_abi(&renamed)).expect("abi");
+    assert!(abi.get("migrate").is_none());
+}
+
+#[test]
+fn failed_request_reports_contract_line_with_debug_info() {
+    const DEBUG_CONTRACT: &str = r#"
+        function construct() end
+        local function check(amount)
+            assert(amount > 1, "amount too small")
+        end
+        function transfer(to, amount)
+            check(amount)
+        end
+    "#;
+    let compile = |strip: bool| {
+        let lua = mlua::Lua::new();
+        let chunk = lua
+            .load(DEBUG_CONTRACT)
+            .set_name("=contract")
+            .and_then(|chunk| chunk.into_function())
+            .expect("compile");
+        chunk.dump(strip)
+    };
+    let executor = ExecutorImpl::new(10_000_000, 16 * 1024 * 1024, &ExecutorPool::new(1));
+    let transfer = |index: u32, bytecode: Vec<u8>| {
+        let cell_dep = CellDep::new_builder()
+            .out_point(OutPoint::new_builder().index(index.pack()).build())
+            .build();
+        let project = KoProject::new(cell_dep, Bytes::from(bytecode), script(0), vec![], None);
+        let mut global_cell = global_cell("{}");
+        let abi = wait(executor.get_contract_abi(&project)).expect("abi");
+        assert_eq!(abi.get("transfer").map(|abi| abi.params), Some(2));
+        wait(executor.execute_lua_requests(
+            &mut global_cell,
+            &project,
+            &[request("transfer('ab', 1)")],
+            &[[0, 0]],
+            &block(),
+        ))
+        .expect("execute")
+        .remove(0)
+        .expect_err("transfer")
+        .to_string()
+    };
+
+    let error = transfer(1, compile(false));
+    assert!(error.contains("contract:4: amount too small"), "{}", error);
+    assert!(error.contains("stack traceback"), "{}", error);
+    assert!(
+        error.contains("contract:7: in function 'transfer'"),
+        "{}",
+        error
+    );
+
+    // stripped bytecode has traceback only
+    let error = transfer(2, compile(true));
+    assert!(!error.contains("contract:4:"), "{}", error);
+    assert!(error.contains("in function 'transfer'"), "{}", error);
+}
@@ -1,44 +1,74 @@
 use std::collections::HashMap;
 use std::time::Duration;
 
-use ko_context_assembler::AssemblerImpl;
-use ko_context_driver::DriverImpl;
-use ko_context_executor::ExecutorImpl;
+use ko_context_assembler::{discover_managed_projects, AssemblerImpl};
+use ko_context_driver::{DriverError, DriverImpl};
+use ko_context_executor::{ExecutorImpl, ExecutorPool};
 use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::core::TransactionView;
 use ko_protocol::ckb_types::packed::Script;
 use ko_protocol::ckb_types::prelude::Unpack;
 use ko_protocol::secp256k1::SecretKey;
 use ko_protocol::tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use ko_protocol::tokio::sync::Mutex;
 use ko_protocol::tokio::task::JoinHandle;
-use ko_protocol::traits::{Assembler, CkbClient, ContextRpc, Driver, Executor};
-use ko_protocol::types::assembler::{KoCellOutput, KoProject, KoRequest};
-use ko_protocol::types::context::{KoContextGlobalCell, KoContextRpcEcho};
-use ko_protocol::types::{config::KoDriveConfig, error::ErrorType};
+use ko_protocol::traits::{Assembler, CkbClient, ContextRpc, Driver, Executor, Storage};
+use ko_protocol::types::assembler::{
+    KoAssembleReceipt, KoBlockContext, KoCellOutput, KoContractAbi, KoEvent, KoProject, KoRequest,
+    KoRequestSimulation,
+};
+use ko_protocol::types::config::{KoDriveConfig, KoFeeConfig};
+use ko_protocol::types::context::{
+    KoContextGlobalCell, KoContextRpcEcho, KoDriveAction, KoDriveAttempt, KoPendingDrive,
+    KoRejection, KoRequestReceipt, KoTransactionStatus,
+};
+use ko_protocol::types::error::ErrorType;
 use ko_protocol::{async_trait, lazy_static, log, tokio, KoResult, ProjectDeps, H256};
+use ko_storage::{StorageError, StorageImpl};
 
 #[cfg(test)]
 mod tests;
 
+mod verify;
+pub use verify::{compare_transaction_outputs, verify_drive_transaction};
+
 #[derive(Default)]
 struct ProjectContext {
-    pub contract_code: Bytes,
-    pub project_owner: Script,
+    pub project: KoProject,
     pub global_cell: KoContextGlobalCell,
+    pub block_context: KoBlockContext,
+}
+
+// drive transaction along with the parts to rebuild it with higher fee
+struct DriveTransaction {
+    unsigned_tx: TransactionView,
+    cell_outputs: Vec<KoCellOutput>,
+    inputs_capacity: u64,
+    fee_rate: u64,
+    signed_tx: TransactionView,
+    fee: u64,
+}
+
+fn next_global_cell(tx: &TransactionView) -> KoContextGlobalCell {
+    let output = tx.output(0).unwrap();
+    let data = tx.outputs_data().get(0).unwrap();
+    KoContextGlobalCell::from_output(output, data.unpack())
 }
 
 pub struct ContextImpl<C: CkbClient> {
     pub assembler: AssemblerImpl<C>,
     pub executor: ExecutorImpl,
     pub driver: DriverImpl<C>,
+    pub storage: StorageImpl,
 
+    rpc_client: C,
     drive_interval: Duration,
     idle_duration: Duration,
     config: KoDriveConfig,
 
     project_context: ProjectContext,
     rpc_receiver: UnboundedReceiver<KoContextRpcEcho>,
-    listening_requests: HashMap<H256, UnboundedSender<KoResult<H256>>>,
+    listening_requests: HashMap<H256, UnboundedSender<KoResult<KoRequestReceipt>>>,
 }
 
 impl<C: CkbClient> ContextImpl<C> {
@@ -48,12 +78,25 @@ impl<C: CkbClient> ContextImpl<C> {
         project_type_args: &H256,
         project_deps: &ProjectDeps,
         config: &KoDriveConfig,
+        executor_pool: &ExecutorPool,
+        storage: &StorageImpl,
     ) -> (ContextImpl<C>, UnboundedSender<KoContextRpcEcho>) {
         let (sender, receiver) = unbounded_channel();
         let context = ContextImpl {
-            assembler: AssemblerImpl::new(rpc_client, project_type_args, project_deps),
-            executor: ExecutorImpl::new(),
+            assembler: AssemblerImpl::new(
+                rpc_client,
+                project_type_args,
+                project_deps,
+                config.random_seed_block_delay,
+            ),
+            executor: ExecutorImpl::new(
+                config.project_max_instructions(project_type_args),
+                config.max_lua_memory_bytes,
+                executor_pool,
+            ),
             driver: DriverImpl::new(rpc_client, privkey),
+            storage: storage.clone(),
+            rpc_client: rpc_client.clone(),
             drive_interval: Duration::ZERO,
             idle_duration: Duration::ZERO,
             config: config.clone(),
@@ -65,10 +108,12 @@ impl<C: CkbClient> ContextImpl<C> {
     }
 
     async fn start_drive_loop(&mut self) -> KoResult<()> {
-        let contract_dep = self.assembler.prepare_transaction_project_celldep().await?;
-        self.project_context.contract_code = contract_dep.lua_code.clone();
-        self.project_context.project_owner = contract_dep.contract_owner.clone();
-        self.project_context.global_cell = self.assembler.get_project_global_cell().await?;
+        self.project_context.project = self.assembler.prepare_transaction_project_celldep().await?;
+        self.project_context.block_context = self.assembler.get_tip_block_context().await?;
+        self.project_context.global_cell = match self.resume_pending_drive().await? {
+            Some(global_cell) => global_cell,
+            None => self.assembler.get_project_global_cell().await?,
+        };
 
         log::info!(
             "[{}] knside-out drive server started new drive loop",
@@ -80,7 +125,9 @@ impl<C: CkbClient> ContextImpl<C> {
         loop {
             tokio::select! {
                 _ = tokio::time::sleep(self.drive_interval) => {
-                    if let Some(hash) = self.drive(&contract_dep).await? {
+                    self.refresh_project().await?;
+                    let project = self.project_context.project.clone();
+                    if let Some(hash) = self.drive(&project).await? {
                         log::info!(
                             "[{}] transaction #{} confirmed",
                             self.assembler.get_project_args(),
@@ -106,11 +153,38 @@ impl<C: CkbClient> ContextImpl<C> {
                             &inputs,
                             &candidates,
                             &components
-                        );
+                        ).await;
                         response.send(payment_ckb).expect("EstimatePaymentCkb channel");
                     },
+                    KoContextRpcEcho::SimulateRequest(
+                        ((inputs, method_call, candidates, components), response)
+                    ) => {
+                        let simulation = self.simulate_request(
+                            &method_call,
+                            &inputs,
+                            &candidates,
+                            &components
+                        ).await;
+                        response.send(simulation).expect("SimulateRequest channel");
+                    },
+                    KoContextRpcEcho::CallView(((inputs, method_call), response)) => {
+                        let result = self.call_view(&method_call, &inputs).await;
+                        response.send(result).expect("CallView channel");
+                    },
                     KoContextRpcEcho::ListenRequestCommitted((hash, response))=> {
                         self.listen_request_committed(&hash, response);
+                    },
+                    KoContextRpcEcho::FetchRequestReceipt((hash, response)) => {
+                        let receipt = self.fetch_request_receipt(&hash);
+                        response.send(receipt).expect("FetchRequestReceipt channel");
+                    },
+                    KoContextRpcEcho::GetContractAbi(response) => {
+                        let abi = self.get_contract_abi().await;
+                        response.send(abi).expect("GetContractAbi channel");
+                    },
+                    KoContextRpcEcho::PreviewUpgrade((project, response)) => {
+                        let preview = self.preview_upgrade(&project).await;
+                        response.send(preview).expect("PreviewUpgrade channel");
                     }
                 }
             }
@@ -123,6 +197,77 @@ impl<C: CkbClient> ContextImpl<C> {
         Ok(())
     }
 
+    // wait for the drive transaction which was sent before restart, then answer its requests
+    async fn resume_pending_drive(&mut self) -> KoResult<Option<KoContextGlobalCell>> {
+        let project_type_args = self.assembler.get_project_args().clone();
+        let mut drive = match self.storage.load_pending_drive(&project_type_args)? {
+            Some(drive) => drive,
+            None => return Ok(None),
+        };
+        log::info!(
+            "[{}] resume pending drive transaction #{}",
+            project_type_args,
+            drive.transaction_hash
+        );
+        // the transaction can't be rebuilt after restart, so it's only waited
+        let interval = Duration::from_secs(self.config.drive_interval_sec as u64);
+        let pending_timeout = Duration::from_secs(self.config.pending_timeout_sec);
+        let global_cell = loop {
+            let status = self
+                .driver
+                .wait_transaction_committed(
+                    &drive.transaction_hash,
+                    &interval,
+                    self.config.block_confirms_count,
+                    &pending_timeout,
+                )
+                .await?;
+            match status {
+                KoTransactionStatus::Committed => {
+                    break self.storage.load_global_cell(&project_type_args)?
+                }
+                KoTransactionStatus::Pending => log::warn!(
+                    "[{}] pending drive transaction #{} still not committed",
+                    project_type_args,
+                    drive.transaction_hash
+                ),
+                KoTransactionStatus::Rejected(rejection) => {
+                    // the replaced transaction may have been committed before this one
+                    if self.find_committed_attempt(&mut drive).await? {
+                        break Some(self.project_context.global_cell.clone());
+                    }
+                    // requests of dropped transaction are still live, so they will be driven again
+                    log::warn!(
+                        "[{}] pending drive transaction #{} dropped: {:?}",
+                        project_type_args,
+                        drive.transaction_hash,
+                        rejection
+                    );
+                    self.storage.discard_pending_drive(&project_type_args)?;
+                    return Ok(None);
+                }
+            }
+        };
+        self.project_context.block_context = drive.block_context.clone();
+        self.complete_drive(drive)?;
+        Ok(global_cell)
+    }
+
+    // pick up the upgraded project cell, global data is migrated in the following drive
+    async fn refresh_project(&mut self) -> KoResult<()> {
+        let out_point = self.assembler.get_project_out_point().await?;
+        if out_point != self.project_context.project.cell_dep.out_point() {
+            self.project_context.project =
+                self.assembler.prepare_transaction_project_celldep().await?;
+            log::info!(
+                "[{}] project upgraded, deployment = {}",
+                self.assembler.get_project_args(),
+                out_point
+            );
+        }
+        Ok(())
+    }
+
     pub(self) async fn drive(&mut self, project_dep: &KoProject) -> KoResult<Option<H256>> {
         // assemble knside-out transaction
         let (tx, mut receipt) = self
@@ -132,116 +277,249 @@ impl<C: CkbClient> ContextImpl<C> {
                 &project_dep.cell_dep,
             )
             .await?;
-        if receipt.requests.is_empty() {
+        if receipt.requests.is_empty() && !receipt.migrate_global {
             return Ok(None);
         }
         log::info!(
-            "[{}] start to assemble knside-out transaction, requests count = {}",
+            "[{}] start to assemble knside-out transaction, requests count = {}, migration = {}",
             self.assembler.get_project_args(),
-            receipt.requests.len()
+            receipt.requests.len(),
+            receipt.migrate_global
         );
-        let mut total_inputs_capacity = receipt.global_cell.capacity;
-        let personal_outputs = self.executor.execute_lua_requests(
-            &mut receipt.global_cell,
-            &project_dep.contract_owner,
-            &receipt.requests,
-            &project_dep.lua_code,
-            &receipt.random_seeds,
-        )?;
-        let mut cell_outputs = vec![KoCellOutput::new(
-            vec![(
-                receipt.global_cell.lock_script.clone(),
-                Some(receipt.global_cell.output_data.clone()),
-            )],
-            receipt.global_cell.capacity,
-        )];
-
-        // trim unworkable requests from transaction inputs
-        let mut request_hashes = tx
+        let total_inputs_capacity = receipt.global_cell.capacity
+            + receipt
+                .requests
+                .iter()
+                .map(|request| request.capacity)
+                .sum::<u64>();
+        let (cell_outputs, request_results) =
+            execute_assemble_receipt(&self.executor, project_dep, &mut receipt).await?;
+        let request_results = tx
             .inputs()
             .into_iter()
             .skip(1)
-            .map(|input| (input.previous_output().tx_hash().unpack(), None))
-            .collect::<Vec<(H256, _)>>();
-        personal_outputs
-            .into_iter()
-            .enumerate()
-            .for_each(|(i, output)| match output {
-                Ok(output_assemble) => {
-                    cell_outputs.push(output_assemble);
-                    total_inputs_capacity += receipt.requests[i].capacity;
-                }
-                Err(err) => {
-                    // recover the previous cell before its request operation
-                    let request = &receipt.requests[i];
-                    let cells = request
-                        .inputs
-                        .iter()
-                        .map(|(script, data)| {
-                            if data.is_empty() {
-                                (script.clone(), None)
-                            } else {
-                                (script.clone(), Some(data.clone()))
-                            }
-                        })
-                        .collect::<Vec<_>>();
-                    cell_outputs.push(KoCellOutput::new(cells, request.capacity));
-                    total_inputs_capacity += receipt.requests[i].capacity;
-                    request_hashes[i].1 = Some(err);
-                }
-            });
+            .map(|input| input.previous_output().tx_hash().unpack())
+            .zip(request_results)
+            .map(|(request_hash, events)| (request_hash, events.map_err(|err| err.to_string())))
+            .collect::<Vec<_>>();
 
-        // complete transaction
-        let tx = self
-            .assembler
-            .fill_transaction_with_outputs(tx, &cell_outputs, total_inputs_capacity, 100_000_000)
+        // complete transaction with the fee policy of project
+        let fee_config = self
+            .config
+            .fee_settings
+            .project_fee(self.assembler.get_project_args());
+        let fee_rate = fee_config.estimate_fee_rate(&self.rpc_client).await;
+        let mut drive_tx = DriveTransaction {
+            unsigned_tx: tx.clone(),
+            cell_outputs,
+            inputs_capacity: total_inputs_capacity,
+            fee_rate,
+            signed_tx: tx,
+            fee: 0,
+        };
+        self.complete_transaction(&mut drive_tx, 0).await?;
+        log::info!(
+            "[{}] pay fee = {} shannons, fee rate = {}",
+            self.assembler.get_project_args(),
+            drive_tx.fee,
+            fee_rate
+        );
+        let hash = self
+            .driver
+            .send_transaction(drive_tx.signed_tx.clone())
             .await?;
-        let signature = self.driver.sign_transaction(&tx);
-        let tx = self
-            .assembler
-            .complete_transaction_with_signature(tx, signature);
-        let next_global_cell = tx.output(0).unwrap().clone();
-        let next_global_data = tx.outputs_data().get(0).unwrap().clone();
-        let hash = self.driver.send_transaction(tx).await?;
 
-        // record last running context
-        self.project_context.global_cell =
-            KoContextGlobalCell::from_output(next_global_cell, next_global_data.unpack());
+        // record last running context, which survives restart until transaction confirmed
+        self.project_context.global_cell = next_global_cell(&drive_tx.signed_tx);
+        self.project_context.block_context = receipt.block_context.clone();
+        let attempt = KoDriveAttempt::new(hash.clone(), drive_tx.fee, KoDriveAction::Submit, None);
+        let mut drive = KoPendingDrive::new(
+            hash,
+            request_results,
+            receipt.block_context,
+            receipt.random_seeds,
+            vec![attempt],
+        );
+        self.storage.save_pending_drive(
+            self.assembler.get_project_args(),
+            &drive,
+            &self.project_context.global_cell,
+        )?;
 
         // wait transaction has been confirmed for enough confirmations
-        self.driver
-            .wait_transaction_committed(
-                &hash,
-                &self.drive_interval,
-                self.config.block_confirms_count,
-            )
-            .await?;
+        self.wait_drive_committed(&mut drive, drive_tx).await?;
 
-        // clear request listening callbacks
-        request_hashes
-            .into_iter()
-            .for_each(|(request_hash, error)| {
-                if let Some(callback) = self.listening_requests.remove(&request_hash) {
-                    if let Err(err) = {
-                        if let Some(msg) = error {
-                            callback.send(Err(msg))
-                        } else {
-                            callback.send(Ok(request_hash))
+        // store request outcomes and clear request listening callbacks
+        let hash = drive.transaction_hash.clone();
+        self.complete_drive(drive)?;
+
+        Ok(Some(hash))
+    }
+
+    // fill outputs and sign, the fee grows until it covers the size of signed transaction
+    async fn complete_transaction(
+        &self,
+        drive_tx: &mut DriveTransaction,
+        min_fee: u64,
+    ) -> KoResult<()> {
+        let tx_size = drive_tx.unsigned_tx.data().serialized_size_in_block();
+        let mut fee = KoFeeConfig::calc_fee(drive_tx.fee_rate, tx_size).max(min_fee);
+        loop {
+            let filled_tx = self
+                .assembler
+                .fill_transaction_with_outputs(
+                    drive_tx.unsigned_tx.clone(),
+                    &drive_tx.cell_outputs,
+                    drive_tx.inputs_capacity,
+                    fee,
+                )
+                .await?;
+            let signature = self.driver.sign_transaction(&filled_tx);
+            let signed_tx = self
+                .assembler
+                .complete_transaction_with_signature(filled_tx, signature);
+            let tx_size = signed_tx.data().serialized_size_in_block();
+            let required_fee = KoFeeConfig::calc_fee(drive_tx.fee_rate, tx_size);
+            if required_fee <= fee {
+                drive_tx.signed_tx = signed_tx;
+                drive_tx.fee = fee;
+                return Ok(());
+            }
+            fee = required_fee;
+        }
+    }
+
+    // wait drive transaction committed, it's rebroadcasted or replaced by a higher fee one
+    // when pending too long or rejected for fee, and every submission is recorded
+    async fn wait_drive_committed(
+        &mut self,
+        drive: &mut KoPendingDrive,
+        mut drive_tx: DriveTransaction,
+    ) -> KoResult<()> {
+        let project_type_args = self.assembler.get_project_args().clone();
+        let pending_timeout = Duration::from_secs(self.config.pending_timeout_sec);
+        loop {
+            let status = self
+                .driver
+                .wait_transaction_committed(
+                    &drive.transaction_hash,
+                    &self.drive_interval,
+                    self.config.block_confirms_count,
+                    &pending_timeout,
+                )
+                .await?;
+            let last_action = drive.attempts.last().map(|attempt| attempt.action);
+            let (action, rejection) = match status {
+                KoTransactionStatus::Committed => return Ok(()),
+                KoTransactionStatus::Pending => match last_action {
+                    Some(KoDriveAction::Rebroadcast) => (KoDriveAction::BumpFee, None),
+                    _ => (KoDriveAction::Rebroadcast, None),
+                },
+                KoTransactionStatus::Rejected(rejection) => match rejection {
+                    KoRejection::Fee(_) => (KoDriveAction::BumpFee, Some(rejection)),
+                    KoRejection::Other(_) => (KoDriveAction::Rebroadcast, Some(rejection)),
+                    KoRejection::Conflict(_) | KoRejection::Script(_) => {
+                        // the replaced transaction may have been committed before this one
+                        if self.find_committed_attempt(drive).await? {
+                            return Ok(());
                         }
-                    } {
-                        log::error!(
-                            "[{}] request callback error: {}",
-                            self.assembler.get_project_args(),
-                            err
-                        );
+                        self.storage.discard_pending_drive(&project_type_args)?;
+                        return Err(DriverError::TransactionRejected(
+                            rejection,
+                            drive.transaction_hash.clone(),
+                        )
+                        .into());
+                    }
+                },
+            };
+
+            // give up resubmitting, but keep pending drive to be resumed after restart
+            let resubmissions = drive.attempts.len() - 1;
+            if resubmissions >= self.config.max_resubmit_attempts as usize {
+                return Err(DriverError::ResubmissionExhausted(
+                    resubmissions,
+                    drive.transaction_hash.clone(),
+                )
+                .into());
+            }
+            log::warn!(
+                "[{}] drive transaction #{} not committed, action = {:?}, rejection = {:?}",
+                project_type_args,
+                drive.transaction_hash,
+                action,
+                rejection
+            );
+            match action {
+                KoDriveAction::BumpFee => {
+                    let min_fee = drive_tx.fee * (100 + self.config.fee_bump_percent) / 100;
+                    self.complete_transaction(&mut drive_tx, min_fee).await?;
+                    match self
+                        .driver
+                        .send_transaction(drive_tx.signed_tx.clone())
+                        .await
+                    {
+                        Ok(hash) => {
+                            drive.transaction_hash = hash;
+                            self.project_context.global_cell =
+                                next_global_cell(&drive_tx.signed_tx);
+                        }
+                        // keep waiting the previous one, and the next bump goes higher
+                        Err(err) => log::warn!("[{}] {}", project_type_args, err),
                     }
                 }
-            });
+                _ => {
+                    if let Err(err) = self
+                        .driver
+                        .send_transaction(drive_tx.signed_tx.clone())
+                        .await
+                    {
+                        log::warn!("[{}] {}", project_type_args, err);
+                    }
+                }
+            }
+            drive.attempts.push(KoDriveAttempt::new(
+                drive.transaction_hash.clone(),
+                drive_tx.fee,
+                action,
+                rejection,
+            ));
+            self.storage.save_pending_drive(
+                &project_type_args,
+                drive,
+                &self.project_context.global_cell,
+            )?;
+        }
+    }
 
-        Ok(Some(hash))
+    async fn find_committed_attempt(&mut self, drive: &mut KoPendingDrive) -> KoResult<bool> {
+        let interval = Duration::from_secs(self.config.drive_interval_sec as u64);
+        let mut hashes = drive
+            .attempts
+            .iter()
+            .map(|attempt| attempt.transaction_hash.clone())
+            .filter(|hash| hash != &drive.transaction_hash)
+            .collect::<Vec<_>>();
+        hashes.dedup();
+        for hash in hashes {
+            let status = self
+                .driver
+                .wait_transaction_committed(
+                    &hash,
+                    &interval,
+                    self.config.block_confirms_count,
+                    &Duration::ZERO,
+                )
+                .await?;
+            if status == KoTransactionStatus::Committed {
+                drive.transaction_hash = hash;
+                self.project_context.global_cell = self.assembler.get_project_global_cell().await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
-    pub fn estimate_payment_ckb(
+    pub async fn estimate_payment_ckb(
         &self,
         method_call: &str,
         inputs: &[(Script, Bytes)],
@@ -256,20 +534,128 @@ impl<C: CkbClient> ContextImpl<C> {
             0,
             0,
         );
-        self.executor.estimate_payment_ckb(
-            &self.project_context.global_cell,
-            &self.project_context.project_owner,
-            request,
-            &self.project_context.contract_code,
-        )
+        self.executor
+            .estimate_payment_ckb(
+                &self.project_context.global_cell,
+                &self.project_context.project,
+                request,
+                &self.project_context.block_context,
+            )
+            .await
+    }
+
+    pub async fn simulate_request(
+        &self,
+        method_call: &str,
+        inputs: &[(Script, Bytes)],
+        candidates: &[Script],
+        components: &[Bytes],
+    ) -> KoResult<KoRequestSimulation> {
+        let request = KoRequest::new(
+            Bytes::from(method_call.as_bytes().to_vec()),
+            inputs.to_owned(),
+            candidates.to_owned(),
+            components.to_owned(),
+            0,
+            0,
+        );
+        self.executor
+            .simulate_request(
+                &self.project_context.global_cell,
+                &self.project_context.project,
+                request,
+                &self.project_context.block_context,
+            )
+            .await
+    }
+
+    pub async fn call_view(
+        &self,
+        method_call: &str,
+        inputs: &[(Script, Bytes)],
+    ) -> KoResult<String> {
+        self.executor
+            .call_view(
+                &self.project_context.global_cell,
+                &self.project_context.project,
+                method_call,
+                inputs,
+                &self.project_context.block_context,
+            )
+            .await
+    }
+
+    /// Run `migrate` of upgraded project against current global data without committing,
+    /// returns the migrated global data and the contract ABI of upgraded project
+    pub async fn preview_upgrade(&self, project: &KoProject) -> KoResult<(String, KoContractAbi)> {
+        let mut global_cell = self.project_context.global_cell.clone();
+        self.executor
+            .migrate_global(
+                &mut global_cell,
+                project,
+                &self.project_context.block_context,
+            )
+            .await?;
+        let abi = self.executor.get_contract_abi(project).await?;
+        let global_data = String::from_utf8_lossy(&global_cell.output_data).into_owned();
+        Ok((global_data, abi))
+    }
+
+    pub async fn get_contract_abi(&self) -> KoResult<KoContractAbi> {
+        self.executor
+            .get_contract_abi(&self.project_context.project)
+            .await
     }
 
     pub fn listen_request_committed(
         &mut self,
         request_hash: &H256,
-        sender: UnboundedSender<KoResult<H256>>,
+        sender: UnboundedSender<KoResult<KoRequestReceipt>>,
     ) {
-        self.listening_requests.insert(request_hash.clone(), sender);
+        let outcome = match self.storage.load_request_outcome(request_hash) {
+            Ok(Some(outcome)) => outcome.map_err(|err| StorageError::FailedRequest(err).into()),
+            Ok(None) => {
+                self.listening_requests.insert(request_hash.clone(), sender);
+                return;
+            }
+            Err(err) => Err(err),
+        };
+        if sender.send(outcome).is_err() {
+            log::error!(
+                "[{}] request callback closed",
+                self.assembler.get_project_args()
+            );
+        }
+    }
+
+    pub fn fetch_request_receipt(&self, request_hash: &H256) -> Option<KoRequestReceipt> {
+        match self.storage.load_request_outcome(request_hash) {
+            Ok(outcome) => outcome.and_then(Result::ok),
+            Err(err) => {
+                log::error!("[{}] {}", self.assembler.get_project_args(), err);
+                None
+            }
+        }
+    }
+
+    // persist outcomes of confirmed drive transaction before answering its listeners
+    fn complete_drive(&mut self, drive: KoPendingDrive) -> KoResult<()> {
+        let outcomes = drive.into_outcomes();
+        self.storage
+            .complete_pending_drive(self.assembler.get_project_args(), &outcomes)?;
+        for (request_hash, outcome) in outcomes {
+            if let Some(callback) = self.listening_requests.remove(&request_hash) {
+                let result = outcome.map_err(|err| StorageError::FailedRequest(err).into());
+                if let Err(err) = callback.send(result) {
+                    log::error!(
+                        "[{}] request callback error: {}",
+                        self.assembler.get_project_args(),
+                        err
+                    );
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn run(mut self) {
@@ -288,11 +674,82 @@ lazy_static! {
     static ref CONTEXT_POOL: Mutex<HashMap<H256, Context>> = Mutex::new(HashMap::new());
 }
 
+/// Output cells of drive transaction, and events or error of each request
+pub type ExecutedOutputs = (Vec<KoCellOutput>, Vec<KoResult<Vec<KoEvent>>>);
+
+/// Run requests of an assembled receipt and make output cells of the drive transaction, the
+/// cells of failed requests are recovered as they were before the request
+pub async fn execute_assemble_receipt(
+    executor: &impl Executor,
+    project: &KoProject,
+    receipt: &mut KoAssembleReceipt,
+) -> KoResult<ExecutedOutputs> {
+    if receipt.migrate_global {
+        executor
+            .migrate_global(&mut receipt.global_cell, project, &receipt.block_context)
+            .await?;
+    }
+    let random_seeds = receipt
+        .random_seeds
+        .iter()
+        .map(|random_seed| random_seed.seeds)
+        .collect::<Vec<_>>();
+    let personal_outputs = executor
+        .execute_lua_requests(
+            &mut receipt.global_cell,
+            project,
+            &receipt.requests,
+            &random_seeds,
+            &receipt.block_context,
+        )
+        .await?;
+    let mut cell_outputs = vec![KoCellOutput::new(
+        vec![(
+            receipt.global_cell.lock_script.clone(),
+            Some(receipt.global_cell.output_data.clone()),
+        )],
+        receipt.global_cell.capacity,
+        vec![],
+    )];
+
+    // trim unworkable requests from transaction inputs
+    let request_results = personal_outputs
+        .into_iter()
+        .zip(receipt.requests.iter())
+        .map(|(output, request)| match output {
+            Ok(mut output_assemble) => {
+                let events = std::mem::take(&mut output_assemble.events);
+                cell_outputs.push(output_assemble);
+                Ok(events)
+            }
+            Err(err) => {
+                // recover the previous cell before its request operation
+                let cells = request
+                    .inputs
+                    .iter()
+                    .map(|(script, data)| {
+                        if data.is_empty() {
+                            (script.clone(), None)
+                        } else {
+                            (script.clone(), Some(data.clone()))
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                cell_outputs.push(KoCellOutput::new(cells, request.capacity, vec![]));
+                Err(err)
+            }
+        })
+        .collect();
+    Ok((cell_outputs, request_results))
+}
+
 pub struct ContextMgr<C: CkbClient> {
     rpc_client: C,
     private_key: SecretKey,
     project_deps: ProjectDeps,
     driver_config: KoDriveConfig,
+    executor_pool: ExecutorPool,
+    storage: StorageImpl,
 }
 
 impl<C: CkbClient + 'static> ContextMgr<C> {
@@ -301,12 +758,15 @@ impl<C: CkbClient + 'static> ContextMgr<C> {
         private_key: &SecretKey,
         project_deps: &ProjectDeps,
         driver_config: &KoDriveConfig,
+        storage: &StorageImpl,
     ) -> Self {
         ContextMgr {
             rpc_client: rpc_client.clone(),
             private_key: *private_key,
             project_deps: project_deps.clone(),
             driver_config: driver_config.clone(),
+            executor_pool: ExecutorPool::new(driver_config.executor_pool_size as usize),
+            storage: storage.clone(),
         }
     }
 
@@ -324,6 +784,61 @@ impl<C: CkbClient + 'static> ContextMgr<C> {
         }
     }
 
+    /// Reconcile local projects status with the projects whose global cells are driven by
+    /// project manager on chain, discovered ones missing locally are saved as running
+    pub async fn reconcile_projects(
+        &self,
+        mut projects_status: Vec<(H256, bool)>,
+    ) -> KoResult<Vec<(H256, bool)>> {
+        let discovered_projects = discover_managed_projects(
+            &self.rpc_client,
+            &self.project_deps.project_code_hash,
+            &self.project_deps.project_manager.payload().into(),
+        )
+        .await?;
+        for (project_type_args, _) in &projects_status {
+            if !discovered_projects.contains(project_type_args) {
+                log::warn!(
+                    "[{}] no managed global cell found on chain",
+                    project_type_args
+                );
+            }
+        }
+        // projects which were stopped locally keep stopped
+        let stored_status = self.storage.load_projects_status()?;
+        for project_type_args in discovered_projects {
+            if projects_status
+                .iter()
+                .any(|(hash, _)| hash == &project_type_args)
+            {
+                continue;
+            }
+            if let Some(status) = stored_status
+                .iter()
+                .find(|(hash, _)| hash == &project_type_args)
+            {
+                projects_status.push(status.clone());
+                continue;
+            }
+            log::info!(
+                "[{}] discovered managed project on chain",
+                project_type_args
+            );
+            self.storage.save_project_status(&project_type_args, true)?;
+            projects_status.push((project_type_args, true));
+        }
+        Ok(projects_status)
+    }
+
+    /// Executor on the pool shared by contexts, for running contract code out of any project
+    pub fn new_executor(&self) -> ExecutorImpl {
+        ExecutorImpl::new(
+            self.driver_config.max_instructions_per_request,
+            self.driver_config.max_lua_memory_bytes,
+            &self.executor_pool,
+        )
+    }
+
     pub async fn dump_contexts_status() -> Vec<(H256, bool)> {
         CONTEXT_POOL
             .lock()
@@ -345,6 +860,8 @@ impl<C: CkbClient + 'static> ContextMgr<C> {
             project_type_args,
             &self.project_deps,
             &self.driver_config,
+            &self.executor_pool,
+            &self.storage,
         );
         *context = tokio::spawn(ctx.run());
         *rpc_sender = rpc;
@@ -365,11 +882,17 @@ impl<C: CkbClient + 'static> ContextRpc for ContextMgr<C> {
             project_type_args,
             &self.project_deps,
             &self.driver_config,
+            &self.executor_pool,
+            &self.storage,
         );
         CONTEXT_POOL
             .lock()
             .await
             .insert(project_type_args.clone(), (tokio::spawn(ctx.run()), rpc));
+        // managed project must be recovered even if driver crashes before next persistence
+        if let Err(err) = self.storage.save_project_status(project_type_args, true) {
+            log::error!("[{}] {}", project_type_args, err);
+        }
         true
     }
 
@@ -404,11 +927,64 @@ impl<C: CkbClient + 'static> ContextRpc for ContextMgr<C> {
         false
     }
 
+    async fn simulate_request(
+        &mut self,
+        project_type_args: &H256,
+        method_call: &str,
+        inputs: &[(Script, String)],
+        candidates: &[Script],
+        components: &[String],
+        response: UnboundedSender<KoResult<KoRequestSimulation>>,
+    ) -> bool {
+        if let Some((ctx, rpc_sender)) = CONTEXT_POOL.lock().await.get_mut(project_type_args) {
+            if ctx.is_finished() {
+                self.awake_sleeping_context(project_type_args, ctx, rpc_sender);
+            }
+            let inputs = inputs
+                .iter()
+                .map(|(s, d)| (s.clone(), Bytes::from(d.as_bytes().to_vec())))
+                .collect();
+            let components = components
+                .iter()
+                .map(|s| Bytes::from(s.as_bytes().to_vec()))
+                .collect();
+            let params = KoContextRpcEcho::SimulateRequest((
+                (inputs, method_call.into(), candidates.into(), components),
+                response,
+            ));
+            rpc_sender.send(params).unwrap();
+            return true;
+        }
+        false
+    }
+
+    async fn call_view(
+        &mut self,
+        project_type_args: &H256,
+        method_call: &str,
+        inputs: &[(Script, String)],
+        response: UnboundedSender<KoResult<String>>,
+    ) -> bool {
+        if let Some((ctx, rpc_sender)) = CONTEXT_POOL.lock().await.get_mut(project_type_args) {
+            if ctx.is_finished() {
+                self.awake_sleeping_context(project_type_args, ctx, rpc_sender);
+            }
+            let inputs = inputs
+                .iter()
+                .map(|(s, d)| (s.clone(), Bytes::from(d.as_bytes().to_vec())))
+                .collect();
+            let params = KoContextRpcEcho::CallView(((inputs, method_call.into()), response));
+            rpc_sender.send(params).unwrap();
+            return true;
+        }
+        false
+    }
+
     async fn listen_request_committed(
         &mut self,
         project_type_args: &H256,
         request_hash: &H256,
-        response: UnboundedSender<KoResult<H256>>,
+        response: UnboundedSender<KoResult<KoRequestReceipt>>,
     ) -> bool {
         if let Some((ctx, rpc_sender)) = CONTEXT_POOL.lock().await.get_mut(project_type_args) {
             if ctx.is_finished() {
@@ -420,4 +996,55 @@ impl<C: CkbClient + 'static> ContextRpc for ContextMgr<C> {
         }
         false
     }
+
+    async fn fetch_request_receipt(
+        &mut self,
+        project_type_args: &H256,
+        request_hash: &H256,
+        response: UnboundedSender<Option<KoRequestReceipt>>,
+    ) -> bool {
+        if let Some((ctx, rpc_sender)) = CONTEXT_POOL.lock().await.get_mut(project_type_args) {
+            if ctx.is_finished() {
+                self.awake_sleeping_context(project_type_args, ctx, rpc_sender);
+            }
+            let params = KoContextRpcEcho::FetchRequestReceipt((request_hash.clone(), response));
+            rpc_sender.send(params).unwrap();
+            return true;
+        }
+        false
+    }
+
+    async fn preview_upgrade(
+        &mut self,
+        project_type_args: &H256,
+        project: &KoProject,
+        response: UnboundedSender<KoResult<(String, KoContractAbi)>>,
+    ) -> bool {
+        if let Some((ctx, rpc_sender)) = CONTEXT_POOL.lock().await.get_mut(project_type_args) {
+            if ctx.is_finished() {
+                self.awake_sleeping_context(project_type_args, ctx, rpc_sender);
+            }
+            let params = KoContextRpcEcho::PreviewUpgrade((project.clone(), response));
+            rpc_sender.send(params).unwrap();
+            return true;
+        }
+        false
+    }
+
+    async fn get_contract_abi(
+        &mut self,
+        project_type_args: &H256,
+        response: UnboundedSender<KoResult<KoContractAbi>>,
+    ) -> bool {
+        if let Some((ctx, rpc_sender)) = CONTEXT_POOL.lock().await.get_mut(project_type_args) {
+            if ctx.is_finished() {
+                self.awake_sleeping_context(project_type_args, ctx, rpc_sender);
+            }
+            rpc_sender
+                .send(KoContextRpcEcho::GetContractAbi(response))
+                .unwrap();
+            return true;
+        }
+        false
+    }
 }
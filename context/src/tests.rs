@@ -1,14 +1,204 @@
-use ko_protocol::traits::Assembler;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use ko_context_assembler::make_transaction_outputs;
+use ko_context_driver::DriverError;
+use ko_context_executor::ExecutorPool;
+use ko_protocol::ckb_jsonrpc_types::{
+    BlockNumber, BlockView, CellWithStatus, HeaderView, JsonBytes, OutPoint, OutputsValidator,
+    Transaction, TransactionWithStatus, TxStatus, Uint32,
+};
+use ko_protocol::ckb_sdk::rpc::ckb_indexer::{Cell, Pagination, SearchKey};
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::core::{self, BlockBuilder, TransactionView};
+use ko_protocol::ckb_types::packed::{self, CellInput, CellOutput, Script};
+use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack, Unpack};
+use ko_protocol::traits::{Assembler, CkbClient, Storage, RPC};
+use ko_protocol::types::assembler::KoCellOutput;
+use ko_protocol::types::config::KoDriveConfig;
+use ko_protocol::types::context::{
+    KoDriveAction, KoDriveAttempt, KoFeeRateStatistics, KoPendingDrive, KoRejection,
+};
 use ko_protocol::H256;
-use ko_protocol::{hex, secp256k1::SecretKey, tokio, TestVars::*};
+use ko_protocol::{async_trait, hex, secp256k1::SecretKey, tokio, TestVars::*};
 use ko_rpc_client::RpcClient;
+use ko_storage::StorageImpl;
+
+use crate::{
+    compare_transaction_outputs, verify_drive_transaction, ContextImpl, ContextMgr,
+    DriveTransaction,
+};
+
+// answers polls of drive transaction in order, and records polled and broadcasted hashes,
+// every indexer search finds the same live cells
+#[derive(Clone, Default)]
+struct MockChain {
+    statuses: Arc<Mutex<VecDeque<TxStatus>>>,
+    polled: Arc<Mutex<Vec<H256>>>,
+    sent: Arc<Mutex<Vec<H256>>>,
+    live_cells: Arc<Mutex<Vec<Cell>>>,
+}
+
+impl MockChain {
+    fn new(statuses: Vec<TxStatus>) -> Self {
+        MockChain {
+            statuses: Arc::new(Mutex::new(statuses.into())),
+            ..Default::default()
+        }
+    }
+
+    fn add_live_cell(&self, output: CellOutput, data: Bytes, tx_hash: &H256) {
+        self.live_cells.lock().unwrap().push(Cell {
+            output: output.into(),
+            output_data: JsonBytes::from_bytes(data),
+            out_point: packed::OutPoint::new(tx_hash.pack(), 0).into(),
+            block_number: 1.into(),
+            tx_index: Uint32::from(0),
+        });
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.statuses.lock().unwrap().is_empty()
+    }
+
+    fn polled(&self) -> Vec<H256> {
+        self.polled.lock().unwrap().clone()
+    }
+
+    fn sent(&self) -> Vec<H256> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+fn committed_block() -> core::BlockView {
+    BlockBuilder::default().number(1u64.pack()).build()
+}
+
+#[async_trait]
+impl CkbClient for MockChain {
+    fn get_block_by_number(&self, _number: BlockNumber) -> RPC<BlockView> {
+        unimplemented!()
+    }
+
+    fn get_block(&self, _hash: &H256) -> RPC<BlockView> {
+        Box::pin(async move { Ok(committed_block().into()) })
+    }
+
+    fn get_tip_header(&self) -> RPC<HeaderView> {
+        Box::pin(async move { Ok(committed_block().header().into()) })
+    }
+
+    fn get_header_by_number(&self, _number: BlockNumber) -> RPC<HeaderView> {
+        Box::pin(async move { Ok(committed_block().header().into()) })
+    }
+
+    fn get_transaction(&self, hash: &H256) -> RPC<Option<TransactionWithStatus>> {
+        self.polled.lock().unwrap().push(hash.clone());
+        let status = self.statuses.lock().unwrap().pop_front();
+        let mut tx = TransactionWithStatus::with_pending(None);
+        tx.tx_status = status.expect("chain statuses exhausted");
+        Box::pin(async move { Ok(Some(tx)) })
+    }
+
+    fn get_live_cell(&self, _out_point: &OutPoint, _with_data: bool) -> RPC<CellWithStatus> {
+        unimplemented!()
+    }
+
+    fn send_transaction(
+        &self,
+        tx: &Transaction,
+        _outputs_validator: Option<OutputsValidator>,
+    ) -> RPC<H256> {
+        let tx: packed::Transaction = tx.clone().into();
+        let hash: H256 = tx.calc_tx_hash().unpack();
+        self.sent.lock().unwrap().push(hash.clone());
+        Box::pin(async move { Ok(hash) })
+    }
+
+    fn get_txs_by_hashes(&self, _hash: Vec<H256>) -> RPC<Vec<Option<TransactionWithStatus>>> {
+        unimplemented!()
+    }
+
+    fn get_fee_rate_statistics(&self) -> RPC<Option<KoFeeRateStatistics>> {
+        unimplemented!()
+    }
+
+    fn fetch_live_cells(
+        &self,
+        _search_key: SearchKey,
+        _limit: u32,
+        _cursor: Option<JsonBytes>,
+    ) -> RPC<Pagination<Cell>> {
+        let objects = self.live_cells.lock().unwrap().clone();
+        Box::pin(async move {
+            Ok(Pagination {
+                objects,
+                last_cursor: JsonBytes::default(),
+            })
+        })
+    }
+}
 
-use crate::ContextImpl;
+// context whose drive transaction has just been submitted, with its pending drive stored
+async fn submitted_context(
+    rpc_client: &MockChain,
+    config: &KoDriveConfig,
+) -> (ContextImpl<MockChain>, KoPendingDrive, DriveTransaction) {
+    let privkey = SecretKey::from_slice(&[1u8; 32]).unwrap();
+    let (ctx, _) = ContextImpl::new(
+        rpc_client,
+        &privkey,
+        &[7u8; 32].into(),
+        &PROJECT_VARS,
+        config,
+        &ExecutorPool::new(1),
+        &StorageImpl::temporary().expect("storage"),
+    );
+    let tx = TransactionView::new_advanced_builder()
+        .input(CellInput::new_builder().build())
+        .build();
+    let global_output = KoCellOutput::new(
+        vec![(Script::default(), Some(Bytes::from(r#"{"count":1}"#)))],
+        0,
+        vec![],
+    );
+    let mut drive_tx = DriveTransaction {
+        unsigned_tx: tx.clone(),
+        cell_outputs: vec![global_output],
+        inputs_capacity: 1_000_000_000_000,
+        fee_rate: 1000,
+        signed_tx: tx,
+        fee: 0,
+    };
+    ctx.complete_transaction(&mut drive_tx, 0).await.unwrap();
+    let hash: H256 = drive_tx.signed_tx.hash().unpack();
+    let attempt = KoDriveAttempt::new(hash.clone(), drive_tx.fee, KoDriveAction::Submit, None);
+    let drive = KoPendingDrive::new(hash, vec![], Default::default(), vec![], vec![attempt]);
+    ctx.storage
+        .save_pending_drive(
+            ctx.assembler.get_project_args(),
+            &drive,
+            &Default::default(),
+        )
+        .unwrap();
+    (ctx, drive, drive_tx)
+}
+
+fn resubmit_config(max_resubmit_attempts: u8) -> KoDriveConfig {
+    KoDriveConfig {
+        drive_interval_sec: 0,
+        block_confirms_count: 0,
+        pending_timeout_sec: 0,
+        max_resubmit_attempts,
+        fee_bump_percent: 200,
+        ..DRIVE_CONFIG.clone()
+    }
+}
 
 #[tokio::test]
 async fn drive_one() {
     // prepare parts
-    let rpc_client = RpcClient::new(&CKB_URL, &CKB_INDEXER_URL);
+    let rpc_client = RpcClient::new(CKB_URL, CKB_INDEXER_URL);
     let privkey = SecretKey::from_slice(OWNER_PRIVATE_KEY.as_bytes()).expect("private key");
     let (mut ctx, _) = ContextImpl::new(
         &rpc_client,
@@ -16,6 +206,8 @@ async fn drive_one() {
         &PROJECT_TYPE_ARGS.into(),
         &PROJECT_VARS,
         &DRIVE_CONFIG,
+        &ExecutorPool::new(1),
+        &StorageImpl::temporary().expect("storage"),
     );
 
     // prepare to make instance of context
@@ -27,5 +219,324 @@ async fn drive_one() {
 
     // drive knside-out transaction
     let hash = ctx.drive(&project_dep).await.expect("drive");
-    println!("hash = {}", hex::encode(&hash.unwrap_or(H256::default())));
+    println!("hash = {}", hex::encode(hash.unwrap_or(H256::default())));
+}
+
+#[test]
+fn compare_drive_outputs() {
+    let script = |byte: u8| {
+        Script::new_builder()
+            .args(Bytes::from(vec![byte; 20]).pack())
+            .build()
+    };
+    let cell_outputs = vec![
+        KoCellOutput::new(
+            vec![(script(0), Some(Bytes::from(r#"{"count":1}"#)))],
+            1000,
+            vec![],
+        ),
+        KoCellOutput::new(vec![(script(1), None)], 0, vec![]),
+    ];
+    let code_hash: H256 = [2u8; 32].into();
+    let type_args: H256 = [3u8; 32].into();
+    let occupied = make_transaction_outputs(&cell_outputs, &code_hash, &type_args, 0, 0)
+        .0
+        .iter()
+        .map(|output| -> u64 { output.capacity().unpack() })
+        .sum::<u64>();
+    // inputs leave 500 shannons after outputs, which global cell takes apart from fee
+    let (outputs, outputs_data) =
+        make_transaction_outputs(&cell_outputs, &code_hash, &type_args, occupied + 500, 100);
+    let global_capacity: u64 = outputs[0].capacity().unpack();
+    let make_tx = |outputs: Vec<CellOutput>, outputs_data: Vec<Bytes>| {
+        TransactionView::new_advanced_builder()
+            .outputs(outputs)
+            .outputs_data(outputs_data.pack())
+            .build()
+    };
+
+    // a change cell of driver is allowed
+    let mut committed = outputs.clone();
+    committed.push(CellOutput::new_builder().lock(script(0)).build());
+    let mut committed_data = outputs_data.clone();
+    committed_data.push(Bytes::new());
+    let tx = make_tx(committed.clone(), committed_data.clone());
+    assert!(compare_transaction_outputs(&outputs, &outputs_data, &tx).is_empty());
+
+    // tampered global data and capacity, and foreign change cell are reported
+    committed_data[0] = Bytes::from(r#"{"count":2}"#);
+    committed[0] = committed[0]
+        .clone()
+        .as_builder()
+        .capacity((global_capacity - 300).pack())
+        .build();
+    committed[2] = CellOutput::new_builder().lock(script(9)).build();
+    let tx = make_tx(committed, committed_data);
+    let mismatches = compare_transaction_outputs(&outputs, &outputs_data, &tx);
+    let fields = mismatches
+        .iter()
+        .map(|mismatch| (mismatch.index, mismatch.field.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(fields, vec![(0, "data"), (0, "capacity"), (2, "change")]);
+}
+
+#[tokio::test]
+async fn verify_one() {
+    // drive one knside-out transaction and replay it
+    let rpc_client = RpcClient::new(CKB_URL, CKB_INDEXER_URL);
+    let privkey = SecretKey::from_slice(OWNER_PRIVATE_KEY.as_bytes()).expect("private key");
+    let (mut ctx, _) = ContextImpl::new(
+        &rpc_client,
+        &privkey,
+        &PROJECT_TYPE_ARGS.into(),
+        &PROJECT_VARS,
+        &DRIVE_CONFIG,
+        &ExecutorPool::new(1),
+        &StorageImpl::temporary().expect("storage"),
+    );
+    let project_dep = ctx
+        .assembler
+        .prepare_transaction_project_celldep()
+        .await
+        .expect("project dep");
+    if let Some(hash) = ctx.drive(&project_dep).await.expect("drive") {
+        let verification =
+            verify_drive_transaction(&rpc_client, &PROJECT_VARS, &DRIVE_CONFIG, &hash)
+                .await
+                .expect("verify");
+        assert!(verification.is_honest(), "{:?}", verification.mismatches);
+    }
+}
+
+#[tokio::test]
+async fn discover_projects() {
+    // managed projects on chain should be recovered into empty local storage
+    let rpc_client = RpcClient::new(CKB_URL, CKB_INDEXER_URL);
+    let privkey = SecretKey::from_slice(OWNER_PRIVATE_KEY.as_bytes()).expect("private key");
+    let storage = StorageImpl::temporary().expect("storage");
+    let context_mgr = ContextMgr::new(
+        &rpc_client,
+        &privkey,
+        &PROJECT_VARS,
+        &DRIVE_CONFIG,
+        &storage,
+    );
+    let projects_status = context_mgr
+        .reconcile_projects(vec![])
+        .await
+        .expect("reconcile");
+    let project_type_args: H256 = PROJECT_TYPE_ARGS.into();
+    assert!(projects_status.contains(&(project_type_args, true)));
+    assert_eq!(
+        storage.load_projects_status().expect("load"),
+        projects_status
+    );
+}
+
+#[test]
+fn rejections_are_classified() {
+    let classify = |reason: &str| match KoRejection::classify(reason) {
+        KoRejection::Conflict(_) => "conflict",
+        KoRejection::Fee(_) => "fee",
+        KoRejection::Script(_) => "script",
+        KoRejection::Other(_) => "other",
+    };
+    let reasons = [
+        r#"{"type":"Resolve","description":"Resolve failed Dead(OutPoint(0x01))"}"#,
+        r#"{"type":"Invalidated","description":"RBF replaced by 0x02"}"#,
+        r#"{"type":"LowFeeRate","description":"The min fee rate is 1000 shannons/KW"}"#,
+        r#"{"type":"RBFRejected","description":"Tx's current fee is 1000, expect it to >= 2500"}"#,
+        r#"{"type":"Verification","description":"Verification failed Script(ValidationFailure)"}"#,
+        r#"{"type":"Expiry","description":"Transaction expired"}"#,
+    ];
+    let classes = reasons.map(classify);
+    assert_eq!(
+        classes,
+        ["conflict", "conflict", "fee", "fee", "script", "other"]
+    );
+}
+
+#[tokio::test]
+async fn stuck_drive_is_rebroadcasted_then_fee_bumped() {
+    let low_fee_rate = r#"{"type":"LowFeeRate","description":"The min fee rate is 1000"}"#;
+    let rpc_client = MockChain::new(vec![
+        TxStatus::pending(),
+        TxStatus::pending(),
+        TxStatus::rejected(low_fee_rate.into()),
+        TxStatus::committed(committed_block().hash().unpack()),
+    ]);
+    let (mut ctx, mut drive, drive_tx) = submitted_context(&rpc_client, &resubmit_config(3)).await;
+    let submitted = drive.transaction_hash.clone();
+    ctx.wait_drive_committed(&mut drive, drive_tx)
+        .await
+        .expect("committed");
+    assert!(rpc_client.is_exhausted());
+
+    // pending at first is rebroadcasted, and pending again or fee rejection bumps fee
+    let actions = drive
+        .attempts
+        .iter()
+        .map(|attempt| attempt.action)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        actions,
+        vec![
+            KoDriveAction::Submit,
+            KoDriveAction::Rebroadcast,
+            KoDriveAction::BumpFee,
+            KoDriveAction::BumpFee
+        ]
+    );
+    let rejections = drive
+        .attempts
+        .iter()
+        .map(|attempt| attempt.rejection.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rejections,
+        vec![
+            None,
+            None,
+            None,
+            Some(KoRejection::Fee(low_fee_rate.into()))
+        ]
+    );
+    let fees = drive
+        .attempts
+        .iter()
+        .map(|attempt| attempt.fee)
+        .collect::<Vec<_>>();
+    assert_eq!(fees[0], fees[1]);
+    assert!(fees[2] >= fees[1] * 3 && fees[3] >= fees[2] * 3);
+
+    // each bump replaces the transaction being waited
+    let hashes = drive
+        .attempts
+        .iter()
+        .map(|attempt| attempt.transaction_hash.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(hashes[0], submitted);
+    assert_eq!(hashes[1], submitted);
+    assert_ne!(hashes[2], hashes[1]);
+    assert_ne!(hashes[3], hashes[2]);
+    assert_eq!(drive.transaction_hash, hashes[3]);
+    assert_eq!(
+        rpc_client.polled(),
+        vec![
+            submitted.clone(),
+            submitted,
+            hashes[2].clone(),
+            hashes[3].clone()
+        ]
+    );
+    assert_eq!(rpc_client.sent(), hashes[1..]);
+
+    // every attempt is persisted along with the pending drive
+    let stored = ctx
+        .storage
+        .load_pending_drive(ctx.assembler.get_project_args())
+        .unwrap()
+        .expect("pending drive");
+    assert_eq!(stored.transaction_hash, drive.transaction_hash);
+    assert_eq!(stored.attempts.len(), 4);
+}
+
+#[tokio::test]
+async fn stuck_drive_gives_up_after_max_resubmissions() {
+    let rpc_client = MockChain::new(vec![TxStatus::pending(), TxStatus::pending()]);
+    let (mut ctx, mut drive, drive_tx) = submitted_context(&rpc_client, &resubmit_config(1)).await;
+    let error = ctx
+        .wait_drive_committed(&mut drive, drive_tx)
+        .await
+        .expect_err("exhausted");
+    assert!(matches!(
+        error.downcast_ref::<DriverError>(),
+        Some(DriverError::ResubmissionExhausted(1, _))
+    ));
+    assert!(rpc_client.is_exhausted());
+    assert_eq!(rpc_client.sent().len(), 1);
+
+    // pending drive is kept to be resumed after restart
+    let stored = ctx
+        .storage
+        .load_pending_drive(ctx.assembler.get_project_args())
+        .unwrap();
+    assert_eq!(stored.expect("pending drive").attempts.len(), 2);
+}
+
+#[tokio::test]
+async fn conflicted_drive_is_discarded_without_committed_attempt() {
+    let dead_input = r#"{"type":"Resolve","description":"Resolve failed Dead(OutPoint(0x01))"}"#;
+    let rpc_client = MockChain::new(vec![
+        TxStatus::pending(),
+        TxStatus::pending(),
+        TxStatus::rejected(dead_input.into()),
+        TxStatus::pending(),
+    ]);
+    let (mut ctx, mut drive, drive_tx) = submitted_context(&rpc_client, &resubmit_config(3)).await;
+    let submitted = drive.transaction_hash.clone();
+    let error = ctx
+        .wait_drive_committed(&mut drive, drive_tx)
+        .await
+        .expect_err("rejected");
+    assert!(matches!(
+        error.downcast_ref::<DriverError>(),
+        Some(DriverError::TransactionRejected(
+            KoRejection::Conflict(_),
+            _
+        ))
+    ));
+    assert!(rpc_client.is_exhausted());
+
+    // the replaced submission is polled once more before giving up the drive
+    assert_eq!(rpc_client.polled().last(), Some(&submitted));
+    let stored = ctx
+        .storage
+        .load_pending_drive(ctx.assembler.get_project_args())
+        .unwrap();
+    assert!(stored.is_none());
+}
+
+#[tokio::test]
+async fn resumed_drive_finds_committed_attempt_after_rejection() {
+    let dead_input = r#"{"type":"Resolve","description":"Resolve failed Dead(OutPoint(0x01))"}"#;
+    let rpc_client = MockChain::new(vec![
+        TxStatus::rejected(dead_input.into()),
+        TxStatus::committed(committed_block().hash().unpack()),
+    ]);
+    let (mut ctx, mut drive, drive_tx) = submitted_context(&rpc_client, &resubmit_config(3)).await;
+    let submitted = drive.transaction_hash.clone();
+
+    // the fee bumped replacement was the last attempt before restart
+    let bumped: H256 = [9u8; 32].into();
+    drive.attempts.push(KoDriveAttempt::new(
+        bumped.clone(),
+        drive_tx.fee * 3,
+        KoDriveAction::BumpFee,
+        None,
+    ));
+    drive.transaction_hash = bumped.clone();
+    let project_type_args = ctx.assembler.get_project_args().clone();
+    ctx.storage
+        .save_pending_drive(&project_type_args, &drive, &Default::default())
+        .unwrap();
+
+    // the replacement is rejected since the first submission has been committed
+    let global_output = CellOutput::new_builder()
+        .capacity(500_000_000_000u64.pack())
+        .build();
+    rpc_client.add_live_cell(global_output, Bytes::from(r#"{"count":1}"#), &submitted);
+    let global_cell = ctx
+        .resume_pending_drive()
+        .await
+        .expect("resume")
+        .expect("committed");
+    assert!(rpc_client.is_exhausted());
+    assert_eq!(rpc_client.polled(), vec![bumped, submitted]);
+
+    // global cell comes from the committed attempt and the drive is completed
+    assert_eq!(global_cell.capacity, 500_000_000_000);
+    assert_eq!(global_cell.output_data, Bytes::from(r#"{"count":1}"#));
+    let stored = ctx.storage.load_pending_drive(&project_type_args).unwrap();
+    assert!(stored.is_none());
 }
@@ -0,0 +1,137 @@
+use ko_context_assembler::{make_transaction_outputs, replay_drive_transaction};
+use ko_context_executor::{ExecutorError, ExecutorImpl, ExecutorPool};
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::core::TransactionView;
+use ko_protocol::ckb_types::packed::CellOutput;
+use ko_protocol::ckb_types::prelude::{Entity, Unpack};
+use ko_protocol::traits::CkbClient;
+use ko_protocol::types::config::KoDriveConfig;
+use ko_protocol::types::context::{KoDriveVerification, KoOutputMismatch};
+use ko_protocol::{hex, KoResult, ProjectDeps, H256};
+
+use crate::execute_assemble_receipt;
+
+/// Replay a committed drive transaction offline, run its requests again with the seeds
+/// recovered from chain, and diff the outputs against what the driver committed
+pub async fn verify_drive_transaction<C: CkbClient>(
+    rpc_client: &C,
+    project_deps: &ProjectDeps,
+    config: &KoDriveConfig,
+    transaction_hash: &H256,
+) -> KoResult<KoDriveVerification> {
+    let mut replay = replay_drive_transaction(
+        rpc_client,
+        transaction_hash,
+        &project_deps.project_code_hash,
+    )
+    .await?;
+    let executor = ExecutorImpl::new(
+        config.project_max_instructions(&replay.project_type_args),
+        config.max_lua_memory_bytes,
+        &ExecutorPool::new(config.executor_pool_size as usize),
+    );
+    let inputs_capacity = replay.receipt.global_cell.capacity
+        + replay
+            .receipt
+            .requests
+            .iter()
+            .map(|request| request.capacity)
+            .sum::<u64>();
+    let (cell_outputs, request_results) =
+        execute_assemble_receipt(&executor, &replay.project, &mut replay.receipt).await?;
+    let (outputs, outputs_data) = make_transaction_outputs(
+        &cell_outputs,
+        &project_deps.project_code_hash,
+        &replay.project_type_args,
+        inputs_capacity,
+        replay.fee,
+    );
+    let mismatches = compare_transaction_outputs(&outputs, &outputs_data, &replay.transaction);
+    let limited_requests = request_results
+        .iter()
+        .enumerate()
+        .filter_map(|(i, result)| {
+            let error = result.as_ref().err()?.downcast_ref::<ExecutorError>()?;
+            error.exceeds_limits().then_some(i)
+        })
+        .collect();
+    let request_errors = request_results
+        .into_iter()
+        .map(|result| result.err().map(|err| err.to_string()))
+        .collect();
+    Ok(KoDriveVerification::new(
+        transaction_hash.clone(),
+        replay.project_type_args,
+        replay.receipt.random_seeds,
+        request_errors,
+        limited_requests,
+        mismatches,
+    ))
+}
+
+/// Compare expected outputs with the committed ones, cells beyond expected outputs can only
+/// be change cells of driver
+pub fn compare_transaction_outputs(
+    outputs: &[CellOutput],
+    outputs_data: &[Bytes],
+    tx: &TransactionView,
+) -> Vec<KoOutputMismatch> {
+    let mut mismatches = vec![];
+    let mut check = |index: usize, field: &str, expected: String, actual: String| {
+        if expected != actual {
+            mismatches.push(KoOutputMismatch::new(index, field.into(), expected, actual));
+        }
+    };
+    for (i, (output, data)) in outputs.iter().zip(outputs_data).enumerate() {
+        let actual = match tx.output(i) {
+            Some(actual) => actual,
+            None => {
+                check(i, "cell", "present".into(), "missing".into());
+                continue;
+            }
+        };
+        let actual_data = tx.outputs_data().get(i).unwrap().raw_data();
+        check(
+            i,
+            "lock",
+            hex::encode(output.lock().as_slice()),
+            hex::encode(actual.lock().as_slice()),
+        );
+        check(
+            i,
+            "type",
+            hex::encode(output.type_().as_slice()),
+            hex::encode(actual.type_().as_slice()),
+        );
+        check(
+            i,
+            "data",
+            String::from_utf8_lossy(data).into(),
+            String::from_utf8_lossy(&actual_data).into(),
+        );
+        let expected_capacity: u64 = output.capacity().unpack();
+        let actual_capacity: u64 = actual.capacity().unpack();
+        check(
+            i,
+            "capacity",
+            expected_capacity.to_string(),
+            actual_capacity.to_string(),
+        );
+    }
+    if let Some(global_output) = outputs.first() {
+        let driver_lock = hex::encode(global_output.lock().as_slice());
+        for i in outputs.len()..tx.outputs().len() {
+            let actual = tx.output(i).unwrap();
+            let is_change =
+                actual.type_().is_none() && tx.outputs_data().get(i).unwrap().raw_data().is_empty();
+            let actual_lock = hex::encode(actual.lock().as_slice());
+            let actual = if is_change {
+                actual_lock
+            } else {
+                format!("{} with type or data", actual_lock)
+            };
+            check(i, "change", driver_lock.clone(), actual);
+        }
+    }
+    mismatches
+}
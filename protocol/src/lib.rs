@@ -129,7 +129,21 @@ pub mod TestVars {
                 KoCellDep::new(KNSIDEOUT_TX_HASH.into(), 0, DepType::Code.into()),
             ]
         );
-        pub static ref DRIVE_CONFIG: KoDriveConfig = KoDriveConfig::new(3, 10, 3, 100);
+        pub static ref DRIVE_CONFIG: KoDriveConfig = KoDriveConfig {
+            drive_interval_sec: 3,
+            max_reqeusts_count: 10,
+            block_confirms_count: 3,
+            kickout_idle_sec: 100,
+            max_instructions_per_request: 10_000_000,
+            max_lua_memory_bytes: 64 * 1024 * 1024,
+            random_seed_block_delay: 2,
+            executor_pool_size: 4,
+            fee_settings: Default::default(),
+            pending_timeout_sec: 120,
+            max_resubmit_attempts: 5,
+            fee_bump_percent: 200,
+            projects: vec![],
+        };
     }
 
     #[derive(Default, Clone, Copy)]
@@ -154,11 +168,63 @@ impl traits::ContextRpc for TestVars::MockContextRpc {
         false
     }
 
+    async fn simulate_request(
+        &mut self,
+        _project_type_args: &H256,
+        _method_call: &str,
+        _inputs: &[(ckb_types::packed::Script, String)],
+        _candidates: &[ckb_types::packed::Script],
+        _components: &[String],
+        _response: tokio::sync::mpsc::UnboundedSender<
+            KoResult<types::assembler::KoRequestSimulation>,
+        >,
+    ) -> bool {
+        false
+    }
+
+    async fn call_view(
+        &mut self,
+        _project_type_args: &H256,
+        _method_call: &str,
+        _inputs: &[(ckb_types::packed::Script, String)],
+        _response: tokio::sync::mpsc::UnboundedSender<KoResult<String>>,
+    ) -> bool {
+        false
+    }
+
     async fn listen_request_committed(
         &mut self,
         _project_type_args: &H256,
         _request_hash: &H256,
-        _response: tokio::sync::mpsc::UnboundedSender<KoResult<H256>>,
+        _response: tokio::sync::mpsc::UnboundedSender<KoResult<types::context::KoRequestReceipt>>,
+    ) -> bool {
+        false
+    }
+
+    async fn fetch_request_receipt(
+        &mut self,
+        _project_type_args: &H256,
+        _request_hash: &H256,
+        _response: tokio::sync::mpsc::UnboundedSender<Option<types::context::KoRequestReceipt>>,
+    ) -> bool {
+        false
+    }
+
+    async fn get_contract_abi(
+        &mut self,
+        _project_type_args: &H256,
+        _response: tokio::sync::mpsc::UnboundedSender<KoResult<types::assembler::KoContractAbi>>,
+    ) -> bool {
+        false
+    }
+
+    async fn preview_upgrade(
+        &mut self,
+        _project_type_args: &H256,
+        _project: &types::assembler::KoProject,
+        _response: tokio::sync::mpsc::UnboundedSender<
+            KoResult<(String, types::assembler::KoContractAbi)>,
+        >,
     ) -> bool {
         false
     }
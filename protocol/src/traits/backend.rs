@@ -1,4 +1,6 @@
-use crate::types::backend::KoRequestInput;
+use crate::types::assembler::{KoContractAbi, KoRequestSimulation};
+use crate::types::backend::{KoDeployOptions, KoProjectInfo, KoRequestInput, KoUpgradeReport};
+use crate::types::context::KoRequestReceipt;
 use crate::{async_trait, KoResult, ProjectDeps, H256};
 use ckb_types::{bytes::Bytes, packed::OutPoint};
 
@@ -8,6 +10,7 @@ pub trait Backend: Send + Sync {
         &mut self,
         contract: Bytes,
         address: String,
+        options: KoDeployOptions,
         project_deps: &ProjectDeps,
     ) -> KoResult<(H256, H256)>;
 
@@ -16,8 +19,9 @@ pub trait Backend: Send + Sync {
         contract: Bytes,
         address: String,
         project_type_args: &H256,
+        options: KoDeployOptions,
         project_deps: &ProjectDeps,
-    ) -> KoResult<H256>;
+    ) -> KoResult<(H256, KoUpgradeReport)>;
 
     async fn create_project_request_digest(
         &mut self,
@@ -29,12 +33,46 @@ pub trait Backend: Send + Sync {
         project_deps: &ProjectDeps,
     ) -> KoResult<(H256, u64)>;
 
+    async fn simulate_project_request(
+        &mut self,
+        function_call: String,
+        input: KoRequestInput,
+        component_outputs: &[OutPoint],
+        candidate_lockscripts: &[String],
+        project_type_args: &H256,
+    ) -> KoResult<KoRequestSimulation>;
+
+    async fn call_project_view(
+        &mut self,
+        function_call: String,
+        input_outpoints: &[OutPoint],
+        project_type_args: &H256,
+    ) -> KoResult<String>;
+
+    async fn get_contract_abi(&mut self, project_type_args: &H256) -> KoResult<KoContractAbi>;
+
+    async fn get_project_info(&mut self, project_type_args: &H256) -> KoResult<KoProjectInfo>;
+
+    async fn check_project_upgrade(
+        &mut self,
+        contract: Bytes,
+        address: String,
+        project_type_args: &H256,
+        options: &KoDeployOptions,
+    ) -> KoResult<KoUpgradeReport>;
+
     async fn check_project_request_committed(
         &mut self,
         transaction_hash: &H256,
         project_type_args: &H256,
         project_deps: &ProjectDeps,
-    ) -> KoResult<Option<H256>>;
+    ) -> KoResult<Option<KoRequestReceipt>>;
+
+    async fn fetch_request_receipt(
+        &mut self,
+        request_hash: &H256,
+        project_type_args: &H256,
+    ) -> KoResult<Option<KoRequestReceipt>>;
 
     async fn drive_project_on_management(
         &mut self,
@@ -6,6 +6,7 @@ use ckb_jsonrpc_types::{
 };
 use ckb_sdk::rpc::ckb_indexer::{Cell, Pagination, SearchKey};
 
+use crate::types::context::KoFeeRateStatistics;
 use crate::{async_trait, KoResult, H256};
 
 #[async_trait]
@@ -17,6 +18,8 @@ pub trait CkbClient: Send + Sync + Clone {
 
     fn get_tip_header(&self) -> RPC<HeaderView>;
 
+    fn get_header_by_number(&self, number: BlockNumber) -> RPC<HeaderView>;
+
     fn get_transaction(&self, hash: &H256) -> RPC<Option<TransactionWithStatus>>;
 
     fn get_live_cell(&self, out_point: &OutPoint, with_data: bool) -> RPC<CellWithStatus>;
@@ -29,6 +32,8 @@ pub trait CkbClient: Send + Sync + Clone {
 
     fn get_txs_by_hashes(&self, hash: Vec<H256>) -> RPC<Vec<Option<TransactionWithStatus>>>;
 
+    fn get_fee_rate_statistics(&self) -> RPC<Option<KoFeeRateStatistics>>;
+
     // indexer api
     fn fetch_live_cells(
         &self,
@@ -1,6 +1,8 @@
 use ckb_types::packed::Script;
 use tokio::sync::mpsc::UnboundedSender;
 
+use crate::types::assembler::{KoContractAbi, KoProject, KoRequestSimulation};
+use crate::types::context::KoRequestReceipt;
 use crate::{async_trait, KoResult, H256};
 
 #[async_trait]
@@ -17,10 +19,48 @@ pub trait ContextRpc: Send + Sync {
         response: UnboundedSender<KoResult<u64>>,
     ) -> bool;
 
+    async fn simulate_request(
+        &mut self,
+        project_type_args: &H256,
+        method_call: &str,
+        inputs: &[(Script, String)],
+        candidates: &[Script],
+        components: &[String],
+        response: UnboundedSender<KoResult<KoRequestSimulation>>,
+    ) -> bool;
+
+    async fn call_view(
+        &mut self,
+        project_type_args: &H256,
+        method_call: &str,
+        inputs: &[(Script, String)],
+        response: UnboundedSender<KoResult<String>>,
+    ) -> bool;
+
     async fn listen_request_committed(
         &mut self,
         project_type_args: &H256,
         request_hash: &H256,
-        response: UnboundedSender<KoResult<H256>>,
+        response: UnboundedSender<KoResult<KoRequestReceipt>>,
+    ) -> bool;
+
+    async fn fetch_request_receipt(
+        &mut self,
+        project_type_args: &H256,
+        request_hash: &H256,
+        response: UnboundedSender<Option<KoRequestReceipt>>,
+    ) -> bool;
+
+    async fn get_contract_abi(
+        &mut self,
+        project_type_args: &H256,
+        response: UnboundedSender<KoResult<KoContractAbi>>,
+    ) -> bool;
+
+    async fn preview_upgrade(
+        &mut self,
+        project_type_args: &H256,
+        project: &KoProject,
+        response: UnboundedSender<KoResult<(String, KoContractAbi)>>,
     ) -> bool;
 }
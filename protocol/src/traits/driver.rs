@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use crate::types::context::KoTransactionStatus;
 use crate::{async_trait, KoResult, H256};
 use ckb_types::bytes::Bytes;
 use ckb_types::core::TransactionView;
@@ -15,5 +16,6 @@ pub trait Driver {
         hash: &H256,
         interval: &Duration,
         confirms: u8,
-    ) -> KoResult<()>;
+        pending_timeout: &Duration,
+    ) -> KoResult<KoTransactionStatus>;
 }
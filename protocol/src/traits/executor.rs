@@ -1,25 +1,54 @@
 use ckb_types::bytes::Bytes;
 use ckb_types::packed::Script;
 
-use crate::types::assembler::{KoCellOutput, KoRequest};
+use crate::types::assembler::{
+    KoBlockContext, KoCellOutput, KoContractAbi, KoProject, KoRequest, KoRequestSimulation,
+};
 use crate::types::context::KoContextGlobalCell;
-use crate::KoResult;
+use crate::{async_trait, KoResult};
 
+#[async_trait]
 pub trait Executor {
-    fn execute_lua_requests(
+    async fn execute_lua_requests(
         &self,
         global_cell: &mut KoContextGlobalCell,
-        project_owner: &Script,
+        project: &KoProject,
         user_requests: &[KoRequest],
-        project_lua_code: &Bytes,
-        random_seeds: &[i64; 2],
+        random_seeds: &[[i64; 2]],
+        block_context: &KoBlockContext,
     ) -> KoResult<Vec<KoResult<KoCellOutput>>>;
 
-    fn estimate_payment_ckb(
+    async fn estimate_payment_ckb(
         &self,
         global_cell: &KoContextGlobalCell,
-        project_owner: &Script,
+        project: &KoProject,
         request: KoRequest,
-        project_lua_code: &Bytes,
+        block_context: &KoBlockContext,
     ) -> KoResult<u64>;
+
+    async fn simulate_request(
+        &self,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        request: KoRequest,
+        block_context: &KoBlockContext,
+    ) -> KoResult<KoRequestSimulation>;
+
+    async fn call_view(
+        &self,
+        global_cell: &KoContextGlobalCell,
+        project: &KoProject,
+        function_call: &str,
+        inputs: &[(Script, Bytes)],
+        block_context: &KoBlockContext,
+    ) -> KoResult<String>;
+
+    async fn migrate_global(
+        &self,
+        global_cell: &mut KoContextGlobalCell,
+        project: &KoProject,
+        block_context: &KoBlockContext,
+    ) -> KoResult<()>;
+
+    async fn get_contract_abi(&self, project: &KoProject) -> KoResult<KoContractAbi>;
 }
@@ -4,6 +4,7 @@ mod client;
 mod context;
 mod driver;
 mod executor;
+mod storage;
 
 pub use assembler::Assembler;
 pub use backend::Backend;
@@ -11,3 +12,4 @@ pub use client::{CkbClient, RPC};
 pub use context::ContextRpc;
 pub use driver::Driver;
 pub use executor::Executor;
+pub use storage::Storage;
@@ -0,0 +1,39 @@
+use crate::types::context::{KoContextGlobalCell, KoPendingDrive, KoRequestOutcome};
+use crate::{KoResult, H256};
+use ckb_types::core::TransactionView;
+
+/// Durable state of driver, which lets a restarted driver resume where it left off
+pub trait Storage: Send + Sync {
+    fn save_project_status(&self, project_type_args: &H256, running: bool) -> KoResult<()>;
+
+    fn load_projects_status(&self) -> KoResult<Vec<(H256, bool)>>;
+
+    fn save_digest_transaction(&self, digest: &H256, tx: &TransactionView) -> KoResult<()>;
+
+    fn load_digest_transaction(&self, digest: &H256) -> KoResult<Option<TransactionView>>;
+
+    fn take_digest_transaction(&self, digest: &H256) -> KoResult<Option<TransactionView>>;
+
+    // the global cell which drive transaction outputs becomes the last known one
+    fn save_pending_drive(
+        &self,
+        project_type_args: &H256,
+        drive: &KoPendingDrive,
+        global_cell: &KoContextGlobalCell,
+    ) -> KoResult<()>;
+
+    fn load_pending_drive(&self, project_type_args: &H256) -> KoResult<Option<KoPendingDrive>>;
+
+    // store outcomes of requests and forget the pending drive at once
+    fn complete_pending_drive(
+        &self,
+        project_type_args: &H256,
+        outcomes: &[(H256, KoRequestOutcome)],
+    ) -> KoResult<()>;
+
+    fn discard_pending_drive(&self, project_type_args: &H256) -> KoResult<()>;
+
+    fn load_global_cell(&self, project_type_args: &H256) -> KoResult<Option<KoContextGlobalCell>>;
+
+    fn load_request_outcome(&self, request_hash: &H256) -> KoResult<Option<KoRequestOutcome>>;
+}
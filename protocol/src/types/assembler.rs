@@ -1,10 +1,16 @@
+use ckb_hash::{blake2b_256, Blake2bBuilder, CKB_HASH_PERSONALIZATION};
+use ckb_jsonrpc_types::{HeaderView, OutPoint};
 use ckb_types::bytes::Bytes;
-use ckb_types::packed::{CellDep, Script};
+use ckb_types::core::{EpochNumberWithFraction, TransactionView};
+use ckb_types::packed::{self, CellDep, Script};
+use ckb_types::prelude::Entity;
 use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
 
 use super::context::KoContextGlobalCell;
+use crate::{KoResult, H256};
 
-#[derive(Constructor, Debug)]
+#[derive(Constructor, Clone, Debug)]
 pub struct KoRequest {
     pub function_call: Bytes,
     pub inputs: Vec<(Script, Bytes)>,
@@ -14,42 +20,214 @@ pub struct KoRequest {
     pub capacity: u64,
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Clone, Default, Debug)]
 pub struct KoProject {
     pub cell_dep: CellDep,
     pub lua_code: Bytes,
     pub contract_owner: Script,
+    pub modules: Vec<KoLuaModule>,
+    pub manifest: Option<KoProjectManifest>,
 }
 
-pub struct KoAssembleReceipt {
-    pub requests: Vec<KoRequest>,
-    pub global_cell: KoContextGlobalCell,
-    pub random_seeds: [i64; 2],
+// project cell data which carries a manifest starts with magic and format version, then the
+// length of manifest JSON (u32 little-endian), the manifest itself and finally lua bytecode
+const PROJECT_MANIFEST_MAGIC: &[u8; 4] = b"KOPM";
+const PROJECT_MANIFEST_VERSION: u8 = 1;
+
+/// Description of project deployed along with its bytecode, for tooling and explorers
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct KoProjectManifest {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    // public functions which requests are allowed to call, all of them if empty
+    #[serde(default)]
+    pub functions: Vec<KoFunctionAbi>,
+    #[serde(default)]
+    pub views: Vec<String>,
+    // JSON schema of global data
+    #[serde(default)]
+    pub schema: Option<serde_json::Value>,
 }
 
-impl KoAssembleReceipt {
-    pub fn new(
-        requests: Vec<KoRequest>,
-        global_cell: KoContextGlobalCell,
-        random_bytes: [u8; 16],
-    ) -> Self {
-        let random_seeds = {
-            let mut seed_one = [0u8; 8];
-            seed_one.copy_from_slice(&random_bytes[..8]);
-            let mut seed_two = [0u8; 8];
-            seed_two.copy_from_slice(&random_bytes[8..]);
-            [i64::from_le_bytes(seed_one), i64::from_le_bytes(seed_two)]
-        };
-        KoAssembleReceipt {
-            requests,
-            global_cell,
-            random_seeds,
+impl KoProjectManifest {
+    pub fn pack_with(&self, bytecode: &[u8]) -> Bytes {
+        let manifest = serde_json::to_vec(self).expect("manifest JSON");
+        let mut data = PROJECT_MANIFEST_MAGIC.to_vec();
+        data.push(PROJECT_MANIFEST_VERSION);
+        data.extend_from_slice(&(manifest.len() as u32).to_le_bytes());
+        data.extend_from_slice(&manifest);
+        data.extend_from_slice(bytecode);
+        Bytes::from(data)
+    }
+
+    /// Split project cell data into manifest and bytecode, the data of projects deployed
+    /// without manifest is bytecode itself
+    pub fn unpack(data: &Bytes) -> Result<(Option<Self>, Bytes), String> {
+        if !data.starts_with(PROJECT_MANIFEST_MAGIC) {
+            return Ok((None, data.clone()));
+        }
+        let header_len = PROJECT_MANIFEST_MAGIC.len() + 5;
+        if data.len() < header_len {
+            return Err("manifest header is truncated".into());
+        }
+        let version = data[PROJECT_MANIFEST_MAGIC.len()];
+        if version != PROJECT_MANIFEST_VERSION {
+            return Err(format!("unsupported manifest version {}", version));
         }
+        let mut manifest_len = [0u8; 4];
+        manifest_len.copy_from_slice(&data[header_len - 4..header_len]);
+        let manifest_end = header_len + u32::from_le_bytes(manifest_len) as usize;
+        if data.len() < manifest_end {
+            return Err("manifest is truncated".into());
+        }
+        let manifest = serde_json::from_slice(&data[header_len..manifest_end])
+            .map_err(|err| err.to_string())?;
+        Ok((Some(manifest), data.slice(manifest_end..)))
+    }
+}
+
+/// Lua module stored in a TYPE_ID cell, which project contract can `require` by type id
+#[derive(Constructor, Clone, Debug, PartialEq)]
+pub struct KoLuaModule {
+    pub type_id: H256,
+    pub data_hash: H256,
+    pub code: Bytes,
+}
+
+impl KoLuaModule {
+    pub fn pinned(type_id: H256, code: Bytes) -> Self {
+        let data_hash = blake2b_256(&code).into();
+        KoLuaModule::new(type_id, data_hash, code)
+    }
+
+    // the data hash is pinned by project, so the code of an upgraded module cell won't match
+    pub fn verify(&self) -> bool {
+        H256::from(blake2b_256(&self.code)) == self.data_hash
+    }
+}
+
+#[derive(Serialize, Deserialize, Constructor, Clone, Default, Debug, PartialEq, Eq)]
+pub struct KoBlockContext {
+    pub block_hash: H256,
+    pub block_number: u64,
+    pub timestamp: u64,
+    pub epoch: u64,
+}
+
+impl From<HeaderView> for KoBlockContext {
+    fn from(header: HeaderView) -> Self {
+        let epoch: u64 = header.inner.epoch.into();
+        KoBlockContext::new(
+            header.hash.into(),
+            header.inner.number.into(),
+            header.inner.timestamp.into(),
+            EpochNumberWithFraction::from_full_value(epoch).number(),
+        )
     }
 }
 
+/// Random seeds of one request, derived from the hash of a block mined after the request
+/// cell was committed, so neither the driver nor the sender can choose them in advance
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug, PartialEq, Eq)]
+pub struct KoRandomSeed {
+    pub seeds: [i64; 2],
+    pub request_outpoint: OutPoint,
+    pub seed_block_hash: H256,
+    pub seed_block_number: u64,
+}
+
+impl KoRandomSeed {
+    pub fn derive(
+        request_outpoint: OutPoint,
+        seed_block_hash: H256,
+        seed_block_number: u64,
+    ) -> Self {
+        let seeds = derive_seeds(&request_outpoint, &seed_block_hash);
+        KoRandomSeed::new(seeds, request_outpoint, seed_block_hash, seed_block_number)
+    }
+
+    pub fn verify(&self) -> bool {
+        derive_seeds(&self.request_outpoint, &self.seed_block_hash) == self.seeds
+    }
+}
+
+// blake2b(seed_block_hash | request_outpoint) split into two little-endian i64
+fn derive_seeds(request_outpoint: &OutPoint, seed_block_hash: &H256) -> [i64; 2] {
+    let mut random_bytes = [0u8; 16];
+    let mut blake2b = Blake2bBuilder::new(16)
+        .personal(CKB_HASH_PERSONALIZATION)
+        .build();
+    blake2b.update(seed_block_hash.as_bytes());
+    blake2b.update(packed::OutPoint::from(request_outpoint.clone()).as_slice());
+    blake2b.finalize(&mut random_bytes);
+    let mut seed_one = [0u8; 8];
+    seed_one.copy_from_slice(&random_bytes[..8]);
+    let mut seed_two = [0u8; 8];
+    seed_two.copy_from_slice(&random_bytes[8..]);
+    [i64::from_le_bytes(seed_one), i64::from_le_bytes(seed_two)]
+}
+
+#[derive(Constructor)]
+pub struct KoAssembleReceipt {
+    pub requests: Vec<KoRequest>,
+    pub global_cell: KoContextGlobalCell,
+    pub random_seeds: Vec<KoRandomSeed>,
+    pub block_context: KoBlockContext,
+    // project was upgraded after global cell was produced
+    pub migrate_global: bool,
+}
+
+/// Everything needed to re-run a committed drive transaction offline
 #[derive(Constructor)]
+pub struct KoDriveReplay {
+    pub transaction: TransactionView,
+    pub project: KoProject,
+    pub project_type_args: H256,
+    pub receipt: KoAssembleReceipt,
+    pub fee: u64,
+}
+
+#[derive(Constructor, Debug)]
 pub struct KoCellOutput {
     pub cells: Vec<(Script, Option<Bytes>)>,
     pub suggested_capacity: u64,
+    pub events: Vec<KoEvent>,
+}
+
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug, PartialEq, Eq)]
+pub struct KoEvent {
+    pub name: String,
+    pub data: String,
+}
+
+/// Public function of contract, `params` doesn't count the trailing `...` of variadic ones
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug, PartialEq, Eq)]
+pub struct KoFunctionAbi {
+    pub name: String,
+    pub params: u8,
+    #[serde(default)]
+    pub variadic: bool,
+}
+
+#[derive(Serialize, Deserialize, Constructor, Clone, Default, Debug, PartialEq, Eq)]
+pub struct KoContractAbi {
+    pub functions: Vec<KoFunctionAbi>,
+}
+
+impl KoContractAbi {
+    pub fn get(&self, name: &str) -> Option<&KoFunctionAbi> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+}
+
+#[derive(Constructor, Debug)]
+pub struct KoRequestSimulation {
+    pub outputs: KoResult<KoCellOutput>,
+    pub global_data: Bytes,
+    pub deposit_ckb: u64,
+    pub withdraw_ckb: u64,
 }
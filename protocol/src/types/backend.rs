@@ -1,6 +1,40 @@
+use ckb_jsonrpc_types::OutPoint as JsonOutPoint;
 use ckb_types::packed::OutPoint;
+use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
+
+use super::assembler::{KoFunctionAbi, KoProjectManifest};
+use crate::H256;
+
+/// Optional parts of project deployment, `debug_info` keeps source name and line numbers in
+/// deployed bytecode for readable contract errors at the cost of larger project cell
+#[derive(Constructor, Default, Debug)]
+pub struct KoDeployOptions {
+    pub module_type_args: Vec<H256>,
+    pub manifest: Option<KoProjectManifest>,
+    pub debug_info: bool,
+}
 
 pub enum KoRequestInput {
     Address(String),
     Outpoints(Vec<OutPoint>),
 }
+
+/// Deployment of project, `manifest` is absent if project was deployed without it
+#[derive(Serialize, Deserialize, Constructor, Debug)]
+pub struct KoProjectInfo {
+    pub project_type_args: H256,
+    pub deployment: JsonOutPoint,
+    pub owner_lock_hash: H256,
+    pub bytecode_hash: H256,
+    pub manifest: Option<KoProjectManifest>,
+}
+
+/// Checked project upgrade, `global_data` is the live global data after `migrate` of new contract
+#[derive(Serialize, Deserialize, Constructor, Default, Debug, PartialEq, Eq)]
+pub struct KoUpgradeReport {
+    pub added_functions: Vec<KoFunctionAbi>,
+    pub removed_functions: Vec<KoFunctionAbi>,
+    pub changed_functions: Vec<KoFunctionAbi>,
+    pub global_data: String,
+}
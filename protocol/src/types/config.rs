@@ -3,6 +3,7 @@ use ckb_types::prelude::{Builder, Entity, Pack};
 use derive_more::Constructor;
 use serde::{Deserialize, Serialize};
 
+use crate::traits::CkbClient;
 use crate::H256;
 
 #[derive(Deserialize, Clone, Constructor)]
@@ -24,12 +25,162 @@ impl From<&KoCellDep> for CellDep {
     }
 }
 
-#[derive(Deserialize, Clone, Constructor)]
+#[derive(Deserialize, Clone)]
 pub struct KoDriveConfig {
     pub drive_interval_sec: u8,
     pub max_reqeusts_count: u8,
     pub block_confirms_count: u8,
     pub kickout_idle_sec: u64,
+    #[serde(default = "default_max_instructions_per_request")]
+    pub max_instructions_per_request: u64,
+    #[serde(default = "default_max_lua_memory_bytes")]
+    pub max_lua_memory_bytes: u64,
+    #[serde(default = "default_random_seed_block_delay")]
+    pub random_seed_block_delay: u64,
+    #[serde(default = "default_executor_pool_size")]
+    pub executor_pool_size: u8,
+    #[serde(default)]
+    pub fee_settings: KoFeeSettings,
+    #[serde(default = "default_pending_timeout_sec")]
+    pub pending_timeout_sec: u64,
+    #[serde(default = "default_max_resubmit_attempts")]
+    pub max_resubmit_attempts: u8,
+    #[serde(default = "default_fee_bump_percent")]
+    pub fee_bump_percent: u64,
+    #[serde(default)]
+    pub projects: Vec<KoProjectDriveConfig>,
+}
+
+impl KoDriveConfig {
+    pub fn project_max_instructions(&self, project_type_args: &H256) -> u64 {
+        self.projects
+            .iter()
+            .find(|project| &project.project_type_args == project_type_args)
+            .map(|project| project.max_instructions_per_request)
+            .unwrap_or(self.max_instructions_per_request)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct KoProjectDriveConfig {
+    pub project_type_args: H256,
+    pub max_instructions_per_request: u64,
+}
+
+/// Fee policy of transactions, the fee rate is in shannons per 1000 bytes
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KoFeeConfig {
+    #[serde(default = "default_fee_rate")]
+    pub fee_rate: u64,
+    #[serde(default)]
+    pub use_node_fee_rate: bool,
+    #[serde(default = "default_max_fee_rate")]
+    pub max_fee_rate: u64,
+}
+
+impl Default for KoFeeConfig {
+    fn default() -> Self {
+        KoFeeConfig {
+            fee_rate: default_fee_rate(),
+            use_node_fee_rate: false,
+            max_fee_rate: default_max_fee_rate(),
+        }
+    }
+}
+
+impl KoFeeConfig {
+    /// Median fee rate of node is followed if enabled, but never below the configured one
+    pub fn resolve_fee_rate(&self, node_fee_rate: Option<u64>) -> u64 {
+        match node_fee_rate {
+            Some(fee_rate) if self.use_node_fee_rate => {
+                fee_rate.clamp(self.fee_rate, self.max_fee_rate.max(self.fee_rate))
+            }
+            _ => self.fee_rate,
+        }
+    }
+
+    /// Fee rate to pay, which fetches fee rate statistics of node only if it's followed
+    pub async fn estimate_fee_rate(&self, rpc: &impl CkbClient) -> u64 {
+        let mut node_fee_rate = None;
+        if self.use_node_fee_rate {
+            match rpc.get_fee_rate_statistics().await {
+                Ok(statistics) => {
+                    node_fee_rate = statistics.map(|statistics| statistics.median.value())
+                }
+                Err(err) => log::warn!("fetch fee rate statistics failed, error = {}", err),
+            }
+        }
+        self.resolve_fee_rate(node_fee_rate)
+    }
+
+    pub fn calc_fee(fee_rate: u64, tx_size: usize) -> u64 {
+        (tx_size as u64 * fee_rate).div_ceil(1000)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct KoProjectFeeConfig {
+    pub project_type_args: H256,
+    #[serde(flatten)]
+    pub fee: KoFeeConfig,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct KoFeeSettings {
+    #[serde(flatten)]
+    pub default: KoFeeConfig,
+    #[serde(default)]
+    pub projects: Vec<KoProjectFeeConfig>,
+}
+
+impl KoFeeSettings {
+    pub fn project_fee(&self, project_type_args: &H256) -> &KoFeeConfig {
+        self.projects
+            .iter()
+            .find(|project| &project.project_type_args == project_type_args)
+            .map(|project| &project.fee)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn default_max_instructions_per_request() -> u64 {
+    10_000_000
+}
+
+fn default_max_lua_memory_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_random_seed_block_delay() -> u64 {
+    2
+}
+
+fn default_executor_pool_size() -> u8 {
+    4
+}
+
+fn default_pending_timeout_sec() -> u64 {
+    120
+}
+
+fn default_max_resubmit_attempts() -> u8 {
+    5
+}
+
+fn default_fee_bump_percent() -> u64 {
+    200
+}
+
+fn default_fee_rate() -> u64 {
+    1000
+}
+
+fn default_max_fee_rate() -> u64 {
+    100_000
+}
+
+fn default_storage_path() -> String {
+    ".knside-out.db".into()
 }
 
 #[derive(Deserialize)]
@@ -41,6 +192,8 @@ pub struct KoConfig {
     pub ckb_indexer_url: String,
     pub rpc_endpoint: String,
     pub persist_interval_sec: u64,
+    #[serde(default = "default_storage_path")]
+    pub storage_path: String,
     pub project_cell_deps: Vec<KoCellDep>,
     pub drive_settings: KoDriveConfig,
 }
@@ -2,8 +2,12 @@ use ckb_sdk::traits::LiveCell;
 use ckb_types::packed::{CellOutput, Script};
 use ckb_types::{bytes::Bytes, core::Capacity, prelude::Unpack};
 use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender;
 
+use super::assembler::{
+    KoBlockContext, KoContractAbi, KoEvent, KoProject, KoRandomSeed, KoRequestSimulation,
+};
 use crate::{KoResult, H256};
 
 #[derive(Debug)]
@@ -15,10 +19,127 @@ pub enum KoContextRpcEcho {
             UnboundedSender<KoResult<u64>>,
         ),
     ),
-    ListenRequestCommitted((H256, UnboundedSender<KoResult<H256>>)),
+    #[allow(clippy::type_complexity)]
+    SimulateRequest(
+        (
+            (Vec<(Script, Bytes)>, String, Vec<Script>, Vec<Bytes>),
+            UnboundedSender<KoResult<KoRequestSimulation>>,
+        ),
+    ),
+    #[allow(clippy::type_complexity)]
+    CallView(
+        (
+            (Vec<(Script, Bytes)>, String),
+            UnboundedSender<KoResult<String>>,
+        ),
+    ),
+    ListenRequestCommitted((H256, UnboundedSender<KoResult<KoRequestReceipt>>)),
+    FetchRequestReceipt((H256, UnboundedSender<Option<KoRequestReceipt>>)),
+    GetContractAbi(UnboundedSender<KoResult<KoContractAbi>>),
+    PreviewUpgrade(
+        (
+            KoProject,
+            UnboundedSender<KoResult<(String, KoContractAbi)>>,
+        ),
+    ),
+}
+
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug)]
+pub struct KoRequestReceipt {
+    pub transaction_hash: H256,
+    pub events: Vec<KoEvent>,
+    pub block_context: KoBlockContext,
+    pub random_seed: KoRandomSeed,
+}
+
+/// Receipt of committed request, or the reason why the request failed in drive transaction
+pub type KoRequestOutcome = Result<KoRequestReceipt, String>;
+
+/// Drive transaction which is sent but not confirmed yet, with the results of its requests
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug)]
+pub struct KoPendingDrive {
+    pub transaction_hash: H256,
+    pub request_results: Vec<(H256, Result<Vec<KoEvent>, String>)>,
+    pub block_context: KoBlockContext,
+    pub random_seeds: Vec<KoRandomSeed>,
+    #[serde(default)]
+    pub attempts: Vec<KoDriveAttempt>,
+}
+
+impl KoPendingDrive {
+    pub fn into_outcomes(self) -> Vec<(H256, KoRequestOutcome)> {
+        let (hash, block_context) = (self.transaction_hash, self.block_context);
+        self.request_results
+            .into_iter()
+            .zip(self.random_seeds)
+            .map(|((request_hash, events), random_seed)| {
+                let outcome = events.map(|events| {
+                    KoRequestReceipt::new(hash.clone(), events, block_context.clone(), random_seed)
+                });
+                (request_hash, outcome)
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KoDriveAction {
+    Submit,
+    Rebroadcast,
+    BumpFee,
+}
+
+/// One submission of drive transaction, with the rejection which caused it if any
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug)]
+pub struct KoDriveAttempt {
+    pub transaction_hash: H256,
+    pub fee: u64,
+    pub action: KoDriveAction,
+    pub rejection: Option<KoRejection>,
 }
 
-#[derive(Default, Constructor, Debug)]
+/// Class of the reason why ckb node rejected a transaction
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum KoRejection {
+    // inputs are dead or unknown, or the transaction is replaced by another one
+    Conflict(String),
+    // fee is below the pool requirement or not enough to replace the conflicted one
+    Fee(String),
+    // scripts failed in verification, so resubmitting changes nothing
+    Script(String),
+    Other(String),
+}
+
+impl KoRejection {
+    pub fn classify(reason: &str) -> Self {
+        let matches = |keywords: &[&str]| keywords.iter().any(|keyword| reason.contains(keyword));
+        if matches(&[
+            "LowFeeRate",
+            "MinFeeRate",
+            "min fee rate",
+            "RBFRejected",
+            "Full",
+        ]) {
+            KoRejection::Fee(reason.into())
+        } else if matches(&["Verification", "Script", "ValidationFailure"]) {
+            KoRejection::Script(reason.into())
+        } else if matches(&["Resolve", "Dead", "Unknown", "Invalidated", "Replaced"]) {
+            KoRejection::Conflict(reason.into())
+        } else {
+            KoRejection::Other(reason.into())
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KoTransactionStatus {
+    Committed,
+    // still not committed after the pending timeout
+    Pending,
+    Rejected(KoRejection),
+}
+
+#[derive(Default, Constructor, Clone, Debug)]
 pub struct KoContextGlobalCell {
     pub lock_script: Script,
     pub output_data: Bytes,
@@ -47,3 +168,41 @@ impl From<LiveCell> for KoContextGlobalCell {
         KoContextGlobalCell::new(cell.output.lock(), cell.output_data, capacity, occupied)
     }
 }
+
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug, PartialEq, Eq)]
+pub struct KoOutputMismatch {
+    pub index: usize,
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Result of replaying a committed drive transaction against its on-chain outputs
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug)]
+pub struct KoDriveVerification {
+    pub transaction_hash: H256,
+    pub project_type_args: H256,
+    pub random_seeds: Vec<KoRandomSeed>,
+    pub request_errors: Vec<Option<String>>,
+    // requests which ran out of local execution limits, driver may run them under other ones
+    pub limited_requests: Vec<usize>,
+    pub mismatches: Vec<KoOutputMismatch>,
+}
+
+impl KoDriveVerification {
+    pub fn is_honest(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    // mismatches can't blame driver if any request hit the limits of verifier
+    pub fn is_conclusive(&self) -> bool {
+        self.is_honest() || self.limited_requests.is_empty()
+    }
+}
+
+/// Fee rate statistics of recent blocks reported by ckb node, in shannons per 1000 bytes
+#[derive(Deserialize, Clone, Debug)]
+pub struct KoFeeRateStatistics {
+    pub mean: ckb_jsonrpc_types::Uint64,
+    pub median: ckb_jsonrpc_types::Uint64,
+}
@@ -11,6 +11,7 @@ pub enum ErrorType {
     Requester,
     CkbClient,
     RpcServer,
+    Storage,
 }
 
 #[derive(Debug, Constructor, Display)]
@@ -22,4 +23,10 @@ pub struct KoError {
 
 impl Error for KoError {}
 
+impl KoError {
+    pub fn downcast_ref<T: Error + 'static>(&self) -> Option<&T> {
+        self.message.downcast_ref()
+    }
+}
+
 pub type KoResult<T> = Result<T, KoError>;
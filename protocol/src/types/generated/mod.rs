@@ -108,6 +108,13 @@ pub fn is_mol_request_identity(bytes: &[u8]) -> bool {
     false
 }
 
+pub fn parse_mol_identity(bytes: &[u8]) -> Option<(u8, [u8; 32])> {
+    generated::IdentityReader::verify(bytes, false).ok()?;
+    let identity = generated::Identity::new_unchecked(bytes.to_vec().into());
+    let project_id = identity.project_id().raw_data().to_vec().try_into().ok()?;
+    Some((identity.flag().into(), project_id))
+}
+
 pub fn parse_mol_request(bytes: &[u8]) -> generated::Request {
     generated::Request::new_unchecked(bytes.to_vec().into())
 }
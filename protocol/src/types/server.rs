@@ -1,9 +1,11 @@
-use ckb_jsonrpc_types::OutPoint;
+use ckb_jsonrpc_types::{OutPoint, Script};
 use derive_more::Constructor;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
-use crate::{traits::Backend, ProjectDeps};
+use crate::types::assembler::{KoBlockContext, KoEvent, KoRandomSeed};
+use crate::types::backend::KoUpgradeReport;
+use crate::{traits::Backend, ProjectDeps, H256};
 
 #[derive(Deserialize, Serialize, Constructor, Debug)]
 pub struct KoMakeDeployTransactionDigestResponse {
@@ -11,12 +13,42 @@ pub struct KoMakeDeployTransactionDigestResponse {
     pub project_type_args: String,
 }
 
+#[derive(Deserialize, Serialize, Constructor, Debug)]
+pub struct KoMakeUpgradeTransactionDigestResponse {
+    pub digest: String,
+    pub report: KoUpgradeReport,
+}
+
 #[derive(Deserialize, Serialize, Constructor, Debug)]
 pub struct KoMakeRequestTransactionDigestResponse {
     pub digest: String,
     pub payment: String,
 }
 
+#[derive(Serialize, Deserialize, Constructor, Debug)]
+pub struct KoSimulatedCell {
+    pub lock_script: Script,
+    pub data: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Constructor, Debug)]
+pub struct KoSimulateRequestResponse {
+    pub outputs: Vec<KoSimulatedCell>,
+    pub events: Vec<KoEvent>,
+    pub global_data: String,
+    pub deposit: String,
+    pub withdraw: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Constructor, Debug)]
+pub struct KoFetchRequestReceiptResponse {
+    pub transaction_hash: H256,
+    pub events: Vec<KoEvent>,
+    pub block_context: KoBlockContext,
+    pub random_seed: KoRandomSeed,
+}
+
 #[derive(Serialize, Deserialize, Constructor, Debug)]
 pub struct KoPersonalData {
     pub data: String,
@@ -10,6 +10,7 @@ use ko_protocol::ckb_jsonrpc_types::{
 };
 use ko_protocol::ckb_sdk::rpc::ckb_indexer::{Cell, Order, Pagination, SearchKey};
 use ko_protocol::traits::{CkbClient, RPC};
+use ko_protocol::types::context::KoFeeRateStatistics;
 use ko_protocol::types::error::{ErrorType, KoError};
 use ko_protocol::{async_trait, serde_json, tokio, KoResult, H256};
 
@@ -107,6 +108,17 @@ impl CkbClient for RpcClient {
         jsonrpc!("get_tip_header", Target::CKB, self, HeaderView).boxed()
     }
 
+    fn get_header_by_number(&self, number: BlockNumber) -> RPC<HeaderView> {
+        jsonrpc!(
+            "get_header_by_number",
+            Target::CKB,
+            self,
+            HeaderView,
+            number
+        )
+        .boxed()
+    }
+
     fn get_transaction(&self, hash: &H256) -> RPC<Option<TransactionWithStatus>> {
         self.get_transaction(hash).boxed()
     }
@@ -157,6 +169,16 @@ impl CkbClient for RpcClient {
         .boxed()
     }
 
+    fn get_fee_rate_statistics(&self) -> RPC<Option<KoFeeRateStatistics>> {
+        jsonrpc!(
+            "get_fee_rate_statistics",
+            Target::CKB,
+            self,
+            Option<KoFeeRateStatistics>
+        )
+        .boxed()
+    }
+
     fn fetch_live_cells(
         &self,
         search_key: SearchKey,
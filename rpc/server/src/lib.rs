@@ -8,7 +8,10 @@ use ko_protocol::ckb_sdk::HumanCapacity;
 use ko_protocol::ckb_types::bytes::Bytes;
 use ko_protocol::tokio::sync::Mutex;
 use ko_protocol::traits::Backend;
-use ko_protocol::types::backend::KoRequestInput;
+use ko_protocol::types::assembler::{KoContractAbi, KoProjectManifest};
+use ko_protocol::types::backend::{
+    KoDeployOptions, KoProjectInfo, KoRequestInput, KoUpgradeReport,
+};
 use ko_protocol::ProjectDeps;
 use ko_protocol::{async_trait, hex, log, types::server::*, KoResult, H256};
 
@@ -27,6 +30,9 @@ trait KnsideRpc {
         &self,
         sender: String,
         contract_code: String,
+        module_type_args: Option<Vec<H256>>,
+        manifest: Option<KoProjectManifest>,
+        debug_info: Option<bool>,
     ) -> RpcResult<KoMakeDeployTransactionDigestResponse>;
 
     #[method(name = "ko_makeUpgradeTransactionDigest")]
@@ -35,7 +41,10 @@ trait KnsideRpc {
         sender: String,
         new_contract_code: String,
         project_type_args: H256,
-    ) -> RpcResult<H256>;
+        module_type_args: Option<Vec<H256>>,
+        manifest: Option<KoProjectManifest>,
+        debug_info: Option<bool>,
+    ) -> RpcResult<KoMakeUpgradeTransactionDigestResponse>;
 
     #[method(name = "ko_makeRequestTransactionDigest")]
     async fn make_request_transaction_digest(
@@ -48,6 +57,42 @@ trait KnsideRpc {
         project_type_args: H256,
     ) -> RpcResult<KoMakeRequestTransactionDigestResponse>;
 
+    #[method(name = "ko_simulateRequest")]
+    async fn simulate_request(
+        &self,
+        contract_call: String,
+        sender: Option<String>,
+        inputs: Option<Vec<OutPoint>>,
+        candidates: Vec<String>,
+        components: Vec<OutPoint>,
+        project_type_args: H256,
+    ) -> RpcResult<KoSimulateRequestResponse>;
+
+    #[method(name = "ko_callView")]
+    async fn call_view(
+        &self,
+        contract_call: String,
+        inputs: Option<Vec<OutPoint>>,
+        project_type_args: H256,
+    ) -> RpcResult<String>;
+
+    #[method(name = "ko_getContractAbi")]
+    async fn get_contract_abi(&self, project_type_args: H256) -> RpcResult<KoContractAbi>;
+
+    #[method(name = "ko_getProjectInfo")]
+    async fn get_project_info(&self, project_type_args: H256) -> RpcResult<KoProjectInfo>;
+
+    #[method(name = "ko_checkProjectUpgrade")]
+    async fn check_project_upgrade(
+        &self,
+        sender: String,
+        new_contract_code: String,
+        project_type_args: H256,
+        module_type_args: Option<Vec<H256>>,
+        manifest: Option<KoProjectManifest>,
+        debug_info: Option<bool>,
+    ) -> RpcResult<KoUpgradeReport>;
+
     #[method(name = "ko_sendTransactionSignature")]
     async fn send_transaction_signature(&self, digest: H256, signature: String) -> RpcResult<H256>;
 
@@ -58,6 +103,13 @@ trait KnsideRpc {
         project_type_args: H256,
     ) -> RpcResult<Option<H256>>;
 
+    #[method(name = "ko_fetchRequestReceipt")]
+    async fn fetch_request_receipt(
+        &self,
+        request_hash: H256,
+        project_type_args: H256,
+    ) -> RpcResult<Option<KoFetchRequestReceiptResponse>>;
+
     #[method(name = "ko_manageGlobalDataDriver")]
     async fn manage_global_data_driver(&self, project_type_args: H256) -> RpcResult<()>;
 
@@ -88,6 +140,9 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
         &self,
         sender: String,
         contract_code: String,
+        module_type_args: Option<Vec<H256>>,
+        manifest: Option<KoProjectManifest>,
+        debug_info: Option<bool>,
     ) -> RpcResult<KoMakeDeployTransactionDigestResponse> {
         log::debug!(
             "[RPC] receive `make_deploy_transaction_digest` rpc call <= {}",
@@ -96,7 +151,16 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
         let contract = hex::decode(contract_code).map_err(|err| Error::Custom(err.to_string()))?;
         let mut backend = self.ctx.backend.lock().await;
         let (digest, project_type_args) = backend
-            .create_project_deploy_digest(Bytes::from(contract), sender, &self.ctx.project_deps)
+            .create_project_deploy_digest(
+                Bytes::from(contract),
+                sender,
+                KoDeployOptions::new(
+                    module_type_args.unwrap_or_default(),
+                    manifest,
+                    debug_info.unwrap_or_default(),
+                ),
+                &self.ctx.project_deps,
+            )
             .await
             .map_err(|err| Error::Custom(err.to_string()))?;
         let result = KoMakeDeployTransactionDigestResponse::new(
@@ -111,7 +175,10 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
         sender: String,
         new_contract_code: String,
         project_type_args: H256,
-    ) -> RpcResult<H256> {
+        module_type_args: Option<Vec<H256>>,
+        manifest: Option<KoProjectManifest>,
+        debug_info: Option<bool>,
+    ) -> RpcResult<KoMakeUpgradeTransactionDigestResponse> {
         log::debug!(
             "[RPC] receive `make_upgrade_transaction_digest` rpc call <= {}({})",
             sender,
@@ -120,16 +187,24 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
         let contract =
             hex::decode(new_contract_code).map_err(|err| Error::Custom(err.to_string()))?;
         let mut backend = self.ctx.backend.lock().await;
-        let digest = backend
+        let (digest, report) = backend
             .create_project_upgrade_digest(
                 Bytes::from(contract),
                 sender,
                 &project_type_args,
+                KoDeployOptions::new(
+                    module_type_args.unwrap_or_default(),
+                    manifest,
+                    debug_info.unwrap_or_default(),
+                ),
                 &self.ctx.project_deps,
             )
             .await
             .map_err(|err| Error::Custom(err.to_string()))?;
-        Ok(digest)
+        Ok(KoMakeUpgradeTransactionDigestResponse::new(
+            hex::encode(digest),
+            report,
+        ))
     }
 
     async fn make_request_transaction_digest(
@@ -146,22 +221,7 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
             contract_call
         );
         let mut backend = self.ctx.backend.lock().await;
-        if sender.is_none() && inputs.is_none() || sender.is_some() && inputs.is_some() {
-            return Err(Error::Custom(
-                "sender and inputs are mutually exclusive".to_owned(),
-            ));
-        }
-        let input = if let Some(address) = sender {
-            KoRequestInput::Address(address)
-        } else {
-            KoRequestInput::Outpoints(
-                inputs
-                    .unwrap()
-                    .iter()
-                    .map(|v| v.clone().into())
-                    .collect::<Vec<_>>(),
-            )
-        };
+        let input = make_request_input(sender, inputs)?;
         let components = components
             .iter()
             .map(|v| v.clone().into())
@@ -184,6 +244,146 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
         Ok(result)
     }
 
+    async fn simulate_request(
+        &self,
+        contract_call: String,
+        sender: Option<String>,
+        inputs: Option<Vec<OutPoint>>,
+        candidates: Vec<String>,
+        components: Vec<OutPoint>,
+        project_type_args: H256,
+    ) -> RpcResult<KoSimulateRequestResponse> {
+        log::debug!(
+            "[RPC] receive `simulate_request` rpc call <= {}",
+            contract_call
+        );
+        let input = make_request_input(sender, inputs)?;
+        let components = components
+            .iter()
+            .map(|v| v.clone().into())
+            .collect::<Vec<_>>();
+        let simulation = self
+            .ctx
+            .backend
+            .lock()
+            .await
+            .simulate_project_request(
+                contract_call,
+                input,
+                &components,
+                &candidates,
+                &project_type_args,
+            )
+            .await
+            .map_err(|err| Error::Custom(err.to_string()))?;
+        let (outputs, events, error) = match simulation.outputs {
+            Ok(output) => {
+                let cells = output
+                    .cells
+                    .into_iter()
+                    .map(|(lock_script, data)| {
+                        let data = data.map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+                        KoSimulatedCell::new(lock_script.into(), data)
+                    })
+                    .collect();
+                (cells, output.events, None)
+            }
+            Err(err) => (vec![], vec![], Some(err.to_string())),
+        };
+        let result = KoSimulateRequestResponse::new(
+            outputs,
+            events,
+            String::from_utf8_lossy(&simulation.global_data).into_owned(),
+            HumanCapacity::from(simulation.deposit_ckb).to_string(),
+            HumanCapacity::from(simulation.withdraw_ckb).to_string(),
+            error,
+        );
+        Ok(result)
+    }
+
+    async fn call_view(
+        &self,
+        contract_call: String,
+        inputs: Option<Vec<OutPoint>>,
+        project_type_args: H256,
+    ) -> RpcResult<String> {
+        log::debug!("[RPC] receive `call_view` rpc call <= {}", contract_call);
+        let inputs = inputs
+            .unwrap_or_default()
+            .into_iter()
+            .map(Into::into)
+            .collect::<Vec<_>>();
+        self.ctx
+            .backend
+            .lock()
+            .await
+            .call_project_view(contract_call, &inputs, &project_type_args)
+            .await
+            .map_err(|err| Error::Custom(err.to_string()))
+    }
+
+    async fn get_contract_abi(&self, project_type_args: H256) -> RpcResult<KoContractAbi> {
+        log::debug!(
+            "[RPC] receive `get_contract_abi` rpc call <= {}",
+            project_type_args
+        );
+        self.ctx
+            .backend
+            .lock()
+            .await
+            .get_contract_abi(&project_type_args)
+            .await
+            .map_err(|err| Error::Custom(err.to_string()))
+    }
+
+    async fn check_project_upgrade(
+        &self,
+        sender: String,
+        new_contract_code: String,
+        project_type_args: H256,
+        module_type_args: Option<Vec<H256>>,
+        manifest: Option<KoProjectManifest>,
+        debug_info: Option<bool>,
+    ) -> RpcResult<KoUpgradeReport> {
+        log::debug!(
+            "[RPC] receive `check_project_upgrade` rpc call <= {}({})",
+            sender,
+            project_type_args
+        );
+        let contract =
+            hex::decode(new_contract_code).map_err(|err| Error::Custom(err.to_string()))?;
+        self.ctx
+            .backend
+            .lock()
+            .await
+            .check_project_upgrade(
+                Bytes::from(contract),
+                sender,
+                &project_type_args,
+                &KoDeployOptions::new(
+                    module_type_args.unwrap_or_default(),
+                    manifest,
+                    debug_info.unwrap_or_default(),
+                ),
+            )
+            .await
+            .map_err(|err| Error::Custom(err.to_string()))
+    }
+
+    async fn get_project_info(&self, project_type_args: H256) -> RpcResult<KoProjectInfo> {
+        log::debug!(
+            "[RPC] receive `get_project_info` rpc call <= {}",
+            project_type_args
+        );
+        self.ctx
+            .backend
+            .lock()
+            .await
+            .get_project_info(&project_type_args)
+            .await
+            .map_err(|err| Error::Custom(err.to_string()))
+    }
+
     async fn send_transaction_signature(&self, digest: H256, signature: String) -> RpcResult<H256> {
         log::debug!(
             "[RPC] receive `send_transaction_signature` rpc call <= digest({})",
@@ -231,9 +431,38 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
                 &self.ctx.project_deps,
             )
             .await
+            .map(|receipt| receipt.map(|receipt| receipt.transaction_hash))
             .map_err(|err| Error::Custom(err.to_string()))
     }
 
+    async fn fetch_request_receipt(
+        &self,
+        request_hash: H256,
+        project_type_args: H256,
+    ) -> RpcResult<Option<KoFetchRequestReceiptResponse>> {
+        log::debug!(
+            "[RPC] receive `fetch_request_receipt` rpc call <= hash({})",
+            hex::encode(&request_hash)
+        );
+        let receipt = self
+            .ctx
+            .backend
+            .lock()
+            .await
+            .fetch_request_receipt(&request_hash, &project_type_args)
+            .await
+            .map_err(|err| Error::Custom(err.to_string()))?
+            .map(|receipt| {
+                KoFetchRequestReceiptResponse::new(
+                    receipt.transaction_hash,
+                    receipt.events,
+                    receipt.block_context,
+                    receipt.random_seed,
+                )
+            });
+        Ok(receipt)
+    }
+
     async fn manage_global_data_driver(&self, project_type_args: H256) -> RpcResult<()> {
         log::debug!(
             "[RPC] receive `manage_global_drive` rpc call => {}",
@@ -289,6 +518,21 @@ impl<B: Backend + 'static> KnsideRpcServer for RpcServer<B> {
     }
 }
 
+fn make_request_input(
+    sender: Option<String>,
+    inputs: Option<Vec<OutPoint>>,
+) -> RpcResult<KoRequestInput> {
+    match (sender, inputs) {
+        (Some(address), None) => Ok(KoRequestInput::Address(address)),
+        (None, Some(inputs)) => Ok(KoRequestInput::Outpoints(
+            inputs.into_iter().map(Into::into).collect(),
+        )),
+        _ => Err(Error::Custom(
+            "sender and inputs are mutually exclusive".to_owned(),
+        )),
+    }
+}
+
 impl<B: Backend + 'static> RpcServer<B> {
     pub async fn start(
         url: &str,
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 use ko_backend::BackendImpl;
+use ko_context_executor::{ExecutorImpl, ExecutorPool};
 use ko_protocol::ckb_jsonrpc_types::OutPoint;
 use ko_protocol::ckb_types::h256;
 use ko_protocol::secp256k1::{Message, SecretKey};
@@ -8,6 +9,7 @@ use ko_protocol::types::server::*;
 use ko_protocol::{ckb_sdk::SECP256K1, ckb_types::H256, hex, tokio, TestVars::*};
 use ko_rpc_client::RpcClient;
 use ko_rpc_server::RpcServer;
+use ko_storage::StorageImpl;
 
 use jsonrpsee::core::{client::ClientT, rpc_params};
 use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
@@ -18,7 +20,17 @@ async fn create_server_and_client(with_server: bool) -> HttpClient {
     // start rpc server
     if with_server {
         let rpc_client = RpcClient::new(CKB_URL, CKB_INDEXER_URL);
-        let backend = BackendImpl::new(&rpc_client, MockContextRpc::default());
+        let backend = BackendImpl::new(
+            &rpc_client,
+            MockContextRpc::default(),
+            &StorageImpl::temporary().expect("storage"),
+            &ExecutorImpl::new(
+                DRIVE_CONFIG.max_instructions_per_request,
+                DRIVE_CONFIG.max_lua_memory_bytes,
+                &ExecutorPool::new(1),
+            ),
+            &DRIVE_CONFIG.fee_settings,
+        );
         let handle = RpcServer::<_>::start(JSONRPC_PORT, backend, &PROJECT_VARS)
             .await
             .expect("start rpc server");
@@ -93,7 +105,7 @@ async fn call_contract_method() {
         signature.copy_from_slice(&bytes);
         signature
     };
-    let signature = hex::encode(&signature_bytes);
+    let signature = hex::encode(signature_bytes);
 
     // send transaction
     let hash: String = client
@@ -114,7 +126,7 @@ async fn call_contract_method() {
         )
         .await
         .expect("server commit");
-    println!("committed = {}", hex::encode(&committed_hash.unwrap()));
+    println!("committed = {}", hex::encode(committed_hash.unwrap()));
 }
 
 #[tokio::test]
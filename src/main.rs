@@ -1,12 +1,15 @@
 use std::convert::TryInto;
-use std::panic::PanicInfo;
+use std::panic::PanicHookInfo;
+use std::str::FromStr;
 
 use clap::{crate_version, Arg, Command};
 use ko_backend::BackendImpl;
-use ko_context::ContextMgr;
-use ko_protocol::{log, secp256k1::SecretKey, tokio, KoResult, Logger, ProjectDeps};
+use ko_context::{verify_drive_transaction, ContextMgr};
+use ko_protocol::{ckb_types, log, secp256k1::SecretKey, serde_json, tokio};
+use ko_protocol::{traits::Storage, KoResult, Logger, ProjectDeps, H256};
 use ko_rpc::RpcServerRuntime;
 use ko_rpc_client::RpcClient;
+use ko_storage::StorageImpl;
 
 const PROJECT_TYPE_ARGS_TOML: &str = ".project_type_args.toml";
 
@@ -24,6 +27,21 @@ async fn main() -> KoResult<()> {
                 .takes_value(true),
         )
         .subcommand(Command::new("run").about("Run knside-out process"))
+        .subcommand(
+            Command::new("verify")
+                .about("Replay a committed knside-out transaction and diff its outputs")
+                .arg(
+                    Arg::new("tx_hash")
+                        .help("Hash of the committed knside-out transaction")
+                        .required(true)
+                        .takes_value(true)
+                        .value_parser(|value: &str| {
+                            ckb_types::H256::from_str(value.trim_start_matches("0x"))
+                                .map(H256::from)
+                                .map_err(|err| format!("invalid tx_hash, {}", err))
+                        }),
+                ),
+        )
         .get_matches();
 
     // initail log system
@@ -33,12 +51,41 @@ async fn main() -> KoResult<()> {
 
     let config_path = matches.value_of("config_path").unwrap();
     let config = ko_config::load_file(config_path)?;
-    let config_type_args = ko_config::load_type_args_file(PROJECT_TYPE_ARGS_TOML)?;
     let project_deps: &ProjectDeps = &config.as_ref().try_into().expect("config");
 
     // initail CKB rcp client
     let rpc_client = RpcClient::new(&config.ckb_url, &config.ckb_indexer_url);
 
+    // replay transaction instead of running driver
+    if let Some(verify) = matches.subcommand_matches("verify") {
+        let tx_hash = verify.get_one::<H256>("tx_hash").unwrap();
+        let verification =
+            verify_drive_transaction(&rpc_client, project_deps, &config.drive_settings, tx_hash)
+                .await?;
+        println!("{}", serde_json::to_string_pretty(&verification).unwrap());
+        if !verification.is_conclusive() {
+            log::warn!(
+                "mismatched requests ran out of local execution limits, raise them to verify"
+            );
+            std::process::exit(2);
+        }
+        if !verification.is_honest() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // initail local storage, managed projects of legacy toml file are imported only once
+    let storage = StorageImpl::open(&config.storage_path)?;
+    let mut projects_status = storage.load_projects_status()?;
+    if projects_status.is_empty() {
+        if let Ok(config_type_args) = ko_config::load_type_args_file(PROJECT_TYPE_ARGS_TOML) {
+            projects_status = config_type_args.into();
+            for (project_type_args, running) in &projects_status {
+                storage.save_project_status(project_type_args, *running)?;
+            }
+        }
+    }
+
     // initail driver context manager
     let manager_private_key =
         SecretKey::from_slice(config.project_manager_privkey.as_bytes()).expect("private key");
@@ -47,24 +94,47 @@ async fn main() -> KoResult<()> {
         &manager_private_key,
         project_deps,
         &config.drive_settings,
+        &storage,
     );
-    context_mgr.recover_contexts(config_type_args.into()).await;
+    // managed projects are rebuilt from chain in case local storage lost some of them
+    let projects_status = match context_mgr
+        .reconcile_projects(projects_status.clone())
+        .await
+    {
+        Ok(reconciled) => reconciled,
+        Err(err) => {
+            log::error!("discover managed projects failed, error = {}", err);
+            projects_status
+        }
+    };
+    context_mgr.recover_contexts(projects_status).await;
 
-    // backup loop for persisting contexts status into project_type_args toml file
+    // backup loop for persisting contexts status into local storage
+    let status_storage = storage.clone();
     tokio::spawn(async move {
         loop {
             tokio::select! {
                 _ = tokio::time::sleep(std::time::Duration::from_secs(config.persist_interval_sec)) => {
                     let contexts_status = ContextMgr::<RpcClient>::dump_contexts_status().await;
-                    ko_config::save_type_args_file(contexts_status, PROJECT_TYPE_ARGS_TOML)
-                        .expect("save config");
+                    for (project_type_args, running) in contexts_status {
+                        status_storage
+                            .save_project_status(&project_type_args, running)
+                            .expect("save contexts status");
+                    }
                 }
             }
         }
     });
 
     // initail rpc backend
-    let backend = BackendImpl::new(&rpc_client, context_mgr);
+    let executor = context_mgr.new_executor();
+    let backend = BackendImpl::new(
+        &rpc_client,
+        context_mgr,
+        &storage,
+        &executor,
+        &config.drive_settings.fee_settings,
+    );
 
     // start rpc server
     RpcServerRuntime::run(&config.rpc_endpoint, backend, project_deps).await?;
@@ -88,7 +158,7 @@ async fn main() -> KoResult<()> {
     // register channel of panic
     let (panic_sender, mut panic_receiver) = tokio::sync::mpsc::channel(1);
 
-    std::panic::set_hook(Box::new(move |info: &PanicInfo| {
+    std::panic::set_hook(Box::new(move |info: &PanicHookInfo| {
         panic_sender
             .try_send(info.to_string())
             .expect("panic_receiver is droped");
@@ -102,6 +172,7 @@ async fn main() -> KoResult<()> {
             log::warn!("child thread paniced: {}", panic_info)
         }
     }
+    storage.flush()?;
 
     Ok(())
 }
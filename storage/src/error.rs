@@ -0,0 +1,25 @@
+use ko_protocol::derive_more::Display;
+use ko_protocol::types::error::{ErrorType, KoError};
+
+#[derive(Display, Debug)]
+pub enum StorageError {
+    #[display(fmt = "Cannot open storage at {}, reason = {}", _0, _1)]
+    OpenStorage(String, String),
+
+    #[display(fmt = "Storage operation error: {}", _0)]
+    StorageOperation(String),
+
+    #[display(fmt = "Broken storage record, tree = {}, reason = {}", _0, _1)]
+    BrokenRecord(String, String),
+
+    #[display(fmt = "Request failed in drive transaction, reason = {}", _0)]
+    FailedRequest(String),
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<StorageError> for KoError {
+    fn from(error: StorageError) -> KoError {
+        KoError::new(ErrorType::Storage, Box::new(error))
+    }
+}
@@ -0,0 +1,236 @@
+use std::convert::TryFrom;
+
+use ko_protocol::ckb_jsonrpc_types::{JsonBytes, Script as JsonScript};
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::core::TransactionView;
+use ko_protocol::ckb_types::packed::Transaction;
+use ko_protocol::ckb_types::prelude::Entity;
+use ko_protocol::serde::{de::DeserializeOwned, Deserialize, Serialize};
+use ko_protocol::traits::Storage;
+use ko_protocol::types::context::{KoContextGlobalCell, KoPendingDrive, KoRequestOutcome};
+use ko_protocol::{serde_json, KoResult, H256};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::{Db, Tree};
+
+#[cfg(test)]
+mod tests;
+
+mod error;
+pub use error::StorageError;
+
+const PROJECTS_TREE: &str = "projects";
+const DIGESTS_TREE: &str = "digests";
+const DRIVES_TREE: &str = "pending_drives";
+const GLOBALS_TREE: &str = "global_cells";
+const OUTCOMES_TREE: &str = "request_outcomes";
+
+// the last known global cell of project, `KoContextGlobalCell` isn't serializable by itself
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "ko_protocol::serde")]
+struct StoredGlobalCell {
+    lock_script: JsonScript,
+    output_data: JsonBytes,
+    capacity: u64,
+    occupied_capacity: u64,
+}
+
+/// Embedded sled database, every tree is keyed by a 32 bytes hash
+#[derive(Clone)]
+pub struct StorageImpl {
+    db: Db,
+    projects: Tree,
+    digests: Tree,
+    drives: Tree,
+    globals: Tree,
+    outcomes: Tree,
+}
+
+impl StorageImpl {
+    pub fn open(path: &str) -> KoResult<Self> {
+        let db = sled::open(path)
+            .map_err(|err| StorageError::OpenStorage(path.into(), err.to_string()))?;
+        Self::from_db(db)
+    }
+
+    /// Storage which is removed once dropped, only for tests and dry runs
+    pub fn temporary() -> KoResult<Self> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(|err| StorageError::OpenStorage("<temporary>".into(), err.to_string()))?;
+        Self::from_db(db)
+    }
+
+    fn from_db(db: Db) -> KoResult<Self> {
+        let open_tree = |name: &str| {
+            db.open_tree(name)
+                .map_err(|err| StorageError::OpenStorage(name.into(), err.to_string()))
+        };
+        Ok(StorageImpl {
+            projects: open_tree(PROJECTS_TREE)?,
+            digests: open_tree(DIGESTS_TREE)?,
+            drives: open_tree(DRIVES_TREE)?,
+            globals: open_tree(GLOBALS_TREE)?,
+            outcomes: open_tree(OUTCOMES_TREE)?,
+            db,
+        })
+    }
+
+    /// Write everything down to disk, sled flushes periodically by itself as well
+    pub fn flush(&self) -> KoResult<()> {
+        self.db
+            .flush()
+            .map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+        Ok(())
+    }
+}
+
+impl Storage for StorageImpl {
+    fn save_project_status(&self, project_type_args: &H256, running: bool) -> KoResult<()> {
+        insert(&self.projects, project_type_args, vec![running as u8])
+    }
+
+    fn load_projects_status(&self) -> KoResult<Vec<(H256, bool)>> {
+        self.projects
+            .iter()
+            .map(|item| {
+                let (key, value) =
+                    item.map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+                Ok((parse_key(PROJECTS_TREE, &key)?, value.first() == Some(&1)))
+            })
+            .collect()
+    }
+
+    fn save_digest_transaction(&self, digest: &H256, tx: &TransactionView) -> KoResult<()> {
+        insert(&self.digests, digest, tx.data().as_slice().to_vec())
+    }
+
+    fn load_digest_transaction(&self, digest: &H256) -> KoResult<Option<TransactionView>> {
+        let value = self
+            .digests
+            .get(digest)
+            .map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+        value.map(|tx| parse_transaction(&tx)).transpose()
+    }
+
+    fn take_digest_transaction(&self, digest: &H256) -> KoResult<Option<TransactionView>> {
+        let value = self
+            .digests
+            .remove(digest)
+            .map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+        value.map(|tx| parse_transaction(&tx)).transpose()
+    }
+
+    fn save_pending_drive(
+        &self,
+        project_type_args: &H256,
+        drive: &KoPendingDrive,
+        global_cell: &KoContextGlobalCell,
+    ) -> KoResult<()> {
+        let drive = encode(drive)?;
+        let global_cell = encode(&StoredGlobalCell {
+            lock_script: global_cell.lock_script.clone().into(),
+            output_data: JsonBytes::from_bytes(global_cell.output_data.clone()),
+            capacity: global_cell.capacity,
+            occupied_capacity: global_cell.occupied_capacity,
+        })?;
+        (&self.drives, &self.globals)
+            .transaction(|(drives, globals)| {
+                drives.insert(project_type_args.as_bytes(), drive.as_slice())?;
+                globals.insert(project_type_args.as_bytes(), global_cell.as_slice())?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(transaction_error)?;
+        Ok(())
+    }
+
+    fn load_pending_drive(&self, project_type_args: &H256) -> KoResult<Option<KoPendingDrive>> {
+        get(&self.drives, DRIVES_TREE, project_type_args)
+    }
+
+    fn complete_pending_drive(
+        &self,
+        project_type_args: &H256,
+        outcomes: &[(H256, KoRequestOutcome)],
+    ) -> KoResult<()> {
+        let outcomes = outcomes
+            .iter()
+            .map(|(request_hash, outcome)| Ok((request_hash.clone(), encode(outcome)?)))
+            .collect::<KoResult<Vec<_>>>()?;
+        (&self.drives, &self.outcomes)
+            .transaction(|(drives, stored_outcomes)| {
+                for (request_hash, outcome) in &outcomes {
+                    stored_outcomes.insert(request_hash.as_bytes(), outcome.as_slice())?;
+                }
+                drives.remove(project_type_args.as_bytes())?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(transaction_error)?;
+        Ok(())
+    }
+
+    fn discard_pending_drive(&self, project_type_args: &H256) -> KoResult<()> {
+        self.drives
+            .remove(project_type_args)
+            .map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+        Ok(())
+    }
+
+    fn load_global_cell(&self, project_type_args: &H256) -> KoResult<Option<KoContextGlobalCell>> {
+        let cell: Option<StoredGlobalCell> = get(&self.globals, GLOBALS_TREE, project_type_args)?;
+        Ok(cell.map(|cell| {
+            KoContextGlobalCell::new(
+                cell.lock_script.into(),
+                cell.output_data.into_bytes(),
+                cell.capacity,
+                cell.occupied_capacity,
+            )
+        }))
+    }
+
+    fn load_request_outcome(&self, request_hash: &H256) -> KoResult<Option<KoRequestOutcome>> {
+        get(&self.outcomes, OUTCOMES_TREE, request_hash)
+    }
+}
+
+fn insert(tree: &Tree, key: &H256, value: Vec<u8>) -> KoResult<()> {
+    tree.insert(key, value)
+        .map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+    Ok(())
+}
+
+fn get<T: DeserializeOwned>(tree: &Tree, name: &str, key: &H256) -> KoResult<Option<T>> {
+    let value = tree
+        .get(key)
+        .map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+    let value = value
+        .map(|value| serde_json::from_slice(&value))
+        .transpose()
+        .map_err(|err| StorageError::BrokenRecord(name.into(), err.to_string()))?;
+    Ok(value)
+}
+
+fn encode<T: Serialize>(value: &T) -> KoResult<Vec<u8>> {
+    let value =
+        serde_json::to_vec(value).map_err(|err| StorageError::StorageOperation(err.to_string()))?;
+    Ok(value)
+}
+
+fn parse_key(name: &str, key: &[u8]) -> KoResult<H256> {
+    let key = H256::try_from(Bytes::copy_from_slice(key))
+        .map_err(|err| StorageError::BrokenRecord(name.into(), err))?;
+    Ok(key)
+}
+
+fn parse_transaction(tx: &[u8]) -> KoResult<TransactionView> {
+    let tx = Transaction::from_slice(tx)
+        .map_err(|err| StorageError::BrokenRecord(DIGESTS_TREE.into(), err.to_string()))?;
+    Ok(tx.into_view())
+}
+
+fn transaction_error(error: TransactionError<()>) -> StorageError {
+    match error {
+        TransactionError::Storage(err) => StorageError::StorageOperation(err.to_string()),
+        TransactionError::Abort(()) => StorageError::StorageOperation("aborted".into()),
+    }
+}
@@ -0,0 +1,137 @@
+use ko_protocol::ckb_jsonrpc_types::OutPoint;
+use ko_protocol::ckb_types::bytes::Bytes;
+use ko_protocol::ckb_types::core::TransactionBuilder;
+use ko_protocol::ckb_types::packed::{CellOutput, Script};
+use ko_protocol::ckb_types::prelude::{Builder, Entity, Pack};
+use ko_protocol::traits::Storage;
+use ko_protocol::types::assembler::{KoBlockContext, KoEvent, KoRandomSeed};
+use ko_protocol::types::context::{KoContextGlobalCell, KoPendingDrive};
+use ko_protocol::H256;
+
+use crate::StorageImpl;
+
+fn hash(byte: u8) -> H256 {
+    [byte; 32].into()
+}
+
+fn random_seed(byte: u8) -> KoRandomSeed {
+    let out_point = OutPoint {
+        tx_hash: [byte; 32].into(),
+        index: 0.into(),
+    };
+    KoRandomSeed::new([byte as i64, 0], out_point, hash(byte), byte as u64)
+}
+
+fn pending_drive() -> KoPendingDrive {
+    KoPendingDrive::new(
+        hash(9),
+        vec![
+            (hash(1), Ok(vec![KoEvent::new("mint".into(), "1".into())])),
+            (hash(2), Err("out of stock".into())),
+        ],
+        KoBlockContext::new(hash(8), 100, 1_650_000_000_000, 2),
+        vec![random_seed(1), random_seed(2)],
+        vec![],
+    )
+}
+
+#[test]
+fn driver_state_survives_reopen() {
+    let path = std::env::temp_dir().join(format!("ko-storage-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let project_type_args = hash(7);
+    let global_cell = {
+        let output = CellOutput::new_builder()
+            .lock(
+                Script::new_builder()
+                    .args(Bytes::from(vec![3]).pack())
+                    .build(),
+            )
+            .build();
+        KoContextGlobalCell::from_output(output, Bytes::from(r#"{"count":1}"#))
+    };
+    let tx = TransactionBuilder::default()
+        .output(CellOutput::default())
+        .output_data(Bytes::new().pack())
+        .build();
+    {
+        let storage = StorageImpl::open(path).expect("open");
+        storage
+            .save_project_status(&project_type_args, true)
+            .unwrap();
+        storage.save_project_status(&hash(6), false).unwrap();
+        storage.save_digest_transaction(&hash(5), &tx).unwrap();
+        storage
+            .save_pending_drive(&project_type_args, &pending_drive(), &global_cell)
+            .unwrap();
+        storage.flush().unwrap();
+    }
+
+    // everything is there after restart
+    // flusher thread of sled releases the file lock a bit later than the dropped database
+    let storage = (0..20)
+        .find_map(|_| {
+            StorageImpl::open(path)
+                .map_err(|_| std::thread::sleep(std::time::Duration::from_millis(100)))
+                .ok()
+        })
+        .expect("reopen");
+    let projects = storage.load_projects_status().unwrap();
+    assert_eq!(
+        projects,
+        vec![(hash(6), false), (project_type_args.clone(), true)]
+    );
+    let stored_tx = storage.load_digest_transaction(&hash(5)).unwrap();
+    assert_eq!(stored_tx.map(|tx| tx.hash()), Some(tx.hash()));
+    let stored_global = storage
+        .load_global_cell(&project_type_args)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        stored_global.lock_script.as_slice(),
+        global_cell.lock_script.as_slice()
+    );
+    assert_eq!(stored_global.output_data, global_cell.output_data);
+    assert_eq!(
+        stored_global.occupied_capacity,
+        global_cell.occupied_capacity
+    );
+
+    // signed digest is sent only once
+    assert!(storage.take_digest_transaction(&hash(5)).unwrap().is_some());
+    assert!(storage.take_digest_transaction(&hash(5)).unwrap().is_none());
+
+    // confirmed drive turns into request outcomes
+    let drive = storage.load_pending_drive(&project_type_args).unwrap();
+    let outcomes = drive.expect("pending drive").into_outcomes();
+    storage
+        .complete_pending_drive(&project_type_args, &outcomes)
+        .unwrap();
+    assert!(storage
+        .load_pending_drive(&project_type_args)
+        .unwrap()
+        .is_none());
+    let receipt = storage.load_request_outcome(&hash(1)).unwrap().unwrap();
+    let receipt = receipt.expect("receipt");
+    assert_eq!(receipt.transaction_hash, hash(9));
+    assert_eq!(receipt.random_seed, random_seed(1));
+    assert_eq!(receipt.events[0].name, "mint");
+    let failure = storage.load_request_outcome(&hash(2)).unwrap().unwrap();
+    assert_eq!(failure.unwrap_err(), "out of stock");
+    assert!(storage.load_request_outcome(&hash(3)).unwrap().is_none());
+
+    drop(storage);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn dropped_drive_is_discarded() {
+    let storage = StorageImpl::temporary().expect("storage");
+    let global_cell = KoContextGlobalCell::default();
+    storage
+        .save_pending_drive(&hash(7), &pending_drive(), &global_cell)
+        .unwrap();
+    storage.discard_pending_drive(&hash(7)).unwrap();
+    assert!(storage.load_pending_drive(&hash(7)).unwrap().is_none());
+    assert!(storage.load_request_outcome(&hash(1)).unwrap().is_none());
+}